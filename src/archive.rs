@@ -0,0 +1,405 @@
+//! Archives of files stored in the database, as tar.gz or zip.
+//!
+//! Archives are written by blocking code, reading the content of each file
+//! incrementally from the database.
+
+use crate::database::file::open_content;
+use crate::dir::{DirEntry, EntryType};
+use crate::{CabinetError, CabinetResult as Result};
+use actix_web::http::header::HttpDate;
+use rusqlite::{Connection, Transaction};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Seek, Write};
+use std::path::{Component, Path};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Mode of directory entries in archives.
+const DIR_MODE: u32 = 0o755;
+
+/// Mode of imported files without a mode in the archive.
+const DEFAULT_MODE: u32 = 0o644;
+
+/// Archive formats.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+pub enum Format {
+    #[default]
+    #[serde(rename = "tar.gz", alias = "tgz")]
+    TarGz,
+    #[serde(rename = "zip")]
+    Zip,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::TarGz => "application/gzip",
+            Format::Zip => "application/zip",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::TarGz => "tar.gz",
+            Format::Zip => "zip",
+        }
+    }
+}
+
+/// A file or directory to put in an archive.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ArchiveEntry {
+    /// Path of the entry within the archive.
+    pub name: String,
+    pub entry_type: EntryType,
+    /// Id of the file. Not used for directories.
+    pub id: usize,
+    pub size: usize,
+    pub mode: u32,
+    /// Modified date, as an HTTP date.
+    pub modified: Option<String>,
+}
+
+impl ArchiveEntry {
+    /// Create the entry of a directory listing entry, named relative to the
+    /// directory at `base`.
+    pub fn relative_to(base: &str, entry: &DirEntry) -> Self {
+        let base = base.trim_matches('/');
+        let name = if base.is_empty() {
+            entry.path.as_str()
+        } else {
+            entry.path.strip_prefix(base).unwrap_or(&entry.path).trim_start_matches('/')
+        };
+        ArchiveEntry {
+            name: name.to_string(),
+            entry_type: entry.entry_type,
+            id: entry.id,
+            size: entry.size.unwrap_or(0),
+            mode: entry.mode.unwrap_or(DIR_MODE),
+            modified: entry.modified.clone(),
+        }
+    }
+
+    /// Modification time in seconds since the Unix epoch.
+    fn mtime(&self) -> u64 {
+        self.modified
+            .as_deref()
+            .and_then(|m| HttpDate::from_str(m).ok())
+            .map(SystemTime::from)
+            .unwrap_or_else(SystemTime::now)
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// Turn the client-side location of a boilerplate file into a path within
+/// an archive.
+///
+/// Locations relative to the home directory (`~/` or `$HOME/`) or the file
+/// system root become relative paths. Locations leaving the archive with
+/// `..` are rejected.
+///
+pub fn archive_name(location: &str) -> Result<String> {
+    let rel = ["~/", "$HOME/", "${HOME}/"]
+        .iter()
+        .find_map(|home| location.strip_prefix(home))
+        .unwrap_or(location);
+    let mut parts = Vec::new();
+    for comp in Path::new(rel).components() {
+        match comp {
+            Component::Normal(name) => parts.push(name.to_string_lossy().to_string()),
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(CabinetError::BadRequest(format!(
+                    "location cannot be archived: {}",
+                    location
+                )))
+            }
+        }
+    }
+    if parts.is_empty() {
+        return Err(CabinetError::BadRequest(format!("location cannot be archived: {}", location)));
+    }
+    Ok(parts.join("/"))
+}
+
+/// Write a gzip compressed tar archive of `entries` to `writer`.
+pub fn write_tar_gz<W: Write>(conn: &Connection, entries: &[ArchiveEntry], writer: W) -> Result<W> {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use tar::{Builder, EntryType as TarType, Header};
+
+    let mut builder = Builder::new(GzEncoder::new(writer, Compression::default()));
+    for entry in entries {
+        let mut header = Header::new_gnu();
+        header.set_mode(entry.mode);
+        header.set_mtime(entry.mtime());
+        match entry.entry_type {
+            EntryType::Dir => {
+                header.set_entry_type(TarType::Directory);
+                header.set_size(0);
+                builder.append_data(&mut header, format!("{}/", entry.name), std::io::empty())?;
+            }
+            EntryType::File => {
+                header.set_entry_type(TarType::Regular);
+                header.set_size(entry.size as u64);
+                builder.append_data(&mut header, &entry.name, open_content(conn, entry.id)?)?;
+            }
+        }
+    }
+    let writer = builder.into_inner()?.finish()?;
+    Ok(writer)
+}
+
+/// Write a zip archive of `entries` to `writer`.
+///
+/// Zip archives end with a directory of their entries, so the writer must
+/// be seekable.
+///
+pub fn write_zip<W: Write + Seek>(conn: &Connection, entries: &[ArchiveEntry], writer: W) -> Result<W> {
+    use zip::write::SimpleFileOptions;
+    use zip::{CompressionMethod, ZipWriter};
+
+    let zip_err = |e: zip::result::ZipError| CabinetError::Other(e.to_string());
+    let mut zip = ZipWriter::new(writer);
+    for entry in entries {
+        let mut options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .unix_permissions(entry.mode);
+        if let Some(time) = zip_time(entry.mtime()) {
+            options = options.last_modified_time(time);
+        }
+        match entry.entry_type {
+            EntryType::Dir => zip.add_directory(entry.name.as_str(), options).map_err(zip_err)?,
+            EntryType::File => {
+                let options = options.large_file(entry.size as u64 >= u32::MAX as u64);
+                zip.start_file(entry.name.as_str(), options).map_err(zip_err)?;
+                std::io::copy(&mut open_content(conn, entry.id)?, &mut zip)?;
+            }
+        }
+    }
+    zip.finish().map_err(zip_err)
+}
+
+/// Convert seconds since the Unix epoch to a zip timestamp. Zip timestamps
+/// can't represent dates before 1980.
+fn zip_time(secs: u64) -> Option<zip::DateTime> {
+    use chrono::{TimeZone, Utc};
+
+    let date = Utc.timestamp_opt(secs as i64, 0).single()?;
+    zip::DateTime::try_from(date.naive_utc()).ok()
+}
+
+/// Imported lists the paths of the directories and files created or updated
+/// by an archive upload.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash, Serialize)]
+pub struct Imported {
+    pub dirs: Vec<String>,
+    pub files: Vec<String>,
+}
+
+/// Import the entries of a tar, tar.gz or zip archive into the directory at
+/// `dest`, in a single transaction.
+///
+/// The format is detected from the content. Existing files are updated,
+/// keeping their current version as a revision. Modes and modified dates
+/// are taken from the archive. Nothing is imported if any entry is invalid,
+/// or if the content of the entries is larger than `max_size` in total.
+///
+pub fn import<R: Read + Seek>(
+    conn: &mut Connection,
+    dest: &str,
+    mut archive: R,
+    max_size: usize,
+) -> Result<Imported> {
+    use rusqlite::TransactionBehavior::Immediate;
+    use std::io::SeekFrom;
+
+    let mut magic = [0; 4];
+    let n = archive.read(&mut magic)?;
+    archive.seek(SeekFrom::Start(0))?;
+
+    let dest = dest.trim_matches('/');
+    let tx = conn.transaction_with_behavior(Immediate)?;
+    let mut imported = Imported::default();
+    let mut remaining = max_size;
+    match &magic[..n] {
+        [0x1f, 0x8b, ..] => {
+            let archive = flate2::read::GzDecoder::new(archive);
+            import_tar(&tx, dest, archive, &mut remaining, &mut imported)?
+        }
+        [b'P', b'K', ..] => import_zip(&tx, dest, archive, &mut remaining, &mut imported)?,
+        _ => import_tar(&tx, dest, archive, &mut remaining, &mut imported)?,
+    }
+    tx.commit()?;
+    Ok(imported)
+}
+
+fn import_tar<R: Read>(
+    conn: &Transaction<'_>,
+    dest: &str,
+    archive: R,
+    remaining: &mut usize,
+    imported: &mut Imported,
+) -> Result<()> {
+    use tar::{Archive, EntryType as TarType};
+
+    let invalid = |e: std::io::Error| CabinetError::BadRequest(format!("invalid archive: {}", e));
+    let mut archive = Archive::new(archive);
+    for entry in archive.entries().map_err(invalid)? {
+        let entry = entry.map_err(invalid)?;
+        let header = entry.header();
+        let name = String::from_utf8_lossy(&entry.path_bytes()).to_string();
+        let path = import_path(dest, &name)?;
+        let mode = header.mode().map(|m| m & 0o7777).unwrap_or(DEFAULT_MODE);
+        let mtime = header.mtime().unwrap_or(0);
+        match header.entry_type() {
+            TarType::Directory => import_dir(conn, &path, imported)?,
+            TarType::Regular | TarType::Continuous => {
+                let size = header.size().map_err(invalid)? as usize;
+                take_size(remaining, size)?;
+                import_file(conn, &path, entry, size, mode, mtime, imported)?;
+            }
+            // Headers carrying metadata of the next entry
+            TarType::GNULongName | TarType::XHeader | TarType::XGlobalHeader => (),
+            _ => return Err(CabinetError::BadRequest(format!("unsupported archive entry: {}", name))),
+        }
+    }
+    Ok(())
+}
+
+fn import_zip<R: Read + Seek>(
+    conn: &Transaction<'_>,
+    dest: &str,
+    archive: R,
+    remaining: &mut usize,
+    imported: &mut Imported,
+) -> Result<()> {
+    use zip::ZipArchive;
+
+    let invalid = |e: zip::result::ZipError| CabinetError::BadRequest(format!("invalid archive: {}", e));
+    let mut archive = ZipArchive::new(archive).map_err(invalid)?;
+    for i in 0..archive.len() {
+        let file = archive.by_index(i).map_err(invalid)?;
+        let name = file.name().to_string();
+        let path = import_path(dest, &name)?;
+        if file.is_dir() {
+            import_dir(conn, &path, imported)?;
+        } else if file.is_file() {
+            let mode = file.unix_mode().map(|m| m & 0o7777).unwrap_or(DEFAULT_MODE);
+            let mtime = file
+                .last_modified()
+                .and_then(|t| chrono::NaiveDateTime::try_from(t).ok())
+                .map(|t| t.and_utc().timestamp().max(0) as u64)
+                .unwrap_or(0);
+            let size = file.size() as usize;
+            take_size(remaining, size)?;
+            import_file(conn, &path, file, size, mode, mtime, imported)?;
+        } else {
+            return Err(CabinetError::BadRequest(format!("unsupported archive entry: {}", name)));
+        }
+    }
+    Ok(())
+}
+
+/// Get the server-side path of an archive entry imported into `dest`.
+fn import_path(dest: &str, name: &str) -> Result<String> {
+    let mut parts: Vec<String> = dest.split('/').filter(|p| !p.is_empty()).map(String::from).collect();
+    for comp in Path::new(name).components() {
+        match comp {
+            Component::Normal(name) => parts.push(name.to_string_lossy().to_string()),
+            Component::CurDir => (),
+            _ => return Err(CabinetError::BadRequest(format!("invalid archive entry path: {}", name))),
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+/// Take the `size` of an entry from the `remaining` size of the content
+/// an archive may import.
+fn take_size(remaining: &mut usize, size: usize) -> Result<()> {
+    *remaining = remaining.checked_sub(size).ok_or(CabinetError::PailoadTooLarge)?;
+    Ok(())
+}
+
+fn import_dir(conn: &Connection, path: &str, imported: &mut Imported) -> Result<()> {
+    use crate::database::dir::create;
+
+    // The destination itself is included in some archives as `./`
+    if !path.is_empty() {
+        create(conn, path.as_ref())?;
+        imported.dirs.push(path.to_string());
+    }
+    Ok(())
+}
+
+fn import_file<R: Read>(
+    conn: &Transaction<'_>,
+    path: &str,
+    content: R,
+    size: usize,
+    mode: u32,
+    mtime: u64,
+    imported: &mut Imported,
+) -> Result<()> {
+    use crate::database::file::{create_from_reader, fetch_info, update_from_reader, FileIdentifier};
+    use crate::file::NewFile;
+    use std::time::Duration;
+
+    if path.is_empty() {
+        return Err(CabinetError::BadRequest("invalid archive entry path".into()));
+    }
+    let modified = HttpDate::from(UNIX_EPOCH + Duration::from_secs(mtime)).to_string();
+    let mut content = EntryContent {
+        reader: content,
+        remaining: size,
+        error: None,
+    };
+    let res = match fetch_info(conn, FileIdentifier::Path(path.as_ref())) {
+        Ok(file) => update_from_reader(conn, file.id, &mut content, size, mode, &modified),
+        Err(CabinetError::NotFound) => {
+            let new_file = NewFile {
+                path: path.to_string(),
+                content: Vec::new(),
+                mode,
+                modified,
+            };
+            create_from_reader(conn, &new_file, &mut content, size).map(|_| ())
+        }
+        Err(e) => return Err(e),
+    };
+    // Failures to read the entry are errors of the archive, not of storing it
+    if let (Err(_), Some(e)) = (&res, content.error) {
+        return Err(CabinetError::BadRequest(format!("invalid archive: {}: {}", path, e)));
+    }
+    res?;
+    imported.files.push(path.to_string());
+    Ok(())
+}
+
+/// EntryContent reads the content of an archive entry of `remaining` bytes,
+/// keeping the error if the archive is broken or ends before the entry does.
+struct EntryContent<R> {
+    reader: R,
+    remaining: usize,
+    error: Option<String>,
+}
+
+impl<R: Read> Read for EntryContent<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        use std::io::{Error, ErrorKind};
+
+        let res = match self.reader.read(buf) {
+            Ok(0) if self.remaining > 0 && !buf.is_empty() => {
+                Err(Error::new(ErrorKind::UnexpectedEof, "truncated entry"))
+            }
+            res => res,
+        };
+        match &res {
+            Ok(n) => self.remaining = self.remaining.saturating_sub(*n),
+            Err(e) => self.error = Some(e.to_string()),
+        }
+        res
+    }
+}
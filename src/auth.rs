@@ -0,0 +1,235 @@
+//! Token based authentication and authorization of requests.
+
+use crate::database::{block, Pool};
+use crate::request_handlers::destination_path;
+use crate::tls::client_certificate;
+use crate::token::{Resource, Scope, Token};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header, Method};
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use mhlog::err;
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+/// Middleware requiring a valid API token for every request once any token
+/// has been created.
+///
+/// The token is given as `Authorization: Bearer <token>`, or as the password
+/// of basic authentication for WebDAV clients. Read-only tokens are limited
+/// to GET, HEAD, OPTIONS and PROPFIND requests, and tokens with a path
+/// prefix are limited to files and directories under that prefix, including
+/// the destination of MOVE and COPY requests, and to reading boilerplates,
+/// the status and the change feed.
+///
+/// Clients with a verified TLS certificate are authorized as the token named
+/// by its common name instead. The token of an authorized request is stored
+/// in the request extensions.
+///
+pub struct Authentication;
+
+impl<S, B> Transform<S> for Authentication
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuthenticationMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AuthenticationMiddleware {
+            service: Rc::new(RefCell::new(service)),
+        })
+    }
+}
+
+pub struct AuthenticationMiddleware<S> {
+    service: Rc<RefCell<S>>,
+}
+
+impl<S, B> Service for AuthenticationMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            match authorize(&req).await {
+                Ok(Some(token)) => {
+                    req.extensions_mut().insert(token);
+                }
+                Ok(None) => (),
+                Err(resp) => return Ok(req.into_response(resp.into_body())),
+            }
+            let fut = service.borrow_mut().call(req);
+            fut.await
+        })
+    }
+}
+
+/// Authorize a request, returning the token it was made with.
+///
+/// `None` is returned if authentication is disabled, which is the case
+/// until the first token is created. On failure the error response for
+/// the client is returned.
+///
+async fn authorize(req: &ServiceRequest) -> Result<Option<Token>, HttpResponse> {
+    use crate::database::token::{count, fetch_by_hash, fetch_by_name};
+    use crate::token::hash;
+    use crate::CabinetError::NotFound;
+
+    let pool = match req.app_data::<web::Data<Pool>>() {
+        Some(pool) => pool.get_ref().clone(),
+        None => {
+            err!("Database pool missing from application data");
+            return Err(internal_server_error!());
+        }
+    };
+    match block(&pool, |conn| count(conn)).await {
+        Ok(0) => return Ok(None),
+        Ok(_) => (),
+        Err(e) => {
+            err!("Failed to count tokens: {}", e);
+            return Err(internal_server_error!());
+        }
+    }
+
+    //
+    // Authenticate, by client certificate or token secret
+    //
+    let cert = client_certificate(req);
+    let res = match cert {
+        Some(cert) => {
+            let name = match &cert.common_name {
+                Some(name) => name.clone(),
+                None => return Err(forbidden!("client certificate '{}' has no common name", cert.subject)),
+            };
+            match block(&pool, move |conn| fetch_by_name(conn, &name)).await {
+                Err(NotFound) => return Err(forbidden!("no token for client certificate '{}'", cert.subject)),
+                res => res,
+            }
+        }
+        None => {
+            let secret = match bearer_token(req).or_else(|| basic_password(req)) {
+                Some(secret) => secret,
+                None => return Err(unauthorized!("missing token")),
+            };
+            let hash = hash(&secret);
+            match block(&pool, move |conn| fetch_by_hash(conn, &hash)).await {
+                Err(NotFound) => return Err(unauthorized!("invalid token")),
+                res => res,
+            }
+        }
+    };
+    let token = match res {
+        Ok(token) => token,
+        Err(e) => {
+            err!("Failed to fetch token: {}", e);
+            return Err(internal_server_error!());
+        }
+    };
+
+    //
+    // Authorize
+    //
+    let path = resource_path(req.path());
+    let resource = match &path {
+        Some(path) => Resource::Path(path),
+        None if shared_route(req.path()) => Resource::Shared,
+        None => Resource::Other,
+    };
+    if !token.allows(required_scope(req), resource) {
+        return Err(forbidden!("token '{}' does not grant access to {}", token.name, req.path()));
+    }
+    if let Some(dest) = destination_path(req.headers()) {
+        let path = resource_path(dest);
+        let resource = path.as_deref().map_or(Resource::Other, Resource::Path);
+        if !token.allows(Scope::Write, resource) {
+            return Err(forbidden!("token '{}' does not grant access to {}", token.name, dest));
+        }
+    }
+    Ok(Some(token))
+}
+
+fn bearer_token(req: &ServiceRequest) -> Option<String> {
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(|s| s.trim().to_string())
+}
+
+/// Get the password of HTTP basic authentication, which is how WebDAV
+/// clients give the token. The user name is ignored.
+fn basic_password(req: &ServiceRequest) -> Option<String> {
+    let encoded = req
+        .headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    decoded.split_once(':').map(|(_, password)| password.to_string())
+}
+
+fn required_scope(req: &ServiceRequest) -> Scope {
+    match *req.method() {
+        Method::GET | Method::HEAD | Method::OPTIONS => Scope::Read,
+        _ if req.method() == "PROPFIND" => Scope::Read,
+        _ => Scope::Write,
+    }
+}
+
+/// Get the server-side path of the file or directory a request refers to.
+/// The roots of the file tree routes, like `/dav`, refer to the empty path.
+///
+/// `None` is returned for requests outside the file tree.
+///
+fn resource_path(path: &str) -> Option<PathBuf> {
+    use percent_encoding::percent_decode_str;
+
+    const PREFIXES: &[&str] = &["/files", "/dirs", "/revisions/files", "/dav", "/archives/dirs"];
+    let rest = PREFIXES
+        .iter()
+        .filter_map(|p| path.strip_prefix(p))
+        .find(|rest| rest.is_empty() || rest.starts_with('/'))?;
+    let rest = rest.strip_prefix('/').unwrap_or(rest);
+    let decoded = percent_decode_str(rest).decode_utf8_lossy();
+    Some(PathBuf::from(decoded.as_ref()))
+}
+
+/// Check if a request is to a route outside the file tree which tokens
+/// restricted to a path prefix may read. Their handlers only return what
+/// such tokens are allowed to see.
+fn shared_route(path: &str) -> bool {
+    const ROUTES: &[&str] = &[
+        "/status",
+        "/changes",
+        "/events",
+        "/boilerplates",
+        "/revisions/boilerplates",
+        "/archives/boilerplates",
+    ];
+    ROUTES
+        .iter()
+        .any(|route| matches!(path.strip_prefix(route), Some(rest) if rest.is_empty() || rest.starts_with('/')))
+}
@@ -0,0 +1,81 @@
+use crate::boilerplate::Files;
+use crate::CabinetError;
+use serde::{Deserialize, Serialize};
+
+/// A single operation of a batch request.
+///
+/// Operations are given as JSON objects tagged by `op`. Files and
+/// boilerplates may carry preconditions, which must hold when the operation
+/// is applied, after all earlier operations of the batch.
+///
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Operation {
+    /// Create or replace a file. The content is base64 encoded if `base64`
+    /// is set. The mode of an existing file is kept if none is given.
+    PutFile {
+        path: String,
+        content: String,
+        #[serde(default)]
+        base64: bool,
+        mode: Option<u32>,
+        /// ETag the existing file must have.
+        if_match: Option<String>,
+    },
+    DeleteFile {
+        path: String,
+        /// ETag the existing file must have.
+        if_match: Option<String>,
+    },
+    CreateDir {
+        path: String,
+    },
+    /// Create or replace a boilerplate, like a PUT of a boilerplate
+    /// document. The existing script is removed if none is given.
+    PutBoilerplate {
+        name: String,
+        script: Option<String>,
+        files: Files,
+        /// HTTP date the boilerplate must not have been modified after.
+        if_unmodified_since: Option<String>,
+    },
+}
+
+impl Operation {
+    /// The path or name of the file, directory or boilerplate the operation
+    /// applies to.
+    pub fn target(&self) -> &str {
+        match self {
+            Operation::PutFile { path, .. } => path,
+            Operation::DeleteFile { path, .. } => path,
+            Operation::CreateDir { path } => path,
+            Operation::PutBoilerplate { name, .. } => name,
+        }
+    }
+}
+
+/// The change made by an applied operation.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Created,
+    Updated,
+    Deleted,
+    Unchanged,
+}
+
+/// Outcome describes the result of one applied operation, in the response
+/// of a batch request.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize)]
+pub struct Outcome {
+    pub target: String,
+    pub change: Change,
+}
+
+/// Failure describes the operation which made a batch fail, and why.
+#[derive(Debug)]
+pub struct Failure {
+    /// Index of the operation in the batch.
+    pub index: usize,
+    pub error: CabinetError,
+}
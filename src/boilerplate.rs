@@ -7,8 +7,11 @@ use std::convert::TryFrom;
 /// A mapping of client-side file path to server-side file path.
 pub type Files = HashMap<String, String>;
 
-#[derive(Debug, Clone, Eq, PartialEq, Default)]
+/// Boilerplate objects are serialized as the boilerplate document returned
+/// to clients: name, modified date, script and files.
+#[derive(Debug, Clone, Eq, PartialEq, Default, Serialize)]
 pub struct Boilerplate {
+    #[serde(skip)]
     pub id: usize,
     pub name: String,
     pub modified: String,
@@ -30,6 +33,15 @@ impl TryFrom<&Row<'_>> for Boilerplate {
     }
 }
 
+/// A single stored version of a boilerplate's script and files.
+#[derive(Debug, Clone, Eq, PartialEq, Default, Serialize)]
+pub struct BoilerplateRevision {
+    pub revision: usize,
+    pub modified: String,
+    pub script: Option<String>,
+    pub files: Files,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Default, Serialize, Deserialize)]
 pub struct NewBoilerplate {
     pub name: String,
@@ -37,17 +49,36 @@ pub struct NewBoilerplate {
     pub files: Files,
 }
 
+/// The JSON formats accepted for boilerplates: a full boilerplate document,
+/// or the bare files map sent by older clients. The name and modified date
+/// of a document are ignored.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum BoilerplateJson {
+    Document {
+        script: Option<String>,
+        files: Files,
+    },
+    Files(Files),
+}
+
 impl NewBoilerplate {
+    /// Create a boilerplate from either a boilerplate document or a bare
+    /// files map. `script` is only used for bare files maps, which carry
+    /// no script.
     pub fn from_json<T, B>(name: T, script: Option<String>, json: B) -> Result<Self>
     where
         T: AsRef<str>,
         B: AsRef<[u8]>
     {
-        let files: Files = serde_json::from_slice(json.as_ref())?;
+        let (script, files) = match serde_json::from_slice(json.as_ref())? {
+            BoilerplateJson::Document { script, files } => (script, files),
+            BoilerplateJson::Files(files) => (script, files),
+        };
         let bp = NewBoilerplate {
             name: name.as_ref().to_string(),
-            script: script,
-            files: files,
+            script,
+            files,
         };
         Ok(bp)
     }
@@ -0,0 +1,63 @@
+use crate::token::{Resource, Scope, Token};
+use rusqlite::Row;
+use serde::Serialize;
+use std::convert::TryFrom;
+
+/// A logged change of a file, directory or boilerplate.
+///
+/// `kind` is one of `file`, `dir` and `boilerplate`, and `action` one of
+/// `insert`, `update`, `delete` and `move`. The id is the sync cursor of
+/// the change: changes are returned after a cursor in id order.
+///
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize)]
+pub struct Change {
+    pub id: usize,
+    pub kind: String,
+    pub action: String,
+    /// Path of files and directories, name of boilerplates. The new path of
+    /// moved files and directories.
+    pub path: String,
+    /// Previous path of moved files and directories.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_path: Option<String>,
+    /// Time of the change, as an RFC 3339 UTC date.
+    pub time: String,
+}
+
+impl Change {
+    /// Check if `token` may see the change. Tokens limited to a path prefix
+    /// only see changes of files and directories under it.
+    pub fn visible_to(&self, token: &Token) -> bool {
+        self.kind == "boilerplate"
+            || token.allows(Scope::Read, Resource::Path(self.path.as_ref()))
+            || matches!(&self.old_path, Some(old) if token.allows(Scope::Read, Resource::Path(old.as_ref())))
+    }
+
+    /// The paths of the change: its path, and the old path of moves.
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.path.as_str()).chain(self.old_path.as_deref())
+    }
+}
+
+impl TryFrom<&Row<'_>> for Change {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row<'_>) -> Result<Change, Self::Error> {
+        Ok(Change {
+            id: row.get("id")?,
+            kind: row.get("kind")?,
+            action: row.get("action")?,
+            path: row.get("path")?,
+            old_path: row.get("old_path")?,
+            time: row.get("time")?,
+        })
+    }
+}
+
+/// The response to a change feed request: the changes after the requested
+/// cursor, and the cursor to request next.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize)]
+pub struct Changes {
+    pub cursor: usize,
+    pub changes: Vec<Change>,
+}
@@ -46,6 +46,40 @@ macro_rules! bad_request {
     };
 }
 
+//
+// 401 Unauthorized
+//
+#[macro_export]
+macro_rules! unauthorized {
+    () => {
+        actix_web::HttpResponse::Unauthorized()
+            .header("WWW-Authenticate", "Bearer")
+            .header("WWW-Authenticate", "Basic realm=\"Cabinet\"")
+            .body("401 Unauthorized")
+    };
+    ($($arg:tt)+) => {
+        actix_web::HttpResponse::Unauthorized()
+            .header("WWW-Authenticate", "Bearer")
+            .header("WWW-Authenticate", "Basic realm=\"Cabinet\"")
+            .body(format!("401 Unauthorized: {}", format_args!($($arg)+)))
+    };
+}
+
+//
+// 403 Forbidden
+//
+#[macro_export]
+macro_rules! forbidden {
+    () => {
+        actix_web::HttpResponse::Forbidden()
+            .body("403 Forbidden")
+    };
+    ($($arg:tt)+) => {
+        actix_web::HttpResponse::Forbidden()
+            .body(format!("403 Forbidden: {}", format_args!($($arg)+)))
+    };
+}
+
 //
 // 404 Not Found
 //
@@ -61,6 +95,51 @@ macro_rules! not_found {
     };
 }
 
+//
+// 405 Method Not Allowed
+//
+#[macro_export]
+macro_rules! method_not_allowed {
+    () => {
+        actix_web::HttpResponse::MethodNotAllowed()
+            .body("405 Method Not Allowed")
+    };
+    ($($arg:tt)+) => {
+        actix_web::HttpResponse::MethodNotAllowed()
+            .body(format!("405 Method Not Allowed: {}", format_args!($($arg)+)))
+    };
+}
+
+//
+// 409 Conflict
+//
+#[macro_export]
+macro_rules! conflict {
+    () => {
+        actix_web::HttpResponse::Conflict()
+            .body("409 Conflict")
+    };
+    ($($arg:tt)+) => {
+        actix_web::HttpResponse::Conflict()
+            .body(format!("409 Conflict: {}", format_args!($($arg)+)))
+    };
+}
+
+//
+// 410 Gone
+//
+#[macro_export]
+macro_rules! gone {
+    () => {
+        actix_web::HttpResponse::Gone()
+            .body("410 Gone")
+    };
+    ($($arg:tt)+) => {
+        actix_web::HttpResponse::Gone()
+            .body(format!("410 Gone: {}", format_args!($($arg)+)))
+    };
+}
+
 //
 // 412 Precondition Failed
 //
@@ -76,21 +155,51 @@ macro_rules! precondition_failed {
     };
 }
 
+//
+// 415 Unsupported Media Type
+//
+#[macro_export]
+macro_rules! unsupported_media_type {
+    () => {
+        actix_web::HttpResponse::UnsupportedMediaType()
+            .body("415 Unsupported Media Type")
+    };
+    ($($arg:tt)+) => {
+        actix_web::HttpResponse::UnsupportedMediaType()
+            .body(format!("415 Unsupported Media Type: {}", format_args!($($arg)+)))
+    };
+}
+
 //
 // 413 Paiload Too Large
 //
 #[macro_export]
 macro_rules! payload_too_large {
     () => {
-        actix_web::HttpResponse::PreconditionFailed()
+        actix_web::HttpResponse::PayloadTooLarge()
             .body("413 Payload Too Large")
     };
     ($($arg:tt)+) => {
-        actix_web::HttpResponse::PreconditionFailed()
+        actix_web::HttpResponse::PayloadTooLarge()
             .body(format!("413 Payload Too Large: {}", format_args!($($arg)+)))
     };
 }
 
+//
+// 423 Locked
+//
+#[macro_export]
+macro_rules! locked {
+    () => {
+        actix_web::HttpResponse::build(actix_web::http::StatusCode::LOCKED)
+            .body("423 Locked")
+    };
+    ($($arg:tt)+) => {
+        actix_web::HttpResponse::build(actix_web::http::StatusCode::LOCKED)
+            .body(format!("423 Locked: {}", format_args!($($arg)+)))
+    };
+}
+
 //
 // 500 Internal Server Error
 //
@@ -0,0 +1,441 @@
+//! Compression of response bodies and decoding of request bodies.
+//!
+//! Responses are compressed with zstd, brotli or gzip, as negotiated by the
+//! Accept-Encoding header of the request. Content types which are already
+//! compressed, like images and archives, are passed on unchanged, as are
+//! event streams, which must reach clients without buffering.
+
+use actix_web::dev::{BodySize, MessageBody, ResponseBody, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header, HeaderMap, StatusCode};
+use actix_web::web::Bytes;
+use actix_web::Error;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use pin_project_lite::pin_project;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Responses smaller than this are not worth compressing.
+const MIN_SIZE: u64 = 256;
+
+/// Content codings supported for responses and request bodies.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Encoding {
+    Zstd,
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// All encodings, in order of preference when clients accept several
+    /// equally.
+    const PREFERRED: [Encoding; 3] = [Encoding::Zstd, Encoding::Brotli, Encoding::Gzip];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Choose the encoding of a response from the Accept-Encoding header of
+    /// the request. `None` means the response is sent as is.
+    pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+        let mut accepted = Vec::new();
+        for item in accept_encoding.split(',') {
+            let mut params = item.split(';').map(str::trim);
+            let coding = params.next().unwrap_or_default().to_ascii_lowercase();
+            let quality = params
+                .find_map(|p| p.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())
+                .unwrap_or(0.0);
+            accepted.push((coding, quality));
+        }
+        let quality = |name: &str| {
+            let explicit = accepted.iter().find(|(coding, _)| coding == name);
+            let wildcard = accepted.iter().find(|(coding, _)| coding == "*");
+            explicit.or(wildcard).map_or(0.0, |(_, q)| *q)
+        };
+
+        let mut best: Option<(Encoding, f32)> = None;
+        for encoding in Encoding::PREFERRED {
+            let q = match encoding {
+                // Older clients name gzip x-gzip
+                Encoding::Gzip => quality("gzip").max(quality("x-gzip")),
+                _ => quality(encoding.name()),
+            };
+            if q > 0.0 && best.is_none_or(|(_, best)| q > best) {
+                best = Some((encoding, q));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    /// Get the encoding of a request body from its Content-Encoding header.
+    /// `None` means the body is not encoded.
+    pub fn of_request(headers: &HeaderMap) -> Result<Option<Encoding>, String> {
+        let val = match headers.get(header::CONTENT_ENCODING) {
+            Some(val) => val.to_str().map_err(|e| e.to_string())?,
+            None => return Ok(None),
+        };
+        match val.trim().to_ascii_lowercase().as_str() {
+            "" | "identity" => Ok(None),
+            "zstd" => Ok(Some(Encoding::Zstd)),
+            "br" => Ok(Some(Encoding::Brotli)),
+            "gzip" | "x-gzip" => Ok(Some(Encoding::Gzip)),
+            _ => Err(format!("unsupported content encoding: {}", val)),
+        }
+    }
+}
+
+/// Check if responses of a content type are worth compressing.
+fn compressible(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    let (kind, subtype) = match essence.split_once('/') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    match kind {
+        "text" => subtype != "event-stream",
+        "image" => subtype == "svg+xml",
+        "audio" | "video" | "font" => false,
+        "application" => {
+            !subtype.ends_with("+zip")
+                && !subtype.starts_with("vnd.openxmlformats")
+                && !matches!(
+                    subtype,
+                    "zip" | "gzip" | "x-gzip" | "zstd" | "x-bzip2" | "x-xz" | "x-7z-compressed"
+                        | "vnd.rar" | "x-rar-compressed" | "java-archive" | "pdf" | "wasm"
+                )
+        }
+        _ => true,
+    }
+}
+
+/*******************************************************************************
+ *                                                                             *
+ * Encoding
+ *                                                                             *
+ *******************************************************************************/
+
+/// Streaming compressor, collecting its output in memory.
+enum Encoder {
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> io::Result<Encoder> {
+        use flate2::Compression;
+
+        Ok(match encoding {
+            Encoding::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), 3)?),
+            // Quality 5 compresses almost as well as the maximum 11, at a
+            // fraction of the time
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22))),
+            Encoding::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(Vec::new(), Compression::default())),
+        })
+    }
+
+    /// Compress a chunk, returning the compressed output produced so far.
+    fn encode(&mut self, chunk: &[u8]) -> io::Result<Bytes> {
+        let out = match self {
+            Encoder::Zstd(e) => {
+                e.write_all(chunk)?;
+                e.get_mut()
+            }
+            Encoder::Brotli(e) => {
+                e.write_all(chunk)?;
+                e.get_mut()
+            }
+            Encoder::Gzip(e) => {
+                e.write_all(chunk)?;
+                e.get_mut()
+            }
+        };
+        Ok(Bytes::from(std::mem::take(out)))
+    }
+
+    /// Finish compressing, returning the remaining output.
+    fn finish(self) -> io::Result<Bytes> {
+        let out = match self {
+            Encoder::Zstd(e) => e.finish()?,
+            Encoder::Brotli(e) => e.into_inner(),
+            Encoder::Gzip(e) => e.finish()?,
+        };
+        Ok(Bytes::from(out))
+    }
+}
+
+pin_project! {
+    /// Response body which is compressed while it is sent, or passed on
+    /// unchanged.
+    pub struct Encoded<B> {
+        #[pin]
+        body: ResponseBody<B>,
+        encoder: Option<Encoder>,
+        finished: bool,
+    }
+}
+
+impl<B: MessageBody> MessageBody for Encoded<B> {
+    fn size(&self) -> BodySize {
+        match (&self.encoder, self.finished) {
+            (None, false) => self.body.size(),
+            _ => BodySize::Stream,
+        }
+    }
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Error>>> {
+        let mut this = self.project();
+        if *this.finished {
+            return Poll::Ready(None);
+        }
+        let encoder = match this.encoder {
+            Some(encoder) => encoder,
+            None => return this.body.poll_next(cx),
+        };
+        loop {
+            match futures::ready!(this.body.as_mut().poll_next(cx)) {
+                Some(Ok(chunk)) => match encoder.encode(&chunk) {
+                    Ok(out) if out.is_empty() => continue,
+                    Ok(out) => return Poll::Ready(Some(Ok(out))),
+                    Err(e) => return Poll::Ready(Some(Err(e.into()))),
+                },
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => {
+                    *this.finished = true;
+                    let res = this.encoder.take().unwrap().finish();
+                    return Poll::Ready(Some(res.map_err(Into::into)));
+                }
+            }
+        }
+    }
+}
+
+/// Decide if a response should be compressed, and add the headers of the
+/// encoding if so.
+fn start_encoding(head: &mut actix_web::dev::ResponseHead, size: BodySize, encoding: Option<Encoding>) -> Option<Encoder> {
+    let headers = &head.headers;
+    let status = head.status;
+    if !status.is_success() || status == StatusCode::PARTIAL_CONTENT || status == StatusCode::NO_CONTENT {
+        return None;
+    }
+    if headers.contains_key(header::CONTENT_ENCODING) || headers.contains_key(header::CONTENT_RANGE) {
+        return None;
+    }
+    match headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+        Some(content_type) if compressible(content_type) => (),
+        _ => return None,
+    }
+    // The response depends on Accept-Encoding whether compressed or not
+    head.headers.append(header::VARY, header::HeaderValue::from_static("accept-encoding"));
+    match size {
+        BodySize::Sized(n) if n >= MIN_SIZE => (),
+        BodySize::Stream => (),
+        _ => return None,
+    }
+
+    let encoding = encoding?;
+    let encoder = Encoder::new(encoding).ok()?;
+    head.headers.remove(header::CONTENT_LENGTH);
+    head.headers.insert(header::CONTENT_ENCODING, header::HeaderValue::from_static(encoding.name()));
+    // The encoded content differs from the stored one, so its ETag is weak
+    let weak = match head.headers.get(header::ETAG).and_then(|v| v.to_str().ok()) {
+        Some(etag) if !etag.starts_with("W/") => header::HeaderValue::from_str(&format!("W/{}", etag)).ok(),
+        _ => None,
+    };
+    if let Some(etag) = weak {
+        head.headers.insert(header::ETAG, etag);
+    }
+    Some(encoder)
+}
+
+/*******************************************************************************
+ *                                                                             *
+ * Middleware
+ *                                                                             *
+ *******************************************************************************/
+
+/// Middleware compressing response bodies as negotiated by the
+/// Accept-Encoding header of the request.
+///
+/// Only successful responses with a compressible content type are
+/// compressed. Partial content is always sent as is, so ranges refer to the
+/// stored content.
+///
+pub struct Compress;
+
+impl<S, B> Transform<S> for Compress
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Encoded<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CompressMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CompressMiddleware { service })
+    }
+}
+
+pub struct CompressMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for CompressMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Encoded<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let encoding = match req.headers().get(header::ACCEPT_ENCODING).and_then(|v| v.to_str().ok()) {
+            Some(val) if req.method() != actix_web::http::Method::HEAD => Encoding::negotiate(val),
+            _ => None,
+        };
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let res = fut.await?;
+            Ok(res.map_body(move |head, body| {
+                let encoder = start_encoding(head, body.size(), encoding);
+                ResponseBody::Body(Encoded {
+                    body,
+                    encoder,
+                    finished: false,
+                })
+            }))
+        })
+    }
+}
+
+/*******************************************************************************
+ *                                                                             *
+ * Decoding
+ *                                                                             *
+ *******************************************************************************/
+
+/// Writer decoding a request body, writing the decoded content to `W`.
+pub enum Decoder<W: Write> {
+    Identity(W),
+    // The zstd writer of `zstd::stream::write` can't tell if the last frame
+    // is complete, so the underlying writer is used instead.
+    Zstd(zstd::stream::zio::Writer<W, zstd::stream::raw::Decoder<'static>>),
+    Brotli(Box<brotli::DecompressorWriter<W>>),
+    Gzip(flate2::write::GzDecoder<W>),
+}
+
+impl<W: Write> Decoder<W> {
+    pub fn new(encoding: Option<Encoding>, inner: W) -> io::Result<Decoder<W>> {
+        Ok(match encoding {
+            None => Decoder::Identity(inner),
+            Some(Encoding::Zstd) => {
+                Decoder::Zstd(zstd::stream::zio::Writer::new(inner, zstd::stream::raw::Decoder::new()?))
+            }
+            Some(Encoding::Brotli) => Decoder::Brotli(Box::new(brotli::DecompressorWriter::new(inner, 4096))),
+            Some(Encoding::Gzip) => Decoder::Gzip(flate2::write::GzDecoder::new(inner)),
+        })
+    }
+
+    /// Finish decoding, returning the inner writer. Fails if the encoded
+    /// content is incomplete.
+    pub fn finish(self) -> io::Result<W> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "incomplete encoded content");
+        match self {
+            Decoder::Identity(w) => Ok(w),
+            Decoder::Zstd(mut d) => {
+                d.flush()?;
+                d.finish()?;
+                Ok(d.into_inner().0)
+            }
+            Decoder::Brotli(mut d) => {
+                d.close()?;
+                d.into_inner().map_err(|_| invalid())
+            }
+            Decoder::Gzip(d) => d.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Decoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Decoder::Identity(w) => w.write(buf),
+            Decoder::Zstd(d) => d.write(buf),
+            Decoder::Brotli(d) => d.write(buf),
+            Decoder::Gzip(d) => d.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Decoder::Identity(w) => w.flush(),
+            Decoder::Zstd(d) => d.flush(),
+            Decoder::Brotli(d) => d.flush(),
+            Decoder::Gzip(d) => d.flush(),
+        }
+    }
+}
+
+/// Writer failing with `ErrorKind::FileTooLarge` once more than `max` bytes
+/// are written, guarding against small request bodies decoding to huge
+/// content.
+pub struct Limit<W> {
+    inner: W,
+    written: usize,
+    max: usize,
+}
+
+impl<W: Write> Limit<W> {
+    pub fn new(inner: W, max: usize) -> Limit<W> {
+        Limit { inner, written: 0, max }
+    }
+
+    /// Number of bytes written.
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for Limit<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written + buf.len() > self.max {
+            return Err(io::ErrorKind::FileTooLarge.into());
+        }
+        let n = self.inner.write(buf)?;
+        self.written += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decode an encoded request body held in memory, failing with
+/// `ErrorKind::FileTooLarge` if the content is larger than `max` bytes.
+pub fn decode(encoding: Option<Encoding>, body: &[u8], max: usize) -> io::Result<Vec<u8>> {
+    let mut decoder = Decoder::new(encoding, Limit::new(Vec::new(), max))?;
+    decoder.write_all(body)?;
+    Ok(decoder.finish()?.into_inner())
+}
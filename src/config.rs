@@ -0,0 +1,204 @@
+//! Server configuration.
+//!
+//! The configuration is built from, in increasing order of precedence:
+//! built-in defaults, a TOML configuration file, `CABINET_*` environment
+//! variables and command line options.
+//!
+//! Example configuration file:
+//!
+//! ```toml
+//! database = "/var/lib/cabinet/cabinet.sqlite"
+//! bind = ["0.0.0.0:8080", "[::]:8080"]
+//! max-upload-size = 67108864
+//! log-level = "info"
+//! workers = 4
+//! pool-size = 8
+//! busy-timeout = 5000
+//! tls-certificate = "/etc/cabinet/fullchain.pem"
+//! tls-key = "/etc/cabinet/privkey.pem"
+//! tls-client-ca = "/etc/cabinet/clients.pem"
+//! redirect = ["0.0.0.0:80"]
+//! ```
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+
+/// Environment variable naming the configuration file.
+pub const CONFIG_ENV: &str = "CABINET_CONFIG";
+
+const LOG_LEVELS: &[&str] = &["off", "error", "warn", "info", "debug", "trace"];
+
+/// Server configuration shared with the request handlers.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Path of the SQLite database.
+    pub database: PathBuf,
+    /// Addresses to listen on, as `HOST:PORT`.
+    pub bind: Vec<String>,
+    /// Largest accepted file upload, in bytes.
+    pub max_upload_size: usize,
+    /// Default log level, used unless `RUST_LOG` is set.
+    pub log_level: String,
+    /// Number of worker threads. Defaults to the number of CPUs.
+    pub workers: Option<usize>,
+    /// Maximum number of open database connections.
+    pub pool_size: u32,
+    /// Time to wait for a locked database, in milliseconds.
+    pub busy_timeout: u64,
+    /// PEM file with the TLS certificate chain. The server only accepts
+    /// HTTPS when set.
+    pub tls_certificate: Option<PathBuf>,
+    /// PEM file with the private key of the TLS certificate.
+    pub tls_key: Option<PathBuf>,
+    /// PEM file with the CA certificates client certificates are verified
+    /// against. Clients may only authenticate with certificates when set.
+    pub tls_client_ca: Option<PathBuf>,
+    /// Addresses to listen on for plain HTTP, redirecting every request to
+    /// HTTPS.
+    pub redirect: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            database: PathBuf::from("cabinet.sqlite"),
+            bind: vec!["127.0.0.1:8080".to_string()],
+            max_upload_size: 64 * 1024 * 1024,
+            log_level: "info".to_string(),
+            workers: None,
+            pool_size: 8,
+            busy_timeout: 5000,
+            tls_certificate: None,
+            tls_key: None,
+            tls_client_ca: None,
+            redirect: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration file at `path`. Settings missing from the
+    /// file have their default values.
+    pub fn from_file(path: &Path) -> Result<Config> {
+        let txt = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read configuration file {:?}", path))?;
+        let config = toml::from_str(&txt)
+            .with_context(|| format!("Invalid configuration file {:?}", path))?;
+        Ok(config)
+    }
+
+    /// Override settings with the `CABINET_*` environment variables which
+    /// are set.
+    pub fn apply_env(&mut self) -> Result<()> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+
+        if let Some(val) = var("CABINET_DATABASE") {
+            self.database = val.into();
+        }
+        if let Some(val) = var("CABINET_BIND") {
+            self.bind = val.split(',').map(|s| s.trim().to_string()).collect();
+        }
+        if let Some(val) = var("CABINET_MAX_UPLOAD_SIZE") {
+            self.max_upload_size = val
+                .parse()
+                .with_context(|| format!("Invalid CABINET_MAX_UPLOAD_SIZE: {}", val))?;
+        }
+        if let Some(val) = var("CABINET_LOG_LEVEL") {
+            self.log_level = val;
+        }
+        if let Some(val) = var("CABINET_WORKERS") {
+            let workers = val
+                .parse()
+                .with_context(|| format!("Invalid CABINET_WORKERS: {}", val))?;
+            self.workers = Some(workers);
+        }
+        if let Some(val) = var("CABINET_POOL_SIZE") {
+            self.pool_size = val
+                .parse()
+                .with_context(|| format!("Invalid CABINET_POOL_SIZE: {}", val))?;
+        }
+        if let Some(val) = var("CABINET_BUSY_TIMEOUT") {
+            self.busy_timeout = val
+                .parse()
+                .with_context(|| format!("Invalid CABINET_BUSY_TIMEOUT: {}", val))?;
+        }
+        if let Some(val) = var("CABINET_TLS_CERTIFICATE") {
+            self.tls_certificate = Some(val.into());
+        }
+        if let Some(val) = var("CABINET_TLS_KEY") {
+            self.tls_key = Some(val.into());
+        }
+        if let Some(val) = var("CABINET_TLS_CLIENT_CA") {
+            self.tls_client_ca = Some(val.into());
+        }
+        if let Some(val) = var("CABINET_REDIRECT") {
+            self.redirect = val.split(',').map(|s| s.trim().to_string()).collect();
+        }
+        Ok(())
+    }
+
+    /// Check that all settings are usable, returning an error describing
+    /// the first one which is not.
+    pub fn validate(&self) -> Result<()> {
+        if self.database.as_os_str().is_empty() {
+            bail!("Invalid configuration: database path is empty");
+        }
+        if let Some(dir) = self.database.parent() {
+            if !dir.as_os_str().is_empty() && !dir.is_dir() {
+                bail!("Invalid configuration: database directory does not exist: {:?}", dir);
+            }
+        }
+        if self.bind.is_empty() {
+            bail!("Invalid configuration: no bind addresses");
+        }
+        for addr in &self.bind {
+            bind_addrs(addr)?;
+        }
+        if self.max_upload_size == 0 {
+            bail!("Invalid configuration: max-upload-size must be larger than 0");
+        }
+        if !LOG_LEVELS.contains(&self.log_level.as_str()) {
+            bail!(
+                "Invalid configuration: unknown log-level '{}', expected one of: {}",
+                self.log_level,
+                LOG_LEVELS.join(", ")
+            );
+        }
+        if self.workers == Some(0) {
+            bail!("Invalid configuration: workers must be larger than 0");
+        }
+        if self.pool_size == 0 {
+            bail!("Invalid configuration: pool-size must be larger than 0");
+        }
+        match (&self.tls_certificate, &self.tls_key) {
+            (Some(_), None) => bail!("Invalid configuration: tls-certificate is set without tls-key"),
+            (None, Some(_)) => bail!("Invalid configuration: tls-key is set without tls-certificate"),
+            (None, None) if !self.redirect.is_empty() => {
+                bail!("Invalid configuration: redirect requires tls-certificate and tls-key")
+            }
+            (None, None) if self.tls_client_ca.is_some() => {
+                bail!("Invalid configuration: tls-client-ca requires tls-certificate and tls-key")
+            }
+            _ => (),
+        }
+        for addr in &self.redirect {
+            bind_addrs(addr)?;
+        }
+        Ok(())
+    }
+}
+
+/// Resolve a `HOST:PORT` bind address.
+pub fn bind_addrs(addr: &str) -> Result<Vec<SocketAddr>> {
+    let addrs: Vec<_> = addr
+        .to_socket_addrs()
+        .with_context(|| format!("Invalid configuration: invalid bind address '{}'", addr))?
+        .collect();
+    if addrs.is_empty() {
+        bail!("Invalid configuration: bind address '{}' did not resolve", addr);
+    }
+    Ok(addrs)
+}
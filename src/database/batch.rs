@@ -0,0 +1,251 @@
+use crate::batch::{Change, Failure, Operation, Outcome};
+use crate::{CabinetError, CabinetResult as Result};
+use rusqlite::{Connection, Transaction};
+
+/// Apply all operations of a batch in a single transaction, stamping every
+/// change with `modified`.
+///
+/// Either all operations are applied, or none. The operation which made the
+/// batch fail is returned as a `Failure`, while errors of the database
+/// itself are returned as errors.
+///
+pub fn apply(
+    conn: &mut Connection,
+    ops: &[Operation],
+    modified: &str,
+) -> Result<std::result::Result<Vec<Outcome>, Failure>> {
+    use rusqlite::TransactionBehavior::Immediate;
+
+    let tx = conn.transaction_with_behavior(Immediate)?;
+    let mut outcomes = Vec::with_capacity(ops.len());
+    for (index, op) in ops.iter().enumerate() {
+        match apply_one(&tx, op, modified) {
+            Ok(change) => outcomes.push(Outcome {
+                target: op.target().to_string(),
+                change,
+            }),
+            // Dropping the transaction rolls it back
+            Err(error) => return Ok(Err(Failure { index, error })),
+        }
+    }
+    tx.commit()?;
+    Ok(Ok(outcomes))
+}
+
+fn apply_one(conn: &Transaction<'_>, op: &Operation, modified: &str) -> Result<Change> {
+    match op {
+        Operation::PutFile { path, content, base64, mode, if_match } => {
+            let content = if *base64 {
+                base64::decode(content).map_err(|e| CabinetError::BadRequest(format!("invalid base64: {}", e)))?
+            } else {
+                content.clone().into_bytes()
+            };
+            put_file(conn, path, &content, *mode, if_match.as_deref(), modified)
+        }
+        Operation::DeleteFile { path, if_match } => delete_file(conn, path, if_match.as_deref()),
+        Operation::CreateDir { path } => create_dir(conn, path),
+        Operation::PutBoilerplate { name, script, files, if_unmodified_since } => {
+            use crate::boilerplate::NewBoilerplate;
+            use crate::database::boilerplate::{fetch, upsert, BoilerplateIdentifier::Name};
+            use actix_web::http::header::HttpDate;
+            use std::str::FromStr;
+
+            if let Some(date) = if_unmodified_since {
+                let date = HttpDate::from_str(date)
+                    .map_err(|_| CabinetError::BadRequest(format!("invalid date: {}", date)))?;
+                match fetch(conn, Name(name)) {
+                    Ok(bp) if HttpDate::from_str(&bp.modified)? > date => {
+                        return Err(CabinetError::PreconditionFailed)
+                    }
+                    Ok(_) | Err(CabinetError::NotFound) => (),
+                    Err(e) => return Err(e),
+                }
+            }
+            let new = NewBoilerplate {
+                name: name.clone(),
+                script: script.clone(),
+                files: files.clone(),
+            };
+            match upsert(conn, &new, modified)? {
+                true => Ok(Change::Updated),
+                false => Ok(Change::Created),
+            }
+        }
+    }
+}
+
+/// Fetch a file, checking that it has the ETag `if_match` if given.
+fn fetch_matching(conn: &Connection, path: &str, if_match: Option<&str>) -> Result<Option<crate::file::FileInfo>> {
+    use crate::database::file::{fetch_info, FileIdentifier::Path};
+
+    let file = match fetch_info(conn, Path(path.as_ref())) {
+        Ok(file) => Some(file),
+        Err(CabinetError::NotFound) => None,
+        Err(e) => return Err(e),
+    };
+    if let Some(etag) = if_match {
+        let etag = etag.trim_matches('"');
+        match &file {
+            Some(file) if etag == "*" || etag == file.hash => (),
+            _ => return Err(CabinetError::PreconditionFailed),
+        }
+    }
+    Ok(file)
+}
+
+fn put_file(
+    conn: &Transaction<'_>,
+    path: &str,
+    content: &[u8],
+    mode: Option<u32>,
+    if_match: Option<&str>,
+    modified: &str,
+) -> Result<Change> {
+    use crate::database::file::{create, update_from_reader};
+    use crate::file::NewFile;
+
+    if matches!(mode, Some(mode) if mode > 0o7777) {
+        return Err(CabinetError::BadRequest(format!("invalid file mode: {:o}", mode.unwrap())));
+    }
+    match fetch_matching(conn, path, if_match)? {
+        Some(file) => {
+            let mode = mode.unwrap_or(file.mode);
+            update_from_reader(conn, file.id, content, content.len(), mode, modified)?;
+            Ok(Change::Updated)
+        }
+        None => {
+            let new_file = NewFile {
+                path: path.trim_matches('/').to_string(),
+                content: content.to_vec(),
+                mode: mode.unwrap_or(0o644),
+                modified: modified.to_string(),
+            };
+            create(conn, &new_file)?;
+            Ok(Change::Created)
+        }
+    }
+}
+
+fn delete_file(conn: &Connection, path: &str, if_match: Option<&str>) -> Result<Change> {
+    use crate::database::boilerplate::file_used_in_boilerplates;
+    use crate::database::file::{delete, FileIdentifier::Id};
+
+    let file = match fetch_matching(conn, path, if_match)? {
+        Some(file) => file,
+        None => return Err(CabinetError::NotFound),
+    };
+    let bps = file_used_in_boilerplates(conn, file.id)?;
+    if !bps.is_empty() {
+        return Err(CabinetError::BadRequest(format!("file is used in boilerplates:\n{}", bps.join("\n"))));
+    }
+    delete(conn, Id(file.id))?;
+    Ok(Change::Deleted)
+}
+
+fn create_dir(conn: &Connection, path: &str) -> Result<Change> {
+    use crate::database::dir::{create, exists, DirIdentifier::Path};
+
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        return Err(CabinetError::BadRequest("empty directory path".into()));
+    }
+    if exists(conn, Path(path.as_ref()))? {
+        return Ok(Change::Unchanged);
+    }
+    create(conn, path.as_ref())?;
+    Ok(Change::Created)
+}
+
+/*******************************************************************************
+ *                                                                             *
+ * Tests
+ *                                                                             *
+ *******************************************************************************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::file::{fetch, FileIdentifier::Path};
+    use anyhow::Result;
+    use std::collections::HashMap;
+
+    const DATE: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+    fn db() -> Result<Connection> {
+        use crate::database::migrations::migrate;
+        let conn = Connection::open_in_memory()?;
+        migrate(&conn)?;
+        Ok(conn)
+    }
+
+    fn put(path: &str, content: &str) -> Operation {
+        Operation::PutFile {
+            path: path.into(),
+            content: content.into(),
+            base64: false,
+            mode: None,
+            if_match: None,
+        }
+    }
+
+    #[test]
+    fn apply_all() -> Result<()> {
+        let mut conn = db()?;
+        let mut files = HashMap::new();
+        files.insert(".zshrc".to_string(), "dotfiles/zshrc".to_string());
+        let ops = vec![
+            Operation::CreateDir { path: "empty".into() },
+            put("dotfiles/zshrc", "hello"),
+            Operation::PutBoilerplate {
+                name: "dotfiles".into(),
+                script: None,
+                files,
+                if_unmodified_since: None,
+            },
+        ];
+        let outcomes = apply(&mut conn, &ops, DATE)?.unwrap();
+        let changes: Vec<Change> = outcomes.iter().map(|o| o.change).collect();
+        assert_eq!(changes, vec![Change::Created; 3]);
+        assert_eq!(fetch(&conn, Path("dotfiles/zshrc".as_ref()))?.content, b"hello");
+        Ok(())
+    }
+
+    #[test]
+    fn put_boilerplate_replaces_script() -> Result<()> {
+        use crate::database::boilerplate::{fetch, BoilerplateIdentifier::Name};
+
+        let mut conn = db()?;
+        let put_bp = |script: Option<&str>| Operation::PutBoilerplate {
+            name: "bp".into(),
+            script: script.map(String::from),
+            files: HashMap::new(),
+            if_unmodified_since: None,
+        };
+        apply(&mut conn, &[put_bp(Some("make install"))], DATE)?.unwrap();
+        assert_eq!(fetch(&conn, Name("bp"))?.script.as_deref(), Some("make install"));
+        apply(&mut conn, &[put_bp(None)], DATE)?.unwrap();
+        assert_eq!(fetch(&conn, Name("bp"))?.script, None);
+        Ok(())
+    }
+
+    #[test]
+    fn apply_nothing_on_failure() -> Result<()> {
+        let mut conn = db()?;
+        apply(&mut conn, &[put("a.txt", "old")], DATE)?.unwrap();
+
+        let ops = vec![
+            put("a.txt", "new"),
+            put("b.txt", "new"),
+            Operation::DeleteFile {
+                path: "a.txt".into(),
+                if_match: Some("\"not the etag\"".into()),
+            },
+        ];
+        let failure = apply(&mut conn, &ops, DATE)?.unwrap_err();
+        assert_eq!(failure.index, 2);
+        assert!(matches!(failure.error, CabinetError::PreconditionFailed));
+        assert_eq!(fetch(&conn, Path("a.txt".as_ref()))?.content, b"old");
+        assert!(fetch(&conn, Path("b.txt".as_ref())).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,619 @@
+//! Interface for content blobs in the database.
+//!
+//! File content is stored once per unique content, keyed by its SHA-1 hash,
+//! and shared by all files and file revisions with that content. Blob
+//! reference counts are maintained by triggers, which also delete blobs no
+//! longer referenced by anything.
+//!
+//! Content is stored compressed with zstd, unless compressing does not make
+//! it any smaller. Small text content is compressed with a dictionary
+//! trained on the stored files, once one is trained. The codec of every blob
+//! is stored with it, and content is decompressed transparently when read.
+
+use crate::CabinetResult as Result;
+use rusqlite::blob::Blob;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::{Connection, DatabaseName, OptionalExtension, Transaction, TransactionBehavior};
+use std::borrow::Cow;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::str::FromStr;
+
+/// Size of the chunks content is read in when streamed from the database.
+pub const CHUNK_SIZE: usize = 65_536;
+
+/// zstd level content is compressed with. Higher levels compress better,
+/// but slow down uploads.
+const LEVEL: i32 = 3;
+
+/// Content up to this size is compressed in memory, and is compressed with
+/// the dictionary if it is text.
+const SMALL_SIZE: usize = 16_384;
+
+/// Largest size of a trained dictionary.
+const DICTIONARY_SIZE: usize = 16_384;
+
+/// Number of small text blobs needed to train a dictionary, and the most
+/// a dictionary is trained on.
+const MIN_SAMPLES: usize = 16;
+const MAX_SAMPLES: usize = 10_000;
+
+/// Codec describes how the content of a blob is stored.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Codec {
+    Raw,
+    Zstd,
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Codec::Raw),
+            "zstd" => Ok(Codec::Zstd),
+            _ => Err(format!("Unknown blob codec: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Codec::Raw => write!(f, "raw"),
+            Codec::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl ToSql for Codec {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.to_string()))
+    }
+}
+
+impl FromSql for Codec {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: String| FromSqlError::Other(e.into()))
+    }
+}
+
+#[cfg(test)]
+pub fn count(conn: &Connection) -> Result<usize> {
+    let mut stmt = conn.prepare("SELECT count(*) FROM blob")?;
+    let count = stmt.query_row([], |row| row.get(0))?;
+    Ok(count)
+}
+
+/// Store `content`, returning the id of the blob containing it.
+///
+/// If identical content is already stored the existing blob is reused.
+///
+pub fn store(conn: &Connection, content: &[u8]) -> Result<usize> {
+    let hash = hash(content);
+    if let Some(id) = get_id(conn, &hash)? {
+        return Ok(id);
+    }
+    let dictionary = if is_small_text(content) { latest_dictionary(conn)? } else { None };
+    let (codec, dictionary, stored) = encode(content, dictionary.as_ref())?;
+    let mut stmt = conn.prepare("INSERT INTO blob(hash, size, codec, dictionary, content) VALUES (?, ?, ?, ?, ?)")?;
+    let id = stmt.insert(params![hash, content.len(), codec, dictionary, stored.as_ref()])?;
+    Ok(id as usize)
+}
+
+/// Store `size` bytes of content streamed from `reader`, returning the id
+/// of the blob containing it.
+///
+/// Large content is hashed and compressed into temporary files, and only
+/// written to a new blob if identical content isn't stored already.
+///
+pub fn store_from_reader<R: Read>(conn: &Connection, mut reader: R, size: usize) -> Result<usize> {
+    use sha1::{Digest, Sha1};
+
+    //
+    // Small content is compressed in memory
+    //
+    if size <= SMALL_SIZE {
+        let mut content = Vec::with_capacity(size);
+        reader.take(size as u64).read_to_end(&mut content)?;
+        if content.len() < size {
+            return_error!("Expected {} bytes of content, got {}", size, content.len());
+        }
+        return store(conn, &content);
+    }
+
+    //
+    // Hash and compress content, keeping the original in case compressing
+    // doesn't make it smaller
+    //
+    let mut hasher = Sha1::new();
+    let mut raw = tempfile::tempfile()?;
+    let mut encoder = zstd::stream::write::Encoder::new(tempfile::tempfile()?, LEVEL)?;
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut written = 0;
+    while written < size {
+        let n = reader.read(&mut buf[..CHUNK_SIZE.min(size - written)])?;
+        if n == 0 {
+            return_error!("Expected {} bytes of content, got {}", size, written);
+        }
+        hasher.update(&buf[..n]);
+        raw.write_all(&buf[..n])?;
+        encoder.write_all(&buf[..n])?;
+        written += n;
+    }
+
+    //
+    // Deduplicate
+    //
+    let hash = hex::encode(hasher.finalize());
+    if let Some(existing) = get_id(conn, &hash)? {
+        return Ok(existing);
+    }
+
+    //
+    // Write the smaller of the compressed and original content
+    //
+    let mut compressed = encoder.finish()?;
+    let (codec, mut content) = if compressed.stream_position()? < size as u64 {
+        (Codec::Zstd, compressed)
+    } else {
+        (Codec::Raw, raw)
+    };
+    let id = conn
+        .prepare("INSERT INTO blob(size, codec, content) VALUES (?, ?, zeroblob(?))")?
+        .insert(params![size, codec, content.stream_position()?])? as usize;
+    write_content(conn, id, &mut content)?;
+    conn.prepare("UPDATE blob SET hash=? WHERE id IS ?")?
+        .execute(params![hash, id])?;
+    Ok(id)
+}
+
+/// Overwrite the stored content of a blob with the content of `file`,
+/// which must have the same size.
+fn write_content(conn: &Connection, id: usize, file: &mut std::fs::File) -> Result<()> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut blob = conn.blob_open(DatabaseName::Main, "blob", "content", id as i64, false)?;
+    io::copy(file, &mut blob)?;
+    blob.close()?;
+    Ok(())
+}
+
+/// Open the content of a blob for incremental reading.
+pub fn open(conn: &Connection, id: usize) -> Result<Content<'_>> {
+    Ok(Content {
+        conn,
+        id,
+        reader: open_reader(conn, id)?,
+        position: 0,
+    })
+}
+
+fn open_reader(conn: &Connection, id: usize) -> Result<Reader<'_>> {
+    let (codec, dictionary): (Codec, Option<usize>) = query_row!(conn,
+        "SELECT codec, dictionary FROM blob WHERE id IS ?" => |row| Ok((row.get(0)?, row.get(1)?));
+        id
+    )?;
+    let blob = conn.blob_open(DatabaseName::Main, "blob", "content", id as i64, true)?;
+    let reader = match codec {
+        Codec::Raw => Reader::Raw(blob),
+        Codec::Zstd => {
+            let dictionary = match dictionary {
+                Some(id) => fetch_dictionary(conn, id)?,
+                None => Vec::new(),
+            };
+            let blob = BufReader::with_capacity(CHUNK_SIZE, blob);
+            Reader::Zstd(zstd::stream::read::Decoder::with_dictionary(blob, &dictionary)?)
+        }
+    };
+    Ok(reader)
+}
+
+/// Content is a reader of the content of a blob, decompressing it while
+/// it's read.
+///
+/// Seeking in compressed content decompresses the content up to the new
+/// position, and seeking backwards starts over from the beginning.
+///
+pub struct Content<'a> {
+    conn: &'a Connection,
+    id: usize,
+    reader: Reader<'a>,
+    position: u64,
+}
+
+enum Reader<'a> {
+    Raw(Blob<'a>),
+    Zstd(zstd::stream::read::Decoder<'static, BufReader<Blob<'a>>>),
+}
+
+impl Read for Content<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = match &mut self.reader {
+            Reader::Raw(blob) => blob.read(buf)?,
+            Reader::Zstd(decoder) => decoder.read(buf)?,
+        };
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for Content<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        if let Reader::Raw(blob) = &mut self.reader {
+            self.position = blob.seek(pos)?;
+            return Ok(self.position);
+        }
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => self.position.checked_add_signed(n),
+            SeekFrom::End(_) => None,
+        };
+        let target = target.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek in compressed content"))?;
+        if target < self.position {
+            self.reader = open_reader(self.conn, self.id).map_err(|e| io::Error::other(e.to_string()))?;
+            self.position = 0;
+        }
+        let skip = target - self.position;
+        io::copy(&mut self.by_ref().take(skip), &mut io::sink())?;
+        Ok(self.position)
+    }
+}
+
+/// Return the id of the blob with the given content hash.
+pub fn get_id(conn: &Connection, hash: &str) -> Result<Option<usize>> {
+    let mut stmt = conn.prepare("SELECT id FROM blob WHERE hash IS ?")?;
+    let id = stmt.query_row([hash], |row| row.get(0)).optional()?;
+    Ok(id)
+}
+
+/// Compute the content hash used to identify blobs.
+pub fn hash(content: &[u8]) -> String {
+    use sha1::{Digest, Sha1};
+
+    let mut hasher = Sha1::new();
+    hasher.update(content);
+    hex::encode(hasher.finalize())
+}
+
+/*******************************************************************************
+ *                                                                             *
+ * Compression
+ *                                                                             *
+ *******************************************************************************/
+
+/// Dictionary used to compress small text content.
+pub struct Dictionary {
+    pub id: usize,
+    pub content: Vec<u8>,
+}
+
+/// Check if content is compressed with the dictionary.
+fn is_small_text(content: &[u8]) -> bool {
+    content.len() <= SMALL_SIZE && std::str::from_utf8(content).is_ok()
+}
+
+/// Compress content for storage, with `dictionary` if given. Returns the
+/// codec, the id of the dictionary and the content to store. Content which
+/// compressing doesn't make smaller is stored raw.
+fn encode<'a>(content: &'a [u8], dictionary: Option<&Dictionary>) -> io::Result<(Codec, Option<usize>, Cow<'a, [u8]>)> {
+    let compressed = match dictionary {
+        Some(dict) => zstd::bulk::Compressor::with_dictionary(LEVEL, &dict.content)?.compress(content)?,
+        None => zstd::bulk::compress(content, LEVEL)?,
+    };
+    if compressed.len() < content.len() {
+        Ok((Codec::Zstd, dictionary.map(|d| d.id), Cow::Owned(compressed)))
+    } else {
+        Ok((Codec::Raw, None, Cow::Borrowed(content)))
+    }
+}
+
+/// Decompress stored content, which was compressed with `dictionary` if
+/// given.
+pub fn decode(codec: Codec, content: Vec<u8>, dictionary: Option<&[u8]>) -> io::Result<Vec<u8>> {
+    match codec {
+        Codec::Raw => Ok(content),
+        Codec::Zstd => {
+            let mut decoder = zstd::stream::read::Decoder::with_dictionary(&content[..], dictionary.unwrap_or_default())?;
+            let mut decoded = Vec::new();
+            decoder.read_to_end(&mut decoded)?;
+            Ok(decoded)
+        }
+    }
+}
+
+/// Return the latest dictionary, which new small text content is compressed
+/// with.
+pub fn latest_dictionary(conn: &Connection) -> Result<Option<Dictionary>> {
+    let dictionary = conn
+        .prepare("SELECT id, content FROM blob_dictionary ORDER BY id DESC LIMIT 1")?
+        .query_row([], |row| Ok(Dictionary { id: row.get(0)?, content: row.get(1)? }))
+        .optional()?;
+    Ok(dictionary)
+}
+
+fn fetch_dictionary(conn: &Connection, id: usize) -> Result<Vec<u8>> {
+    let content = query_row!(conn,
+        "SELECT content FROM blob_dictionary WHERE id IS ?" => |row| row.get(0);
+        id
+    )?;
+    Ok(content)
+}
+
+/// Train a new dictionary on the small text content stored, which is then
+/// used for new small text content. Returns the id of the dictionary, or
+/// `None` if there is too little small text content to train on.
+pub fn train_dictionary(conn: &Connection) -> Result<Option<usize>> {
+    let mut stmt = conn.prepare(
+        "SELECT blob.codec, blob.content, blob_dictionary.content
+           FROM blob LEFT JOIN blob_dictionary ON blob.dictionary=blob_dictionary.id
+          WHERE blob.hash IS NOT NULL AND blob.size > 0 AND blob.size <= ?
+          ORDER BY blob.id DESC LIMIT ?",
+    )?;
+    let mut rows = stmt.query(params![SMALL_SIZE, MAX_SAMPLES])?;
+    let mut samples = Vec::new();
+    while let Some(row) = rows.next()? {
+        let dictionary: Option<Vec<u8>> = row.get(2)?;
+        let content = decode(row.get(0)?, row.get(1)?, dictionary.as_deref())?;
+        if is_small_text(&content) {
+            samples.push(content);
+        }
+    }
+    if samples.len() < MIN_SAMPLES {
+        return Ok(None);
+    }
+
+    let dictionary = zstd::dict::from_samples(&samples, DICTIONARY_SIZE)?;
+    let id = conn
+        .prepare("INSERT INTO blob_dictionary(content) VALUES (?)")?
+        .insert([&dictionary])?;
+    Ok(Some(id as usize))
+}
+
+/// Totals of a recompression of the stored content.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Recompressed {
+    /// Number of blobs recompressed.
+    pub blobs: usize,
+    /// Stored size of the recompressed blobs, before and after.
+    pub before: u64,
+    pub after: u64,
+}
+
+/// Recompress every blob which isn't stored as new content would be: raw
+/// content compressing makes smaller, and small text content not compressed
+/// with the latest dictionary. Dictionaries no longer used are deleted.
+///
+/// Each blob is recompressed in its own transaction, so the server may keep
+/// running meanwhile.
+///
+pub fn recompress(conn: &Connection) -> Result<Recompressed> {
+    let latest = latest_dictionary(conn)?;
+    let blobs: Vec<(usize, u64, Codec, Option<usize>)> = conn
+        .prepare("SELECT id, size, codec, dictionary FROM blob WHERE hash IS NOT NULL ORDER BY id")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let latest_id = latest.as_ref().map(|d| d.id);
+    let mut totals = Recompressed::default();
+    for (id, size, codec, dictionary) in blobs {
+        let small = size <= SMALL_SIZE as u64;
+        if codec == Codec::Zstd && (!small || dictionary == latest_id) {
+            continue;
+        }
+        if let Some((before, after)) = recompress_blob(conn, id, size, latest.as_ref())? {
+            totals.blobs += 1;
+            totals.before += before;
+            totals.after += after;
+        }
+    }
+
+    conn.prepare(
+        "DELETE FROM blob_dictionary
+          WHERE id NOT IN (SELECT dictionary FROM blob WHERE dictionary IS NOT NULL)
+            AND id IS NOT (SELECT max(id) FROM blob_dictionary)",
+    )?
+    .execute([])?;
+    Ok(totals)
+}
+
+/// Recompress a blob in its own transaction. Returns its stored size before
+/// and after, or `None` if it is stored the same way or no longer exists.
+fn recompress_blob(conn: &Connection, id: usize, size: u64, latest: Option<&Dictionary>) -> Result<Option<(u64, u64)>> {
+    // The blob may have been deleted with its last file since it was listed,
+    // but can't change once the write lock is held.
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    let before: Option<u64> =
+        query_row!(tx, "SELECT length(content) FROM blob WHERE id IS ?" => |row| row.get(0); id).optional()?;
+    let before = match before {
+        Some(before) => before,
+        None => return Ok(None),
+    };
+    let after = if size <= SMALL_SIZE as u64 {
+        recompress_small(&tx, id, latest)?
+    } else {
+        recompress_large(&tx, id, size)?
+    };
+    tx.commit()?;
+    Ok(after.map(|after| (before, after)))
+}
+
+/// Recompress small content in memory. Returns the new stored size, or
+/// `None` if the blob would be stored the same way.
+fn recompress_small(conn: &Connection, id: usize, latest: Option<&Dictionary>) -> Result<Option<u64>> {
+    let (old_codec, stored, old_dictionary, dictionary): (Codec, Vec<u8>, Option<usize>, Option<Vec<u8>>) = query_row!(conn,
+        "SELECT blob.codec, blob.content, blob.dictionary, blob_dictionary.content
+           FROM blob LEFT JOIN blob_dictionary ON blob.dictionary=blob_dictionary.id
+          WHERE blob.id IS ?" => |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?));
+        id
+    )?;
+    let content = decode(old_codec, stored, dictionary.as_deref())?;
+    let latest = latest.filter(|_| is_small_text(&content));
+    let (codec, dictionary, stored) = encode(&content, latest)?;
+    if (codec, dictionary) == (old_codec, old_dictionary) {
+        return Ok(None);
+    }
+    conn.prepare("UPDATE blob SET codec=?, dictionary=?, content=? WHERE id IS ?")?
+        .execute(params![codec, dictionary, stored.as_ref(), id])?;
+    Ok(Some(stored.len() as u64))
+}
+
+/// Compress large raw content through a temporary file. Returns the new
+/// stored size, or `None` if compressing doesn't make the content smaller.
+fn recompress_large(conn: &Connection, id: usize, size: u64) -> Result<Option<u64>> {
+    let mut encoder = zstd::stream::write::Encoder::new(tempfile::tempfile()?, LEVEL)?;
+    io::copy(&mut open(conn, id)?, &mut encoder)?;
+    let mut compressed = encoder.finish()?;
+    let len = compressed.stream_position()?;
+    if len >= size {
+        return Ok(None);
+    }
+    conn.prepare("UPDATE blob SET codec=?, dictionary=NULL, content=zeroblob(?) WHERE id IS ?")?
+        .execute(params![Codec::Zstd, len, id])?;
+    write_content(conn, id, &mut compressed)?;
+    Ok(Some(len))
+}
+
+/*******************************************************************************
+ *                                                                             *
+ * Tests
+ *                                                                             *
+ *******************************************************************************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use rusqlite::Connection;
+
+    fn db() -> Result<Connection> {
+        use crate::database::migrations::migrate;
+        let conn = Connection::open_in_memory()?;
+        migrate(&conn)?;
+        Ok(conn)
+    }
+
+    #[test]
+    fn store_deduplicates() -> Result<()> {
+        let conn = db()?;
+
+        let id = store(&conn, b"hello").unwrap();
+        assert_eq!(store(&conn, b"hello").unwrap(), id);
+        let content: &[u8] = b"hello";
+        assert_eq!(store_from_reader(&conn, content, 5).unwrap(), id);
+        assert_eq!(count(&conn).unwrap(), 1);
+
+        let content: &[u8] = b"world";
+        let other = store_from_reader(&conn, content, 5).unwrap();
+        assert_ne!(other, id);
+        assert_eq!(get_id(&conn, &hash(b"world")).unwrap(), Some(other));
+        assert_eq!(count(&conn).unwrap(), 2);
+
+        let content: &[u8] = b"short";
+        assert!(store_from_reader(&conn, content, 10).is_err());
+
+        let mut buf = String::new();
+        open(&conn, other).unwrap().read_to_string(&mut buf)?;
+        assert_eq!(buf, "world");
+
+        Ok(())
+    }
+
+    fn stored(conn: &Connection, id: usize) -> Result<(Codec, usize, usize)> {
+        let stored = conn.query_row(
+            "SELECT codec, size, length(content) FROM blob WHERE id IS ?",
+            [id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        Ok(stored)
+    }
+
+    #[test]
+    fn store_compresses() -> Result<()> {
+        let conn = db()?;
+        let text = "Hello compression!\n".repeat(5000);
+        let random: Vec<u8> = (0..100_000).map(|_| rand::random()).collect();
+
+        //
+        // Content is only compressed if it gets smaller
+        //
+        let small = store(&conn, &text.as_bytes()[..1000]).unwrap();
+        let (codec, size, len) = stored(&conn, small)?;
+        assert_eq!((codec, size), (Codec::Zstd, 1000));
+        assert!(len < size);
+        let large = store_from_reader(&conn, text.as_bytes(), text.len()).unwrap();
+        assert_eq!(stored(&conn, large)?.0, Codec::Zstd);
+        assert_eq!(store(&conn, text.as_bytes()).unwrap(), large);
+        let raw = store_from_reader(&conn, &random[..], random.len()).unwrap();
+        assert_eq!(stored(&conn, raw)?, (Codec::Raw, random.len(), random.len()));
+        assert_eq!(stored(&conn, store(&conn, b"tiny").unwrap())?.0, Codec::Raw);
+
+        //
+        // Content is decompressed while read, also when seeking
+        //
+        let mut buf = String::new();
+        open(&conn, large).unwrap().read_to_string(&mut buf)?;
+        assert_eq!(buf, text);
+        let mut content = open(&conn, large).unwrap();
+        let mut buf = [0; 5];
+        content.seek(SeekFrom::Start(40_001))?;
+        content.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"compr");
+        content.seek(SeekFrom::Start(0))?;
+        content.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"Hello");
+        assert!(content.seek(SeekFrom::End(0)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn dictionary() -> Result<()> {
+        let conn = db()?;
+        let sample = |i: usize| format!("[section{}]\nname = \"value {}\"\nenabled = true\ncolor = \"auto\"\n", i, i * 7);
+        assert!(train_dictionary(&conn).unwrap().is_none());
+
+        let raw: Vec<usize> = (0..200).map(|i| store(&conn, sample(i).as_bytes()).unwrap()).collect();
+        assert!(raw.iter().all(|&id| stored(&conn, id).unwrap().0 == Codec::Raw));
+        let first = train_dictionary(&conn).unwrap().unwrap();
+
+        //
+        // New small text content is compressed with the latest dictionary
+        //
+        let new = store(&conn, sample(1000).as_bytes()).unwrap();
+        assert_eq!(stored(&conn, new)?.0, Codec::Zstd);
+        let dictionary: Option<usize> =
+            conn.query_row("SELECT dictionary FROM blob WHERE id IS ?", [new], |row| row.get(0))?;
+        assert_eq!(dictionary, Some(first));
+
+        //
+        // Recompressing moves content to the latest dictionary, and deletes
+        // dictionaries no longer used
+        //
+        let totals = recompress(&conn).unwrap();
+        assert_eq!(totals.blobs, raw.len());
+        assert!(totals.after < totals.before);
+        assert_eq!(recompress(&conn).unwrap(), Recompressed::default());
+        let second = train_dictionary(&conn).unwrap().unwrap();
+        assert_eq!(recompress(&conn).unwrap().blobs, raw.len() + 1);
+        let dictionaries: Vec<usize> = conn
+            .prepare("SELECT id FROM blob_dictionary")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(dictionaries, vec![second]);
+
+        let mut buf = String::new();
+        open(&conn, raw[42]).unwrap().read_to_string(&mut buf)?;
+        assert_eq!(buf, sample(42));
+
+        //
+        // Blobs deleted since they were listed are skipped
+        //
+        let gone = store(&conn, sample(2000).as_bytes()).unwrap();
+        conn.execute("DELETE FROM blob WHERE id IS ?", [gone])?;
+        assert_eq!(recompress_blob(&conn, gone, 100, None).unwrap(), None);
+
+        Ok(())
+    }
+}
@@ -1,15 +1,18 @@
-use crate::boilerplate::{Boilerplate, NewBoilerplate};
+use crate::boilerplate::{Boilerplate, BoilerplateRevision, Files, NewBoilerplate};
 use crate::{CabinetError, CabinetResult as Result};
-use rusqlite::Connection;
+use actix_web::http::header::HttpDate;
+use rusqlite::{Connection, Transaction};
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::str::FromStr;
 
-pub async fn count(conn: &Connection) -> Result<usize> {
+pub fn count(conn: &Connection) -> Result<usize> {
     let mut stmt = conn.prepare("SELECT count(*) FROM boilerplate")?;
     let count = stmt.query_row([], |row| row.get(0))?;
     Ok(count)
 }
 
-pub async fn all_names(conn: &Connection) -> Result<Vec<String>> {
+pub fn all_names(conn: &Connection) -> Result<Vec<String>> {
     let mut stmt = conn.prepare("SELECT DISTINCT name FROM boilerplate")?;
     let mut names = Vec::new();
     for res in stmt.query_map([], |row| row.get("name"))? {
@@ -18,8 +21,8 @@ pub async fn all_names(conn: &Connection) -> Result<Vec<String>> {
     Ok(names)
 }
 
-pub async fn fetch(conn: &Connection, ident: BoilerplateIdentifier<'_>) -> Result<Boilerplate> {
-    let id = ident.get_id(conn).await?;
+pub fn fetch(conn: &Connection, ident: BoilerplateIdentifier<'_>) -> Result<Boilerplate> {
+    let id = ident.get_id(conn)?;
     if id.is_none() {
         return Err(CabinetError::NotFound);
     }
@@ -32,7 +35,7 @@ pub async fn fetch(conn: &Connection, ident: BoilerplateIdentifier<'_>) -> Resul
     // Boilerplate files
     //
     let mut stmt = conn.prepare("SELECT path, location FROM bp_files WHERE bp_id IS ?")?;
-    let mut rows = stmt.query(&[&id])?;
+    let mut rows = stmt.query([&id])?;
     while let Some(row) = rows.next()? {
         bp.files.insert(row.get("location")?, row.get("path")?);
     }
@@ -40,136 +43,331 @@ pub async fn fetch(conn: &Connection, ident: BoilerplateIdentifier<'_>) -> Resul
 }
 
 #[allow(dead_code)]
-pub async fn exists(conn: &Connection, ident: BoilerplateIdentifier<'_>) -> Result<bool> {
-    let id = ident.get_id(conn).await?;
+pub fn exists(conn: &Connection, ident: BoilerplateIdentifier<'_>) -> Result<bool> {
+    let id = ident.get_id(conn)?;
     let mut found = false;
     if let Some(id) = id {
         let mut stmt = conn.prepare("SELECT id FROM boilerplate WHERE id IS ?")?;
-        found = stmt.exists(&[&id])?;
+        found = stmt.exists([&id])?;
     }
     Ok(found)
 }
 
-pub async fn create(conn: &mut Connection, new: &NewBoilerplate) -> Result<usize> {
-    use crate::database::file::FileIdentifier::Path;
-    use crate::CabinetError::BadRequest;
-    use actix_web::http::header::HttpDate;
+pub fn create(conn: &mut Connection, new: &NewBoilerplate) -> Result<usize> {
     use std::time::SystemTime;
 
     let date = HttpDate::from(SystemTime::now());
     let tx = conn.transaction()?;
-
-    let bp_id: usize;
-    {
-        //
-        // Insert boilerplate
-        //
-        let mut stmt =
-            tx.prepare("INSERT INTO boilerplate(name, modified, script) VALUES (?, ?, ?)")?;
-        stmt.insert(params![new.name, date.to_string(), new.script])?;
-
-        //
-        // Insert boilerplate files
-        //
-        let mut stmt = tx.prepare("SELECT id FROM boilerplate WHERE name IS ?")?;
-        bp_id = stmt.query_row(params![new.name], |row| row.get(0))?;
-        let mut insert_stmt =
-            tx.prepare("INSERT INTO bp_file_map(boilerplate, file, location) VALUES (?, ?, ?)")?;
-
-        for (file_path_client, file_path_server) in &new.files {
-            let p = Path(file_path_server.as_ref());
-            let file_id: usize = match p.get_id(&tx).await? {
-                Some(file_id) => file_id,
-                None => {
-                    return Err(BadRequest(format!(
-                        "Boilerplate references non-existing file: {}",
-                        file_path_server
-                    )))
-                }
-            };
-            insert_stmt.execute(params![bp_id, file_id, file_path_client])?;
-        }
-    }
-
+    let bp_id = insert(&tx, new, &date.to_string())?;
     tx.commit()?;
     Ok(bp_id)
 }
 
-pub async fn update(conn: &mut Connection, bp: &Boilerplate) -> Result<usize> {
+/// Insert a boilerplate entry and its files. Returns the id of the new
+/// boilerplate.
+fn insert(conn: &Connection, new: &NewBoilerplate, modified: &str) -> Result<usize> {
     use crate::database::file::FileIdentifier::Path;
     use crate::CabinetError::BadRequest;
-    use actix_web::http::header::HttpDate;
-    use std::time::SystemTime;
 
-    let date = HttpDate::from(SystemTime::now());
-    let tx = conn.transaction()?;
+    //
+    // Insert boilerplate
+    //
+    let mut stmt =
+        conn.prepare("INSERT INTO boilerplate(name, modified, script) VALUES (?, ?, ?)")?;
+    stmt.insert(params![new.name, modified, new.script])?;
 
-    {
-        //
-        // Insert boilerplate
-        //
-        let mut stmt = tx.prepare(
-            "UPDATE boilerplate
-                SET name=?, modified=?, script=?
-              WHERE id IS ?",
-        )?;
-        stmt.insert(params![bp.name, date.to_string(), bp.script, bp.id])?;
-
-        //
-        // Insert boilerplate files
-        //
-        tx.prepare("DELETE FROM bp_file_map WHERE boilerplate IS ?")?
-            .execute(&[&bp.id])?;
-        let mut insert_stmt =
-            tx.prepare("INSERT INTO bp_file_map(boilerplate, file, location) VALUES (?, ?, ?)")?;
-
-        for (file_path_client, file_path_server) in &bp.files {
-            let p = Path(file_path_server.as_ref());
-            let file_id: usize = match p.get_id(&tx).await? {
-                Some(file_id) => file_id,
-                None => {
-                    return Err(BadRequest(format!(
-                        "Boilerplate references non-existing file: {}",
-                        file_path_server
-                    )))
-                }
-            };
-            insert_stmt.execute(params![bp.id, file_id, file_path_client])?;
+    //
+    // Insert boilerplate files
+    //
+    let mut stmt = conn.prepare("SELECT id FROM boilerplate WHERE name IS ?")?;
+    let bp_id: usize = stmt.query_row(params![new.name], |row| row.get(0))?;
+    let mut insert_stmt =
+        conn.prepare("INSERT INTO bp_file_map(boilerplate, file, location) VALUES (?, ?, ?)")?;
+
+    for (file_path_client, file_path_server) in &new.files {
+        let p = Path(file_path_server.as_ref());
+        let file_id: usize = match p.get_id(conn)? {
+            Some(file_id) => file_id,
+            None => {
+                return Err(BadRequest(format!(
+                    "Boilerplate references non-existing file: {}",
+                    file_path_server
+                )))
+            }
+        };
+        insert_stmt.execute(params![bp_id, file_id, file_path_client])?;
+    }
+    Ok(bp_id)
+}
+
+/// Create a boilerplate, or update it if it already exists, as part of an
+/// ongoing transaction. The script and files of `new` replace the existing
+/// ones, so an existing script is removed if `new` has none.
+///
+/// Returns whether the boilerplate already existed.
+///
+pub fn upsert(conn: &Transaction<'_>, new: &NewBoilerplate, modified: &str) -> Result<bool> {
+    match fetch(conn, BoilerplateIdentifier::Name(&new.name)) {
+        Ok(mut bp) => {
+            bp.script = new.script.clone();
+            bp.files = new.files.clone();
+            update(conn, &bp, modified)?;
+            Ok(true)
+        }
+        Err(CabinetError::NotFound) => {
+            insert(conn, new, modified)?;
+            Ok(false)
         }
+        Err(e) => Err(e),
     }
+}
 
-    tx.commit()?;
-    Ok(bp.id)
+/// Update a boilerplate entry and its files as part of an ongoing
+/// transaction, keeping the replaced state as a revision.
+pub fn update(conn: &Transaction<'_>, bp: &Boilerplate, modified: &str) -> Result<()> {
+    use crate::database::file::FileIdentifier::Path;
+    use crate::CabinetError::BadRequest;
+
+    archive(conn, bp.id)?;
+
+    //
+    // Update boilerplate
+    //
+    let mut stmt = conn.prepare(
+        "UPDATE boilerplate
+            SET name=?, modified=?, script=?
+          WHERE id IS ?",
+    )?;
+    stmt.insert(params![bp.name, modified, bp.script, bp.id])?;
+
+    //
+    // Insert boilerplate files
+    //
+    conn.prepare("DELETE FROM bp_file_map WHERE boilerplate IS ?")?
+        .execute([&bp.id])?;
+    let mut insert_stmt =
+        conn.prepare("INSERT INTO bp_file_map(boilerplate, file, location) VALUES (?, ?, ?)")?;
+
+    for (file_path_client, file_path_server) in &bp.files {
+        let p = Path(file_path_server.as_ref());
+        let file_id: usize = match p.get_id(conn)? {
+            Some(file_id) => file_id,
+            None => {
+                return Err(BadRequest(format!(
+                    "Boilerplate references non-existing file: {}",
+                    file_path_server
+                )))
+            }
+        };
+        insert_stmt.execute(params![bp.id, file_id, file_path_client])?;
+    }
+    Ok(())
 }
 
-pub async fn delete(conn: &Connection, ident: BoilerplateIdentifier<'_>) -> Result<()> {
-    let id = ident.get_id(conn).await?;
+pub fn delete(conn: &Connection, ident: BoilerplateIdentifier<'_>) -> Result<()> {
+    let id = ident.get_id(conn)?;
     if id.is_none() {
         return Err(CabinetError::NotFound);
     }
     conn.prepare("DELETE FROM boilerplate WHERE id IS ?")?
-        .execute(&[&id])?;
+        .execute([&id])?;
     Ok(())
 }
 
 /// Get the list of all boilerplates which includes the given file.
-pub async fn file_used_in_boilerplates(conn: &Connection, file_id: usize) -> Result<Vec<String>> {
+pub fn file_used_in_boilerplates(conn: &Connection, file_id: usize) -> Result<Vec<String>> {
     let mut stmt = conn.prepare(
         "SELECT DISTINCT boilerplate.name AS name
            FROM boilerplate JOIN bp_file_map ON boilerplate.id=bp_file_map.boilerplate
           WHERE bp_file_map.file IS ?",
     )?;
     let mut boilerplates = Vec::new();
-    for res in stmt.query_map(&[&file_id], |row| row.get("name"))? {
+    for res in stmt.query_map([&file_id], |row| row.get("name"))? {
         boilerplates.push(res?)
     }
     Ok(boilerplates)
 }
 
-pub async fn get_id(conn: &Connection, name: &str) -> Result<Option<usize>> {
+/*******************************************************************************
+ *                                                                             *
+ * Revisions
+ *                                                                             *
+ *******************************************************************************/
+
+/// Keep the current state of a boilerplate as a revision, as part of the
+/// transaction which replaces it.
+fn archive(conn: &Transaction<'_>, id: usize) -> Result<()> {
+    let mut files: HashMap<String, usize> = HashMap::new();
+    let mut stmt = conn.prepare("SELECT file, location FROM bp_file_map WHERE boilerplate IS ?")?;
+    let mut rows = stmt.query([&id])?;
+    while let Some(row) = rows.next()? {
+        files.insert(row.get("location")?, row.get("file")?);
+    }
+    let revision = current_revision(conn, id)?;
+    conn.prepare(
+        "INSERT INTO bp_revision(boilerplate, revision, modified, script, files)
+         SELECT id, ?, modified, script, ? FROM boilerplate WHERE id IS ?",
+    )?
+    .execute(params![revision, serde_json::to_string(&files)?, id])?;
+    Ok(())
+}
+
+/// Return the revision number of the current version of a boilerplate.
+pub fn current_revision(conn: &Connection, id: usize) -> Result<usize> {
+    let rev = query_row!(conn,
+        "SELECT coalesce(max(revision), 0) + 1 FROM bp_revision WHERE boilerplate IS ?" => |row| row.get(0);
+        id
+    )?;
+    Ok(rev)
+}
+
+/// List all revisions of a boilerplate, oldest first. The last entry is the
+/// current version.
+///
+/// Files which have been deleted since a revision was made are left out.
+///
+pub fn revisions(
+    conn: &Connection,
+    ident: BoilerplateIdentifier<'_>,
+) -> Result<Vec<BoilerplateRevision>> {
+    let bp = fetch(conn, ident)?;
+    let mut stmt = conn.prepare(
+        "SELECT revision, modified, script, files
+           FROM bp_revision
+          WHERE boilerplate IS ? ORDER BY revision",
+    )?;
+    let mut rows = stmt.query([&bp.id])?;
+    let mut revisions = Vec::new();
+    while let Some(row) = rows.next()? {
+        let files: String = row.get("files")?;
+        let files: HashMap<String, usize> = serde_json::from_str(&files)?;
+        let mut rev = BoilerplateRevision {
+            revision: row.get("revision")?,
+            modified: row.get("modified")?,
+            script: row.get("script")?,
+            files: Files::new(),
+        };
+        for (location, id) in files {
+            if let Some(path) = file_path(conn, id)? {
+                rev.files.insert(location, path);
+            }
+        }
+        revisions.push(rev);
+    }
+    revisions.push(BoilerplateRevision {
+        revision: current_revision(conn, bp.id)?,
+        modified: bp.modified,
+        script: bp.script,
+        files: bp.files,
+    });
+    Ok(revisions)
+}
+
+/// Find the revision of a boilerplate which was current at the given date.
+///
+/// If `None` is returned the boilerplate didn't exist at that date.
+///
+pub fn revision_at(conn: &Connection, id: usize, date: &HttpDate) -> Result<Option<usize>> {
+    let mut stmt = conn.prepare(
+        "SELECT revision, modified FROM bp_revision WHERE boilerplate IS ?
+         UNION ALL
+         SELECT (SELECT coalesce(max(revision), 0) + 1 FROM bp_revision WHERE boilerplate IS ?), modified
+           FROM boilerplate WHERE id IS ?
+         ORDER BY revision",
+    )?;
+    let mut rows = stmt.query(params![id, id, id])?;
+    let mut found = None;
+    while let Some(row) = rows.next()? {
+        let modified: String = row.get("modified")?;
+        if HttpDate::from_str(&modified)? > *date {
+            break;
+        }
+        found = Some(row.get("revision")?);
+    }
+    Ok(found)
+}
+
+/// Restore a boilerplate to the script and files of a previous revision.
+///
+/// If `date` is given, every file of the restored boilerplate is also
+/// restored to the revision which was current at that date. Files which
+/// didn't exist at that date are left as they are.
+///
+/// The restore is recorded as a new modification, as part of an ongoing
+/// transaction.
+///
+pub fn restore(
+    tx: &Transaction<'_>,
+    ident: BoilerplateIdentifier<'_>,
+    revision: usize,
+    date: Option<&HttpDate>,
+) -> Result<()> {
+    use crate::database::file;
+    use crate::database::file::FileIdentifier;
+    use crate::CabinetError::BadRequest;
+    use rusqlite::OptionalExtension;
+    use std::time::SystemTime;
+
+    let now = HttpDate::from(SystemTime::now()).to_string();
+    let mut bp = fetch(tx, ident)?;
+
+    //
+    // Boilerplate script and files
+    //
+    if revision != current_revision(tx, bp.id)? {
+        let (script, files): (Option<String>, String) = tx
+            .prepare("SELECT script, files FROM bp_revision WHERE boilerplate IS ? AND revision IS ?")?
+            .query_row(params![bp.id, revision], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()?
+            .ok_or(CabinetError::NotFound)?;
+        let files: HashMap<String, usize> = serde_json::from_str(&files)?;
+        bp.script = script;
+        bp.files = Files::new();
+        for (location, id) in files {
+            match file_path(tx, id)? {
+                Some(path) => bp.files.insert(location, path),
+                None => {
+                    return Err(BadRequest(format!(
+                        "Boilerplate revision references deleted file: {}",
+                        location
+                    )))
+                }
+            };
+        }
+    }
+
+    //
+    // File content
+    //
+    if let Some(date) = date {
+        for path in bp.files.values() {
+            let id = match file::get_id(tx, path.as_ref())? {
+                Some(id) => id,
+                None => continue,
+            };
+            if let Some(rev) = file::revision_at(tx, id, date)? {
+                if rev != file::current_revision(tx, id)? {
+                    file::restore(tx, FileIdentifier::Id(id), rev, &now)?;
+                }
+            }
+        }
+    }
+
+    update(tx, &bp, &now)?;
+    Ok(())
+}
+
+fn file_path(conn: &Connection, id: usize) -> Result<Option<String>> {
+    use rusqlite::OptionalExtension;
+    let mut stmt = conn.prepare("SELECT path FROM file_path WHERE id IS ?")?;
+    let path = stmt.query_row([&id], |row| row.get(0)).optional()?;
+    Ok(path)
+}
+
+pub fn get_id(conn: &Connection, name: &str) -> Result<Option<usize>> {
     use rusqlite::OptionalExtension;
     let mut stmt = conn.prepare("SELECT id FROM boilerplate WHERE name IS ?")?;
-    let id = stmt.query_row(&[name], |row| row.get(0)).optional()?;
+    let id = stmt.query_row([name], |row| row.get(0)).optional()?;
     Ok(id)
 }
 
@@ -186,10 +384,10 @@ pub enum BoilerplateIdentifier<'a> {
 }
 
 impl BoilerplateIdentifier<'_> {
-    async fn get_id(&self, conn: &Connection) -> Result<Option<usize>> {
+    fn get_id(&self, conn: &Connection) -> Result<Option<usize>> {
         match self {
             BoilerplateIdentifier::Id(id) => Ok(Some(*id)),
-            BoilerplateIdentifier::Name(name) => get_id(conn, name).await,
+            BoilerplateIdentifier::Name(name) => get_id(conn, name),
         }
     }
 }
@@ -207,20 +405,21 @@ mod tests {
     use rusqlite::Connection;
     use std::collections::HashMap;
 
-    async fn db() -> Result<Connection> {
-        use crate::database::create_tables;
+    fn db() -> Result<Connection> {
+        use crate::database::migrations::migrate;
         let conn = Connection::open_in_memory()?;
-        create_tables(&conn).await?;
+        migrate(&conn)?;
+        let blob = crate::database::blob::store(&conn, &[])?;
         conn.execute(
             "INSERT INTO file VALUES (1, 'myfile', NULL, ?, 493, 164123532)",
-            params![Vec::new()],
+            params![blob],
         )?;
         Ok(conn)
     }
 
-    #[async_std::test]
-    async fn all_boilerplate_functions() -> Result<()> {
-        let mut conn = db().await?;
+    #[test]
+    fn all_boilerplate_functions() -> Result<()> {
+        let mut conn = db()?;
         let mut files = HashMap::new();
         files.insert("myfile".to_string(), "myfile".to_string());
 
@@ -231,11 +430,11 @@ mod tests {
         };
         let name_ident = BoilerplateIdentifier::Name(&new_bp.name);
 
-        assert!(!exists(&conn, name_ident.clone()).await.unwrap());
-        create(&mut conn, &new_bp).await?;
-        assert!(exists(&conn, name_ident.clone()).await.unwrap());
+        assert!(!exists(&conn, name_ident.clone()).unwrap());
+        create(&mut conn, &new_bp)?;
+        assert!(exists(&conn, name_ident.clone()).unwrap());
 
-        let mut bp = fetch(&conn, name_ident.clone()).await.unwrap();
+        let mut bp = fetch(&conn, name_ident.clone()).unwrap();
         assert_eq!(new_bp.name, bp.name);
         assert_eq!(new_bp.script, bp.script);
         assert_eq!(new_bp.files, bp.files);
@@ -243,16 +442,59 @@ mod tests {
         let id_ident = BoilerplateIdentifier::Id(bp.id);
         bp.name = "Updated Boilerplate".into();
         bp.script = Some("sudo apt get awesomeness".into());
-        update(&mut conn, &bp).await.unwrap();
-        assert_eq!(bp, fetch(&conn, id_ident.clone()).await.unwrap());
+        let tx = conn.transaction()?;
+        update(&tx, &bp, &bp.modified).unwrap();
+        tx.commit()?;
+        assert_eq!(bp, fetch(&conn, id_ident.clone()).unwrap());
 
         let names = vec![bp.name];
-        let res = all_names(&conn).await.unwrap();
+        let res = all_names(&conn).unwrap();
         assert_eq!(res, names);
 
-        let res = file_used_in_boilerplates(&conn, 1).await.unwrap();
+        let res = file_used_in_boilerplates(&conn, 1).unwrap();
         assert_eq!(res, names);
 
         Ok(())
     }
+
+    #[test]
+    fn boilerplate_revisions() -> Result<()> {
+        let mut conn = db()?;
+        let mut files = HashMap::new();
+        files.insert("myfile".to_string(), "myfile".to_string());
+
+        let new_bp = NewBoilerplate {
+            name: "Boilerplate 1".into(),
+            script: Some("first".into()),
+            files: files.clone(),
+        };
+        let id = create(&mut conn, &new_bp)?;
+        let ident = BoilerplateIdentifier::Id(id);
+
+        let mut bp = fetch(&conn, ident.clone()).unwrap();
+        bp.script = Some("second".into());
+        bp.files = HashMap::new();
+        let tx = conn.transaction()?;
+        update(&tx, &bp, &bp.modified).unwrap();
+        tx.commit()?;
+
+        let revs = revisions(&conn, ident.clone()).unwrap();
+        assert_eq!(revs.len(), 2);
+        assert_eq!(revs[0].script, new_bp.script);
+        assert_eq!(revs[0].files, files);
+        assert_eq!(revs[1].script, bp.script);
+        assert!(revs[1].files.is_empty());
+
+        let tx = conn.transaction()?;
+        restore(&tx, ident.clone(), 1, None).unwrap();
+        tx.commit()?;
+        let restored = fetch(&conn, ident.clone()).unwrap();
+        assert_eq!(restored.script, new_bp.script);
+        assert_eq!(restored.files, files);
+        assert_eq!(current_revision(&conn, id).unwrap(), 3);
+
+        assert!(restore(&conn.transaction()?, ident, 5, None).is_err());
+
+        Ok(())
+    }
 }
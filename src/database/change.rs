@@ -0,0 +1,106 @@
+//! Interface for the change log, which is written by triggers on the file,
+//! directory and boilerplate tables.
+
+use crate::change::Change;
+use crate::CabinetResult as Result;
+use rusqlite::Connection;
+use std::convert::TryFrom;
+
+/// Return at most `limit` changes after the cursor `since`, in order.
+pub fn since(conn: &Connection, since: usize, limit: usize) -> Result<Vec<Change>> {
+    let mut stmt = conn.prepare("SELECT * FROM change WHERE id > ? ORDER BY id LIMIT ?")?;
+    let mut changes = Vec::new();
+    for res in stmt.query_map([since, limit], |row| Change::try_from(row))? {
+        changes.push(res?);
+    }
+    Ok(changes)
+}
+
+/// Return the cursor of the latest change, or 0 if nothing has changed.
+pub fn latest(conn: &Connection) -> Result<usize> {
+    let mut stmt = conn.prepare("SELECT coalesce(max(id), 0) FROM change")?;
+    let cursor = stmt.query_row([], |row| row.get(0))?;
+    Ok(cursor)
+}
+
+/*******************************************************************************
+ *                                                                             *
+ * Tests
+ *                                                                             *
+ *******************************************************************************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{dir, file};
+    use crate::file::NewFile;
+    use anyhow::Result;
+
+    fn db() -> Result<Connection> {
+        use crate::database::migrations::migrate;
+        let conn = Connection::open_in_memory()?;
+        migrate(&conn)?;
+        conn.pragma_update(None, "foreign_keys", "ON")?;
+        Ok(conn)
+    }
+
+    fn new_file(path: &str) -> NewFile {
+        NewFile {
+            path: path.into(),
+            content: b"content".to_vec(),
+            mode: 0o644,
+            modified: "Sun, 06 Nov 1994 08:49:37 GMT".into(),
+        }
+    }
+
+    fn summary(changes: &[Change]) -> Vec<String> {
+        changes
+            .iter()
+            .map(|c| match &c.old_path {
+                Some(old) => format!("{} {} {} {}", c.kind, c.action, old, c.path),
+                None => format!("{} {} {}", c.kind, c.action, c.path),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn file_changes() -> Result<()> {
+        let mut conn = db()?;
+        assert_eq!(latest(&conn)?, 0);
+
+        let id = file::create(&conn, &new_file("a/b.txt"))?;
+        let cursor = latest(&conn)?;
+        let tx = conn.transaction()?;
+        file::update_mode(&tx, id, 0o755, "Mon, 07 Nov 1994 08:49:37 GMT")?;
+        tx.commit()?;
+        file::move_to(&mut conn, id, "c/d.txt".as_ref(), false)?;
+        file::delete(&conn, file::FileIdentifier::Id(id))?;
+
+        assert_eq!(summary(&since(&conn, 0, 2)?), vec!["dir insert a", "file insert a/b.txt"]);
+        assert_eq!(
+            summary(&since(&conn, cursor, 100)?),
+            vec![
+                "file update a/b.txt",
+                "dir insert c",
+                "file move a/b.txt c/d.txt",
+                "file delete c/d.txt",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn dir_changes() -> Result<()> {
+        let mut conn = db()?;
+        file::create(&conn, &new_file("a/b/c.txt"))?;
+        let cursor = latest(&conn)?;
+        dir::move_to(&mut conn, "a".as_ref(), "x".as_ref(), false)?;
+        dir::delete_recursive(&mut conn, "x".as_ref())?;
+
+        assert_eq!(
+            summary(&since(&conn, cursor, 100)?),
+            vec!["dir move a x", "file delete x/b/c.txt", "dir delete x/b", "dir delete x"]
+        );
+        Ok(())
+    }
+}
@@ -1,5 +1,5 @@
-use crate::dir::Directory;
-use crate::file::File;
+use crate::dir::{Deleted, DirEntry, Directory};
+use crate::file::FileInfo;
 use crate::{CabinetResult as Result, CabinetError};
 use rusqlite::{Connection, OptionalExtension};
 use std::convert::TryFrom;
@@ -8,7 +8,7 @@ use std::path::{Component, Path};
 #[derive(Debug, Clone)]
 pub enum DirContent {
     Dir(Directory),
-    File(File),
+    File(FileInfo),
 }
 
 impl std::fmt::Display for DirContent {
@@ -24,17 +24,17 @@ impl std::fmt::Display for DirContent {
     }
 }
 
-pub async fn count(conn: &Connection) -> Result<usize> {
+pub fn count(conn: &Connection) -> Result<usize> {
     let mut stmt = conn.prepare("SELECT count(*) FROM directory")?;
     let count = stmt.query_row([], |row| row.get(0))?;
     Ok(count)
 }
 
 /// Fetch a directory from the database.
-pub async fn fetch(conn: &Connection, ident: DirIdentifier<'_>) -> Result<Directory> {
+pub fn fetch(conn: &Connection, ident: DirIdentifier<'_>) -> Result<Directory> {
     let id = match ident {
         DirIdentifier::Id(id) => Some(id),
-        DirIdentifier::Path(path) => get_id(conn, path).await?,
+        DirIdentifier::Path(path) => get_id(conn, path)?,
     };
     if id.is_none() {
         return Err(CabinetError::NotFound)
@@ -49,15 +49,15 @@ pub async fn fetch(conn: &Connection, ident: DirIdentifier<'_>) -> Result<Direct
 }
 
 /// Check if a directory exists.
-pub async fn exists(conn: &Connection, ident: DirIdentifier<'_>) -> Result<bool> {
+pub fn exists(conn: &Connection, ident: DirIdentifier<'_>) -> Result<bool> {
     let id = match ident {
         DirIdentifier::Id(id) => Some(id),
-        DirIdentifier::Path(path) => get_id(conn, path).await?,
+        DirIdentifier::Path(path) => get_id(conn, path)?,
     };
     let mut found = false;
     if let Some(id) = id {
         let mut stmt = conn.prepare_cached("SELECT * FROM directory WHERE id IS ?")?;
-        found = stmt.exists(&[&id])?;
+        found = stmt.exists([&id])?;
     }
     Ok(found)
 }
@@ -66,7 +66,7 @@ pub async fn exists(conn: &Connection, ident: DirIdentifier<'_>) -> Result<bool>
 ///
 /// Returns the id if the directory.
 ///
-pub async fn create(conn: &Connection, path: &Path) -> Result<usize> {
+pub fn create(conn: &Connection, path: &Path) -> Result<usize> {
     let mut id_stmt = conn.prepare("SELECT id FROM directory WHERE name IS ? AND parent IS ?")?;
     let mut insert_stmt = conn.prepare("INSERT INTO directory(name, parent) VALUES (?, ?)")?;
     let mut id: Option<usize> = None;
@@ -77,18 +77,16 @@ pub async fn create(conn: &Connection, path: &Path) -> Result<usize> {
     // and all its parents
     //
     for comp in path.components() {
-        match comp {
-            Component::Normal(name) => {
-                let p = params![name.to_string_lossy(), id.clone()];
-                // Check if the directory already exists
-                id = id_stmt.query_row(p.clone(), func).optional()?;
-                if id.is_none() {
-                    // If not, create it
-                    insert_stmt.execute(p)?;
-                    id = Some(id_stmt.query_row(p, func)?);
-                }
+        if let Component::Normal(name) = comp {
+            let parent = id;
+            let p = params![name.to_string_lossy(), parent];
+            // Check if the directory already exists
+            id = id_stmt.query_row(p, func).optional()?;
+            if id.is_none() {
+                // If not, create it
+                insert_stmt.execute(p)?;
+                id = Some(id_stmt.query_row(p, func)?);
             }
-            _ => (),
         }
     }
 
@@ -102,40 +100,209 @@ pub async fn create(conn: &Connection, path: &Path) -> Result<usize> {
 }
 
 /// Delete a directory.
-pub async fn delete(conn: &Connection, ident: DirIdentifier<'_>) -> Result<usize> {
+pub fn delete(conn: &Connection, ident: DirIdentifier<'_>) -> Result<usize> {
     let id = match ident {
         DirIdentifier::Id(id) => Some(id),
-        DirIdentifier::Path(path) => get_id(conn, path).await?,
+        DirIdentifier::Path(path) => get_id(conn, path)?,
     };
     let mut n = 0;
     if id.is_some() {
         let mut stmt = conn.prepare("DELETE FROM directory WHERE id IS ?")?;
-        n = stmt.execute(&[&id])?;
+        n = stmt.execute([&id])?;
     }
     Ok(n)
 }
 
+/// Delete a directory together with all its files and subdirectories, in a
+/// single transaction.
+///
+/// Nothing is deleted if any of the files is used in a boilerplate.
+///
+pub fn delete_recursive(conn: &mut Connection, path: &Path) -> Result<Deleted> {
+    let path = path.to_string_lossy();
+    let tx = conn.transaction()?;
+    let deleted = remove_tree(&tx, path.trim_matches('/'))?;
+    tx.commit()?;
+    Ok(deleted)
+}
+
+/// Delete the directory at `path` and its content, unless any of the files
+/// is used in a boilerplate.
+fn remove_tree(conn: &Connection, path: &str) -> Result<Deleted> {
+    use crate::database::boilerplate::file_used_in_boilerplates;
+    use crate::dir::EntryType;
+    use crate::CabinetError::BadRequest;
+
+    let dir = fetch(conn, DirIdentifier::Path(path.as_ref()))?;
+
+    //
+    // List the content, checking that no file is used in boilerplates
+    //
+    let mut deleted = Deleted::default();
+    deleted.dirs.push(path.to_string());
+    let mut used = Vec::new();
+    let entries = tree(conn, path.as_ref(), None)?;
+    for entry in &entries {
+        match entry.entry_type {
+            EntryType::Dir => deleted.dirs.push(entry.path.clone()),
+            EntryType::File => {
+                let bps = file_used_in_boilerplates(conn, entry.id)?;
+                if !bps.is_empty() {
+                    used.push(format!("{}: {}", entry.path, bps.join(", ")));
+                }
+                deleted.files.push(entry.path.clone());
+            }
+        }
+    }
+    if !used.is_empty() {
+        return Err(BadRequest(format!("files are used in boilerplates:\n{}", used.join("\n"))));
+    }
+
+    //
+    // Delete the content bottom-up, before the directory itself, so every
+    // entry still has its full path when the deletion is logged
+    //
+    let mut delete_file = conn.prepare("DELETE FROM file WHERE id IS ?")?;
+    let mut delete_dir = conn.prepare("DELETE FROM directory WHERE id IS ?")?;
+    for entry in entries.iter().rev() {
+        match entry.entry_type {
+            EntryType::Dir => delete_dir.execute([&entry.id])?,
+            EntryType::File => delete_file.execute([&entry.id])?,
+        };
+    }
+    delete_dir.execute([&dir.id])?;
+    Ok(deleted)
+}
+
+/// Move a directory with all its content to `dest`, in a single transaction.
+///
+/// The ids of the directory and its content are kept, and with them all
+/// boilerplate references to the files. An existing directory at `dest` is
+/// replaced if `overwrite` is set, as by `delete_recursive`, otherwise
+/// `PreconditionFailed` is returned. Returns whether a directory was
+/// replaced.
+///
+pub fn move_to(conn: &mut Connection, src: &Path, dest: &Path, overwrite: bool) -> Result<bool> {
+    use crate::CabinetError::{BadRequest, PreconditionFailed};
+
+    let src = canonical_path(src);
+    let src = Path::new(&src);
+    let dest = canonical_path(dest);
+    let dest = Path::new(&dest);
+    let tx = conn.transaction()?;
+    let dir = fetch(&tx, DirIdentifier::Path(src))?;
+
+    //
+    // Check the destination, removing it if it should be replaced
+    //
+    let name = match dest.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => return Err(BadRequest("invalid destination".to_string())),
+    };
+    if dest.starts_with(src) || src.starts_with(dest) {
+        return Err(BadRequest(format!(
+            "cannot move {} to {}",
+            src.display(),
+            dest.display()
+        )));
+    }
+    let replaced = exists(&tx, DirIdentifier::Path(dest))?;
+    if replaced && !overwrite {
+        return Err(PreconditionFailed);
+    }
+    if replaced {
+        remove_tree(&tx, &dest.to_string_lossy())?;
+    }
+
+    //
+    // Move the directory, creating the parents of the destination
+    //
+    let parent = match dest.parent() {
+        Some(path) if !path.as_os_str().is_empty() => Some(create(&tx, path)?),
+        _ => None,
+    };
+    tx.prepare("UPDATE directory SET name=?, parent=? WHERE id IS ?")?
+        .execute(params![name, parent, dir.id])?;
+    tx.commit()?;
+    Ok(replaced)
+}
+
+/// Copy a directory with all its content to `dest`, in a single
+/// transaction.
+///
+/// Files are copied as by `file::copy`, sharing their content. If `dest`
+/// exists the content is copied into it if `overwrite` is set, updating
+/// existing files, otherwise `PreconditionFailed` is returned. Returns
+/// whether the destination existed.
+///
+pub fn copy_to(
+    conn: &mut Connection,
+    src: &Path,
+    dest: &Path,
+    overwrite: bool,
+    modified: Option<&str>,
+) -> Result<bool> {
+    use crate::database::file;
+    use crate::dir::EntryType;
+    use crate::CabinetError::{BadRequest, PreconditionFailed};
+
+    let src = canonical_path(src);
+    let dest = canonical_path(dest);
+    let tx = conn.transaction()?;
+    fetch(&tx, DirIdentifier::Path(src.as_ref()))?;
+
+    //
+    // Check the destination
+    //
+    if dest.is_empty() || Path::new(&dest).starts_with(&src) {
+        return Err(BadRequest(format!("cannot copy {} to {}", src, dest)));
+    }
+    let replaced = exists(&tx, DirIdentifier::Path(dest.as_ref()))?;
+    if replaced && !overwrite {
+        return Err(PreconditionFailed);
+    }
+
+    //
+    // Copy the directory and its content
+    //
+    create(&tx, dest.as_ref())?;
+    for entry in tree(&tx, src.as_ref(), None)? {
+        let path = format!("{}{}", dest, &entry.path[src.len()..]);
+        match entry.entry_type {
+            EntryType::Dir => {
+                create(&tx, path.as_ref())?;
+            }
+            EntryType::File => {
+                file::copy(&tx, entry.id, path.as_ref(), true, modified)?;
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(replaced)
+}
+
 /// Return the content of a directory.
-pub async fn content(conn: &Connection, ident: DirIdentifier<'_>) -> Result<Vec<DirContent>> {
+pub fn content(conn: &Connection, ident: DirIdentifier<'_>) -> Result<Vec<DirContent>> {
     let root_dirs = [Path::new(""), Path::new("/")];
-    let found = exists(conn, ident.clone()).await?;
+    let found = exists(conn, ident.clone())?;
 
     let id = match ident {
         DirIdentifier::Id(id) if found => Some(id),
         DirIdentifier::Path(path) if root_dirs.contains(&path) => None,
-        DirIdentifier::Path(path) if found => get_id(conn, path).await?,
+        DirIdentifier::Path(path) if found => get_id(conn, path)?,
         _ => return Err(CabinetError::NotFound),
     };
 
     let mut dir_stmt = conn.prepare("SELECT * FROM directory WHERE parent IS ? ORDER BY name")?;
     let mut file_stmt = conn.prepare(
-        "SELECT *
+        "SELECT file.id, path, size, mode, modified, hash
            FROM file JOIN file_path ON file.id=file_path.id
+                     JOIN blob ON file.blob=blob.id
           WHERE parent IS ? ORDER BY name",
     )?;
 
-    let dirs = dir_stmt.query_map(&[&id], |row| Directory::try_from(row))?;
-    let files = file_stmt.query_map(&[&id], |row| File::try_from(row))?;
+    let dirs = dir_stmt.query_map([&id], |row| Directory::try_from(row))?;
+    let files = file_stmt.query_map([&id], |row| FileInfo::try_from(row))?;
 
     let mut content = Vec::new();
     for d in dirs {
@@ -148,24 +315,72 @@ pub async fn content(conn: &Connection, ident: DirIdentifier<'_>) -> Result<Vec<
     Ok(content)
 }
 
+/// Return the content of a directory and its subdirectories, down to
+/// `depth` levels below it. All levels are included if `depth` is `None`.
+///
+/// Each directory is directly followed by its own content.
+///
+pub fn tree(conn: &Connection, path: &Path, depth: Option<usize>) -> Result<Vec<DirEntry>> {
+    fn walk(
+        conn: &Connection,
+        ident: DirIdentifier<'_>,
+        path: &str,
+        depth: Option<usize>,
+        entries: &mut Vec<DirEntry>,
+    ) -> Result<()> {
+        if depth == Some(0) {
+            return Ok(());
+        }
+        for item in content(conn, ident)? {
+            match item {
+                DirContent::Dir(dir) => {
+                    let entry = DirEntry::dir(path, &dir);
+                    let subpath = entry.path.clone();
+                    entries.push(entry);
+                    walk(conn, DirIdentifier::Id(dir.id), &subpath, depth.map(|d| d - 1), entries)?;
+                }
+                DirContent::File(file) => entries.push(DirEntry::from(&file)),
+            }
+        }
+        Ok(())
+    }
+
+    let path = canonical_path(path);
+    let mut entries = Vec::new();
+    walk(conn, DirIdentifier::Path(path.as_ref()), &path, depth, &mut entries)?;
+    Ok(entries)
+}
+
+/// Get the canonical form of a directory path, in which the paths of
+/// directory entries are given: its names joined by single slashes.
+///
+/// Components other than names are skipped, as by `get_id`.
+pub fn canonical_path(path: &Path) -> String {
+    let names: Vec<_> = path
+        .components()
+        .filter_map(|comp| match comp {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect();
+    names.join("/")
+}
+
 /// Find the id of a directory by iterating through its parents.
 ///
 /// If `None` is returned the directory doesn't exist.
 ///
 /// TODO: Handle root directory/empty path?
-pub async fn get_id(conn: &Connection, path: &Path) -> Result<Option<usize>> {
+pub fn get_id(conn: &Connection, path: &Path) -> Result<Option<usize>> {
     let mut stmt = conn.prepare("SELECT id FROM directory WHERE name IS ? AND parent IS ?")?;
     let mut id: Option<usize> = None;
     for comp in path.components() {
-        match comp {
-            Component::Normal(name) => {
-                id = stmt
-                    .query_row(params![name.to_string_lossy(), id], |row| {
-                        row.get::<_, usize>("id")
-                    })
-                    .optional()?;
-            }
-            _ => (),
+        if let Component::Normal(name) = comp {
+            id = stmt
+                .query_row(params![name.to_string_lossy(), id], |row| {
+                    row.get::<_, usize>("id")
+                })
+                .optional()?;
         }
         // If id is None at this point the directory doesn't exist.
         if id.is_none() {
@@ -188,22 +403,22 @@ mod tests {
     use anyhow::Result;
     use rusqlite::Connection;
 
-    async fn db() -> Result<Connection> {
-        use crate::database::create_tables;
+    fn db() -> Result<Connection> {
+        use crate::database::migrations::migrate;
         let conn = Connection::open_in_memory()?;
-        create_tables(&conn).await?;
+        migrate(&conn)?;
         Ok(conn)
     }
 
-    #[async_std::test]
-    async fn test_fetch() -> Result<()> {
-        let conn = db().await?;
+    #[test]
+    fn test_fetch() -> Result<()> {
+        let conn = db()?;
         let ident = DirIdentifier::Path("mydir/foodir".as_ref());
 
         //
         // Should return error before the directory is created
         //
-        assert!(fetch(&conn, ident.clone()).await.is_err());
+        assert!(fetch(&conn, ident.clone()).is_err());
 
         conn.execute("INSERT INTO directory VALUES (0, 'mydir', NULL)", [])?;
         conn.execute("INSERT INTO directory VALUES (1, 'foodir', 0)", [])?;
@@ -211,23 +426,23 @@ mod tests {
         //
         // Should exist and have directory 0 as parent
         //
-        let dir: Directory = fetch(&conn, ident).await?;
+        let dir: Directory = fetch(&conn, ident)?;
         assert_eq!(dir.parent, Some(0));
 
         Ok(())
     }
 
-    #[async_std::test]
-    async fn test_exists() -> Result<()> {
-        let conn = db().await?;
+    #[test]
+    fn test_exists() -> Result<()> {
+        let conn = db()?;
         let ident1 = DirIdentifier::Path("mydir".as_ref());
         let ident2 = DirIdentifier::Path("mydir/foodir".as_ref());
 
         //
         // No directories should exist before created
         //
-        assert!(!exists(&conn, ident1.clone()).await?);
-        assert!(!exists(&conn, ident2.clone()).await?);
+        assert!(!exists(&conn, ident1.clone())?);
+        assert!(!exists(&conn, ident2.clone())?);
 
         conn.execute("INSERT INTO directory VALUES (0, 'mydir', NULL)", [])?;
         conn.execute("INSERT INTO directory VALUES (1, 'foodir', 0)", [])?;
@@ -235,40 +450,160 @@ mod tests {
         //
         // Should exist after created
         //
-        assert!(exists(&conn, ident1).await?);
-        assert!(exists(&conn, ident2).await?);
+        assert!(exists(&conn, ident1)?);
+        assert!(exists(&conn, ident2)?);
 
         Ok(())
     }
 
-    #[async_std::test]
-    async fn all_dir_functions() -> Result<()> {
-        let conn = db().await?;
+    #[test]
+    fn all_dir_functions() -> Result<()> {
+        let conn = db()?;
         let ident1 = DirIdentifier::Path("mydir".as_ref());
         let ident2 = DirIdentifier::Path("foodir".as_ref());
         let ident3 = DirIdentifier::Path("foodir/bardir".as_ref());
 
-        assert!(!exists(&conn, ident1.clone()).await?);
-        create(&conn, "mydir".as_ref()).await?;
-        assert!(exists(&conn, ident1.clone()).await?);
+        assert!(!exists(&conn, ident1.clone())?);
+        create(&conn, "mydir".as_ref())?;
+        assert!(exists(&conn, ident1.clone())?);
 
-        create(&conn, "foodir/bardir".as_ref()).await?;
-        let d1: Directory = fetch(&conn, ident2.clone()).await?;
-        let d2: Directory = fetch(&conn, ident3.clone()).await?;
+        create(&conn, "foodir/bardir".as_ref())?;
+        let d1: Directory = fetch(&conn, ident2.clone())?;
+        let d2: Directory = fetch(&conn, ident3.clone())?;
         assert_eq!(d2.parent, Some(d1.id));
 
-        create(&conn, "mydir/foodir".as_ref()).await?;
-        let cont = content(&conn, ident1.clone()).await?;
+        create(&conn, "mydir/foodir".as_ref())?;
+        let cont = content(&conn, ident1.clone())?;
         println!("mydir content: {:?}", &cont);
         assert_eq!(cont.len(), 1);
 
-        delete(&conn, ident1.clone()).await?;
-        assert!(!exists(&conn, ident1).await?);
+        delete(&conn, ident1.clone())?;
+        assert!(!exists(&conn, ident1)?);
 
-        delete(&conn, ident2.clone()).await?;
-        assert!(!exists(&conn, ident2).await?);
+        delete(&conn, ident2.clone())?;
+        assert!(!exists(&conn, ident2)?);
         let ident3 = DirIdentifier::Id(d2.id);
-        assert!(!exists(&conn, ident3).await?);
+        assert!(!exists(&conn, ident3)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree() -> Result<()> {
+        use crate::dir::EntryType;
+        use crate::file::NewFile;
+
+        let conn = db()?;
+        create(&conn, "foodir/bardir".as_ref())?;
+        let new_file = |path: &str| NewFile {
+            path: path.to_string(),
+            content: b"hello".to_vec(),
+            mode: 0o644,
+            modified: "Wed, 21 Oct 2015 07:28:00 GMT".to_string(),
+        };
+        crate::database::file::create(&conn, &new_file("foodir/a.txt"))?;
+        crate::database::file::create(&conn, &new_file("foodir/bardir/b.txt"))?;
+
+        let paths = |entries: Vec<DirEntry>| -> Vec<String> {
+            entries.into_iter().map(|e| e.path).collect()
+        };
+        assert_eq!(
+            paths(tree(&conn, "".as_ref(), None)?),
+            ["foodir", "foodir/bardir", "foodir/bardir/b.txt", "foodir/a.txt"]
+        );
+        assert_eq!(paths(tree(&conn, "/foodir/".as_ref(), Some(1))?), ["foodir/bardir", "foodir/a.txt"]);
+        assert_eq!(paths(tree(&conn, "foodir".as_ref(), Some(2))?).len(), 3);
+        assert!(tree(&conn, "nodir".as_ref(), None).is_err());
+
+        //
+        // Entry paths should be canonical for non-canonical paths
+        //
+        for path in ["foodir/./bardir", "foodir/././bardir", "foodir////bardir/"] {
+            assert_eq!(paths(tree(&conn, path.as_ref(), None)?), ["foodir/bardir/b.txt"]);
+        }
+        assert_eq!(paths(tree(&conn, "./foodir".as_ref(), Some(1))?), ["foodir/bardir", "foodir/a.txt"]);
+
+        let entries = tree(&conn, "foodir/bardir".as_ref(), None)?;
+        assert_eq!(entries[0].entry_type, EntryType::File);
+        assert_eq!(entries[0].name, "b.txt");
+        assert_eq!(entries[0].size, Some(5));
+        assert_eq!(entries[0].etag.as_deref(), Some(crate::database::blob::hash(b"hello").as_str()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_recursive() -> Result<()> {
+        use crate::file::NewFile;
+
+        let mut conn = db()?;
+        create(&conn, "foodir/bardir".as_ref())?;
+        let new_file = |path: &str| NewFile {
+            path: path.to_string(),
+            content: b"hello".to_vec(),
+            mode: 0o644,
+            modified: "Wed, 21 Oct 2015 07:28:00 GMT".to_string(),
+        };
+        crate::database::file::create(&conn, &new_file("foodir/a.txt"))?;
+        let id = crate::database::file::create(&conn, &new_file("foodir/bardir/b.txt"))?;
+        conn.execute("INSERT INTO boilerplate VALUES (1, 'bp', 'Wed, 21 Oct 2015 07:28:00 GMT', NULL)", [])?;
+        conn.execute("INSERT INTO bp_file_map VALUES (1, 1, ?, '~/b.txt')", [&id])?;
+
+        //
+        // Should refuse while a file is used in a boilerplate
+        //
+        assert!(matches!(
+            delete_recursive(&mut conn, "foodir".as_ref()),
+            Err(CabinetError::BadRequest(_))
+        ));
+        assert!(exists(&conn, DirIdentifier::Path("foodir/bardir".as_ref()))?);
+
+        conn.execute("DELETE FROM boilerplate", [])?;
+        let deleted = delete_recursive(&mut conn, "/foodir".as_ref()).unwrap();
+        assert_eq!(deleted.dirs, ["foodir", "foodir/bardir"]);
+        assert_eq!(deleted.files, ["foodir/bardir/b.txt", "foodir/a.txt"]);
+        assert_eq!(count(&conn).unwrap(), 0);
+        assert_eq!(crate::database::blob::count(&conn).unwrap(), 0);
+        assert!(matches!(delete_recursive(&mut conn, "foodir".as_ref()), Err(CabinetError::NotFound)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy() -> Result<()> {
+        use crate::file::NewFile;
+
+        let mut conn = db()?;
+        create(&conn, "work/empty".as_ref())?;
+        let new_file = |path: &str, content: &[u8]| NewFile {
+            path: path.to_string(),
+            content: content.to_vec(),
+            mode: 0o644,
+            modified: "Wed, 21 Oct 2015 07:28:00 GMT".to_string(),
+        };
+        crate::database::file::create(&conn, &new_file("work/a.txt", b"a"))?;
+        crate::database::file::create(&conn, &new_file("work/sub/b.txt", b"b"))?;
+        crate::database::file::create(&conn, &new_file("home/a.txt", b"old"))?;
+
+        assert!(copy_to(&mut conn, "work".as_ref(), "work/nested".as_ref(), false, None).is_err());
+        assert!(copy_to(&mut conn, "./work".as_ref(), "work//nested".as_ref(), false, None).is_err());
+        assert!(matches!(
+            copy_to(&mut conn, "work".as_ref(), "home".as_ref(), false, None),
+            Err(CabinetError::PreconditionFailed)
+        ));
+        assert!(copy_to(&mut conn, "work".as_ref(), "home".as_ref(), true, None).unwrap());
+
+        let paths: Vec<_> = tree(&conn, "home".as_ref(), None)?.into_iter().map(|e| e.path).collect();
+        assert_eq!(paths, ["home/empty", "home/sub", "home/sub/b.txt", "home/a.txt"]);
+        assert_eq!(tree(&conn, "work".as_ref(), None)?.len(), 4);
+        assert_eq!(crate::database::blob::count(&conn).unwrap(), 3);
+
+        //
+        // Non-canonical paths should copy the same as canonical ones
+        //
+        assert!(!copy_to(&mut conn, "work/././sub".as_ref(), "/copy//sub/".as_ref(), false, None).unwrap());
+        let paths: Vec<_> = tree(&conn, "copy".as_ref(), None)?.into_iter().map(|e| e.path).collect();
+        assert_eq!(paths, ["copy/sub", "copy/sub/b.txt"]);
 
         Ok(())
     }
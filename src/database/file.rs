@@ -1,134 +1,415 @@
 //! Interface for file entries in the database.
 
-use crate::file::{File, NewFile};
+use crate::database::blob::{self, Content};
+use crate::file::{File, FileInfo, NewFile, Revision};
 use crate::{CabinetError, CabinetResult as Result};
-use rusqlite::Connection;
+use actix_web::http::header::HttpDate;
+use rusqlite::{Connection, Transaction};
 use std::convert::TryFrom;
+use std::io::Read;
 use std::path::Path;
+use std::str::FromStr;
 
-pub async fn count(conn: &Connection) -> Result<usize> {
+pub fn count(conn: &Connection) -> Result<usize> {
     let mut stmt = conn.prepare("SELECT count(*) FROM file")?;
     let count = stmt.query_row([], |row| row.get(0))?;
     Ok(count)
 }
 
 #[allow(dead_code)]
-pub async fn exists(conn: &Connection, ident: FileIdentifier<'_>) -> Result<bool> {
+pub fn exists(conn: &Connection, ident: FileIdentifier<'_>) -> Result<bool> {
     let mut exists = false;
-    if let Some(id) = ident.get_id(conn).await? {
+    if let Some(id) = ident.get_id(conn)? {
         exists = conn
             .prepare("SELECT name FROM file WHERE id IS ?")?
-            .exists(&[&id])?
+            .exists([&id])?
     }
     Ok(exists)
 }
 
-pub async fn fetch(conn: &Connection, ident: FileIdentifier<'_>) -> Result<File> {
-    let id = ident.get_id(conn).await?;
+pub fn fetch(conn: &Connection, ident: FileIdentifier<'_>) -> Result<File> {
+    let id = ident.get_id(conn)?;
     if id.is_none() {
         return Err(CabinetError::NotFound);
     }
     let file = conn
         .prepare(
-            "SELECT file.id, path, content, mode, modified
+            "SELECT file.id, path, blob.content, codec, blob_dictionary.content AS dictionary,
+                    mode, modified
             FROM file JOIN file_path ON file.id=file_path.id
+                      JOIN blob ON file.blob=blob.id
+                      LEFT JOIN blob_dictionary ON blob.dictionary=blob_dictionary.id
             WHERE file.id IS ?",
         )?
-        .query_row(&[&id], |row| File::try_from(row))?;
+        .query_row([&id], |row| File::try_from(row))?;
     Ok(file)
 }
 
-pub async fn create(conn: &Connection, file: &NewFile) -> Result<()> {
-    use crate::database::dir;
-
-    //
-    // Get parent id. Creating all parents if they don't exist.
-    //
-    let path = Path::new(&file.path);
-    let name = path.file_name().map(|s| s.to_string_lossy());
-    if name.is_none() {
-        return_error!("Unable to get file name from path: {}", file.path);
+/// Fetch a file without loading its content into memory.
+pub fn fetch_info(conn: &Connection, ident: FileIdentifier<'_>) -> Result<FileInfo> {
+    let id = ident.get_id(conn)?;
+    if id.is_none() {
+        return Err(CabinetError::NotFound);
     }
-    let empty_parents = &[Path::new(""), Path::new("/")];
-    let parent = match path.parent() {
-        Some(path) if !empty_parents.contains(&path) => {
-            match dir::get_id(conn, path).await? {
-                Some(id) => Some(id),
-                None => Some(dir::create(conn, &path).await?)
-            }
-        }
-        _ => None,
-    };
+    let file = conn
+        .prepare(
+            "SELECT file.id, path, size, mode, modified, hash
+            FROM file JOIN file_path ON file.id=file_path.id
+                      JOIN blob ON file.blob=blob.id
+            WHERE file.id IS ?",
+        )?
+        .query_row([&id], |row| FileInfo::try_from(row))?;
+    Ok(file)
+}
+
+/// Open the content of a file for incremental reading.
+pub fn open_content(conn: &Connection, id: usize) -> Result<Content<'_>> {
+    let blob = query_row!(conn,
+        "SELECT blob FROM file WHERE id IS ?" => |row| row.get(0);
+        id
+    )?;
+    blob::open(conn, blob)
+}
+
+/// Create a new file with `size` bytes of content streamed from `reader`.
+///
+/// The content of `file` is ignored. Returns the id of the new file.
+///
+pub fn create_from_reader<R: Read>(
+    conn: &Connection,
+    file: &NewFile,
+    reader: R,
+    size: usize,
+) -> Result<usize> {
+    let blob = blob::store_from_reader(conn, reader, size)?;
+    insert(conn, file, blob)
+}
+
+/// Replace the content and mode of a file, with `size` bytes of content
+/// streamed from `reader`. The current version is kept as a revision.
+pub fn update_from_reader<R: Read>(
+    tx: &Transaction<'_>,
+    id: usize,
+    reader: R,
+    size: usize,
+    mode: u32,
+    modified: &str,
+) -> Result<()> {
+    let blob = blob::store_from_reader(tx, reader, size)?;
+    archive(tx, id)?;
+    tx.prepare("UPDATE file SET blob=?, mode=?, modified=? WHERE id IS ?")?
+        .execute(params![blob, mode, modified, id])?;
+    Ok(())
+}
+
+/// Update the mode of a file, keeping the current version as a revision.
+pub fn update_mode(tx: &Transaction<'_>, id: usize, mode: u32, modified: &str) -> Result<()> {
+    archive(tx, id)?;
+    tx.prepare("UPDATE file SET mode=?, modified=? WHERE id IS ?")?
+        .execute(params![mode, modified, id])?;
+    Ok(())
+}
+
+/// Keep the current version of a file as a revision.
+///
+/// The revision number is derived from the revisions already kept, so this
+/// has to run in the same transaction as the change which replaces the
+/// current version.
+///
+fn archive(tx: &Transaction<'_>, id: usize) -> Result<()> {
+    let mut stmt = tx.prepare(
+        "INSERT INTO file_revision(file, revision, blob, mode, modified)
+         SELECT id, ?, blob, mode, modified FROM file WHERE id IS ?",
+    )?;
+    stmt.execute(params![current_revision(tx, id)?, id])?;
+    Ok(())
+}
+
+/// Create a new file. Returns the id of the new file.
+pub fn create(conn: &Connection, file: &NewFile) -> Result<usize> {
+    let blob = blob::store(conn, &file.content)?;
+    insert(conn, file, blob)
+}
+
+/// Insert a new file entry with the content of `blob`, ignoring the content
+/// of `file`.
+fn insert(conn: &Connection, file: &NewFile, blob: usize) -> Result<usize> {
+    let (name, parent) = locate(conn, Path::new(&file.path))?;
 
     //
     // Create the new file.
     //
     let mut stmt = conn.prepare(
-        "INSERT INTO file(name, parent, content, mode, modified) VALUES (?, ?, ?, ?, ?)",
+        "INSERT INTO file(name, parent, blob, mode, modified) VALUES (?, ?, ?, ?, ?)",
     )?;
-    stmt.insert(params![
+    let id = stmt.insert(params![
         name,
         parent,
-        file.content,
+        blob,
         file.mode,
         file.modified,
     ])?;
 
-    Ok(())
+    Ok(id as usize)
 }
 
-pub async fn update(conn: &Connection, file: &File) -> Result<()> {
+/// Get the name and parent directory id of a file at `path`. Creating all
+/// parents if they don't exist.
+fn locate(conn: &Connection, path: &Path) -> Result<(String, Option<usize>)> {
     use crate::database::dir;
 
-    //
-    // Get parent id. Creating all parents if they don't exist.
-    //
-    let path = Path::new(&file.path);
-    let name = path.file_name().map(|s| s.to_string_lossy());
-    if name.is_none() {
-        return_error!("Unable to get file name from path: {}", file.path);
-    }
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => return_error!("Unable to get file name from path: {}", path.display()),
+    };
     let empty_parents = &[Path::new(""), Path::new("/")];
     let parent = match path.parent() {
         Some(path) if !empty_parents.contains(&path) => {
-            match dir::get_id(conn, path).await? {
+            match dir::get_id(conn, path)? {
                 Some(id) => Some(id),
-                None => Some(dir::create(conn, &path).await?)
+                None => Some(dir::create(conn, path)?)
             }
         }
         _ => None,
     };
+    Ok((name, parent))
+}
+
+/// Move a file to `dest`, in a single transaction. See `relocate`.
+pub fn move_to(conn: &mut Connection, id: usize, dest: &Path, overwrite: bool) -> Result<bool> {
+    let tx = conn.transaction()?;
+    let replaced = relocate(&tx, id, dest, overwrite)?;
+    tx.commit()?;
+    Ok(replaced)
+}
+
+/// Move a file to `dest`.
+///
+/// The file id is kept, and with it the file revisions and all boilerplate
+/// references to the file. An existing file at `dest` is replaced if
+/// `overwrite` is set, otherwise `PreconditionFailed` is returned. Returns
+/// whether a file was replaced.
+///
+pub fn relocate(tx: &Transaction<'_>, id: usize, dest: &Path, overwrite: bool) -> Result<bool> {
+    use crate::database::boilerplate::file_used_in_boilerplates;
+    use crate::CabinetError::{BadRequest, PreconditionFailed};
 
     //
-    // Update the file entry.
+    // Check the destination, removing it if it should be replaced
     //
-    let mut stmt = conn.prepare(
-        "UPDATE file SET name=?, parent=?, content=?, mode=?, modified=? WHERE id IS ?"
-    )?;
-    stmt.insert(params![
-        name,
-        parent,
-        file.content,
-        file.mode,
-        file.modified,
-        file.id,
-    ])?;
+    let existing = get_id(tx, dest)?;
+    if existing == Some(id) {
+        return Err(BadRequest("source and destination are the same".to_string()));
+    }
+    if let Some(existing) = existing {
+        if !overwrite {
+            return Err(PreconditionFailed);
+        }
+        let bps = file_used_in_boilerplates(tx, existing)?;
+        if !bps.is_empty() {
+            return Err(BadRequest(format!(
+                "file is used in boilerplates:\n{}",
+                bps.join("\n")
+            )));
+        }
+        tx.prepare("DELETE FROM file WHERE id IS ?")?
+            .execute([&existing])?;
+    }
 
-    Ok(())
+    let (name, parent) = locate(tx, dest)?;
+    tx.prepare("UPDATE file SET name=?, parent=? WHERE id IS ?")?
+        .execute(params![name, parent, id])?;
+    Ok(existing.is_some())
+}
+
+/// Copy a file to `dest`, in a single transaction. See `copy`.
+pub fn copy_to(
+    conn: &mut Connection,
+    id: usize,
+    dest: &Path,
+    overwrite: bool,
+    modified: Option<&str>,
+) -> Result<bool> {
+    let tx = conn.transaction()?;
+    let replaced = copy(&tx, id, dest, overwrite, modified)?;
+    tx.commit()?;
+    Ok(replaced)
 }
 
-pub async fn delete(conn: &Connection, ident: FileIdentifier<'_>) -> Result<usize> {
-    let id = ident.get_id(conn).await?;
+/// Copy the content and mode of a file to `dest`, sharing the content blob.
+///
+/// The copy gets the modified date of the original unless `modified` is
+/// given. An existing file at `dest` is updated with the copied version,
+/// keeping its revisions and boilerplate references, if `overwrite` is
+/// set, otherwise `PreconditionFailed` is returned. Returns whether a file
+/// was replaced.
+///
+pub fn copy(
+    tx: &Transaction<'_>,
+    id: usize,
+    dest: &Path,
+    overwrite: bool,
+    modified: Option<&str>,
+) -> Result<bool> {
+    use crate::CabinetError::{BadRequest, PreconditionFailed};
+
+    let (blob, mode, src_modified): (usize, u32, String) = tx
+        .prepare("SELECT blob, mode, modified FROM file WHERE id IS ?")?
+        .query_row([&id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    let modified = modified.unwrap_or(&src_modified);
+
+    match get_id(tx, dest)? {
+        Some(existing) if existing == id => {
+            Err(BadRequest("source and destination are the same".to_string()))
+        }
+        Some(_) if !overwrite => Err(PreconditionFailed),
+        Some(existing) => {
+            archive(tx, existing)?;
+            tx.prepare("UPDATE file SET blob=?, mode=?, modified=? WHERE id IS ?")?
+                .execute(params![blob, mode, modified, existing])?;
+            Ok(true)
+        }
+        None => {
+            let new_file = NewFile {
+                path: dest.to_string_lossy().into(),
+                content: Vec::new(),
+                mode,
+                modified: modified.to_string(),
+            };
+            insert(tx, &new_file, blob)?;
+            Ok(false)
+        }
+    }
+}
+
+pub fn delete(conn: &Connection, ident: FileIdentifier<'_>) -> Result<usize> {
+    let id = ident.get_id(conn)?;
     if id.is_none() {
         return Err(CabinetError::NotFound);
     }
     let n = conn
         .prepare("DELETE FROM file WHERE id IS ?")?
-        .execute(&[&id])?;
+        .execute([&id])?;
     Ok(n)
 }
 
-pub async fn get_id(conn: &Connection, path: &Path) -> Result<Option<usize>> {
+/// Return the revision number of the current version of a file.
+pub fn current_revision(conn: &Connection, id: usize) -> Result<usize> {
+    let rev = query_row!(conn,
+        "SELECT coalesce(max(revision), 0) + 1 FROM file_revision WHERE file IS ?" => |row| row.get(0);
+        id
+    )?;
+    Ok(rev)
+}
+
+/// List all revisions of a file, oldest first. The last entry is the
+/// current version.
+pub fn revisions(conn: &Connection, ident: FileIdentifier<'_>) -> Result<Vec<Revision>> {
+    let current = fetch_info(conn, ident)?;
+    let mut stmt = conn.prepare(
+        "SELECT file AS id, ? AS path, revision, size, mode, modified, hash
+           FROM file_revision JOIN blob ON file_revision.blob=blob.id
+          WHERE file IS ? ORDER BY revision",
+    )?;
+    let mut rows = stmt.query(params![current.path, current.id])?;
+    let mut revisions = Vec::new();
+    while let Some(row) = rows.next()? {
+        let file = FileInfo::try_from(row)?;
+        revisions.push(Revision::new(row.get("revision")?, &file));
+    }
+    revisions.push(Revision::new(current_revision(conn, current.id)?, &current));
+    Ok(revisions)
+}
+
+/// Fetch a specific revision of a file.
+///
+/// The returned file has the id and path of the current version, but the
+/// content, mode and modified date of the requested revision.
+///
+pub fn fetch_revision(conn: &Connection, ident: FileIdentifier<'_>, revision: usize) -> Result<File> {
+    use rusqlite::OptionalExtension;
+
+    let current = fetch(conn, ident)?;
+    if revision == current_revision(conn, current.id)? {
+        return Ok(current);
+    }
+    let file = conn
+        .prepare(
+            "SELECT file AS id, ? AS path, blob.content, codec,
+                    blob_dictionary.content AS dictionary, mode, modified
+               FROM file_revision JOIN blob ON file_revision.blob=blob.id
+                    LEFT JOIN blob_dictionary ON blob.dictionary=blob_dictionary.id
+              WHERE file IS ? AND revision IS ?",
+        )?
+        .query_row(params![current.path, current.id, revision], |row| File::try_from(row))
+        .optional()?;
+    file.ok_or(CabinetError::NotFound)
+}
+
+/// Find the revision of a file which was current at the given date.
+///
+/// If `None` is returned the file didn't exist at that date.
+///
+pub fn revision_at(conn: &Connection, id: usize, date: &HttpDate) -> Result<Option<usize>> {
+    let mut stmt = conn.prepare(
+        "SELECT revision, modified FROM file_revision WHERE file IS ?
+         UNION ALL
+         SELECT (SELECT coalesce(max(revision), 0) + 1 FROM file_revision WHERE file IS ?), modified
+           FROM file WHERE id IS ?
+         ORDER BY revision",
+    )?;
+    let mut rows = stmt.query(params![id, id, id])?;
+    let mut found = None;
+    while let Some(row) = rows.next()? {
+        let modified: String = row.get("modified")?;
+        if HttpDate::from_str(&modified)? > *date {
+            break;
+        }
+        found = Some(row.get("revision")?);
+    }
+    Ok(found)
+}
+
+/// Restore a file to the content and mode of a previous revision.
+///
+/// The restore is recorded as a new modification at `modified`, keeping
+/// the replaced version as a revision.
+///
+pub fn restore(
+    tx: &Transaction<'_>,
+    ident: FileIdentifier<'_>,
+    revision: usize,
+    modified: &str,
+) -> Result<FileInfo> {
+    use rusqlite::OptionalExtension;
+
+    let current = fetch_info(tx, ident)?;
+    let found: Option<(usize, u32)> = tx
+        .prepare(
+            "SELECT blob, mode FROM file_revision WHERE file IS ? AND revision IS ?
+             UNION ALL
+             SELECT blob, mode FROM file WHERE id IS ? AND ? IS ?",
+        )?
+        .query_row(
+            params![
+                current.id,
+                revision,
+                current.id,
+                revision,
+                current_revision(tx, current.id)?,
+            ],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    let (blob, mode) = found.ok_or(CabinetError::NotFound)?;
+
+    archive(tx, current.id)?;
+    tx.prepare("UPDATE file SET blob=?, mode=?, modified=? WHERE id IS ?")?
+        .execute(params![blob, mode, modified, current.id])?;
+    fetch_info(tx, FileIdentifier::Id(current.id))
+}
+
+pub fn get_id(conn: &Connection, path: &Path) -> Result<Option<usize>> {
     use rusqlite::OptionalExtension;
     let mut stmt = conn.prepare("SELECT id FROM file_path WHERE path IS ?")?;
     let id = stmt
@@ -156,10 +437,10 @@ pub enum FileIdentifier<'a> {
 }
 
 impl FileIdentifier<'_> {
-    pub async fn get_id(&self, conn: &Connection) -> Result<Option<usize>> {
+    pub fn get_id(&self, conn: &Connection) -> Result<Option<usize>> {
         match self {
             FileIdentifier::Id(id) => Ok(Some(*id)),
-            FileIdentifier::Path(path) => Ok(get_id(conn, path).await?),
+            FileIdentifier::Path(path) => Ok(get_id(conn, path)?),
         }
     }
 }
@@ -176,21 +457,29 @@ mod tests {
     use anyhow::Result;
     use rusqlite::Connection;
 
-    async fn db() -> Result<Connection> {
-        use crate::database::create_tables;
+    fn db() -> Result<Connection> {
+        use crate::database::migrations::migrate;
         let conn = Connection::open_in_memory()?;
-        create_tables(&conn).await?;
+        migrate(&conn)?;
         conn.execute("INSERT INTO directory VALUES (1, 'mydir', NULL)", [])?;
         Ok(conn)
     }
 
-    #[async_std::test]
-    async fn all() -> Result<()> {
-        let conn = db().await?;
+    /// Replace the content, mode and modified date of a file with those of `f`.
+    fn update_content(conn: &Connection, f: &File) -> Result<()> {
+        let tx = conn.unchecked_transaction()?;
+        update_from_reader(&tx, f.id, f.content.as_slice(), f.content.len(), f.mode, &f.modified)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn all() -> Result<()> {
+        let mut conn = db()?;
         let path_ident1 = FileIdentifier::Path("mydir/myfile".as_ref());
         let path_ident2 = FileIdentifier::Path("foo.txt".as_ref());
 
-        assert!(!exists(&conn, path_ident1.clone()).await.unwrap());
+        assert!(!exists(&conn, path_ident1.clone()).unwrap());
         create(
             &conn,
             &NewFile {
@@ -200,26 +489,232 @@ mod tests {
                 modified: "Wed, 21 Oct 2015 02:22:00 GMT".to_string(),
             },
         )
-        .await
         .unwrap();
-        assert!(exists(&conn, path_ident1.clone()).await.unwrap());
+        assert!(exists(&conn, path_ident1.clone()).unwrap());
 
-        let mut f: File = fetch(&conn, path_ident1.clone()).await.unwrap();
+        let mut f: File = fetch(&conn, path_ident1.clone()).unwrap();
         assert_eq!(f.path, "mydir/myfile".to_string());
         assert_eq!(f.mode, 0o755);
 
         let id_ident = FileIdentifier::Id(f.id);
 
+        move_to(&mut conn, f.id, "foo.txt".as_ref(), false).unwrap();
         f.path = "foo.txt".to_string();
-        update(&conn, &f).await.unwrap();
-        let res = fetch(&conn, path_ident1.clone()).await;
+        let res = fetch(&conn, path_ident1.clone());
         assert!(res.is_err());
 
-        assert_eq!(f, fetch(&conn, id_ident.clone()).await.unwrap());
-        assert_eq!(f, fetch(&conn, path_ident2.clone()).await.unwrap());
+        assert_eq!(f, fetch(&conn, id_ident.clone()).unwrap());
+        assert_eq!(f, fetch(&conn, path_ident2.clone()).unwrap());
+
+        delete(&conn, id_ident.clone()).unwrap();
+        assert!(!exists(&conn, id_ident.clone()).unwrap());
+
+        Ok(())
+    }
+
+    #[test]
+    fn revisions() -> Result<()> {
+        let mut conn = db()?;
+        let ident = FileIdentifier::Path("mydir/myfile".as_ref());
+
+        create(
+            &conn,
+            &NewFile {
+                path: "mydir/myfile".into(),
+                content: b"first".to_vec(),
+                mode: 0o644,
+                modified: "Wed, 21 Oct 2015 02:22:00 GMT".to_string(),
+            },
+        )
+        .unwrap();
+        let mut f: File = fetch(&conn, ident.clone()).unwrap();
+        assert_eq!(current_revision(&conn, f.id).unwrap(), 1);
+
+        //
+        // Renaming alone should not create a revision
+        //
+        move_to(&mut conn, f.id, "mydir/renamed".as_ref(), false).unwrap();
+        f.path = "mydir/renamed".into();
+        assert_eq!(current_revision(&conn, f.id).unwrap(), 1);
+
+        f.content = b"second".to_vec();
+        f.modified = "Thu, 22 Oct 2015 02:22:00 GMT".to_string();
+        update_content(&conn, &f).unwrap();
+
+        let id_ident = FileIdentifier::Id(f.id);
+        let revs = super::revisions(&conn, id_ident.clone()).unwrap();
+        assert_eq!(revs.len(), 2);
+        assert_eq!(revs[0].revision, 1);
+        assert_eq!(revs[0].size, 5);
+        assert_eq!(revs[1].revision, 2);
+        assert_eq!(revs[1].etag, f.content_hash());
+
+        let old = fetch_revision(&conn, id_ident.clone(), 1).unwrap();
+        assert_eq!(old.content, b"first".to_vec());
+        assert_eq!(old.path, "mydir/renamed".to_string());
+        assert_eq!(fetch_revision(&conn, id_ident.clone(), 2).unwrap(), f);
+        assert!(fetch_revision(&conn, id_ident.clone(), 3).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn restore_revision() -> Result<()> {
+        let conn = db()?;
+        let dates = [
+            "Wed, 21 Oct 2015 02:22:00 GMT",
+            "Thu, 22 Oct 2015 02:22:00 GMT",
+            "Fri, 23 Oct 2015 02:22:00 GMT",
+        ];
+
+        create(
+            &conn,
+            &NewFile {
+                path: "myfile".into(),
+                content: b"first".to_vec(),
+                mode: 0o644,
+                modified: dates[0].to_string(),
+            },
+        )
+        .unwrap();
+        let mut f: File = fetch(&conn, FileIdentifier::Path("myfile".as_ref())).unwrap();
+        f.content = b"second".to_vec();
+        f.mode = 0o755;
+        f.modified = dates[1].to_string();
+        update_content(&conn, &f).unwrap();
+
+        let before: HttpDate = "Tue, 20 Oct 2015 02:22:00 GMT".parse().unwrap();
+        let between: HttpDate = "Wed, 21 Oct 2015 12:00:00 GMT".parse().unwrap();
+        let after: HttpDate = dates[2].parse().unwrap();
+        assert_eq!(revision_at(&conn, f.id, &before).unwrap(), None);
+        assert_eq!(revision_at(&conn, f.id, &between).unwrap(), Some(1));
+        assert_eq!(revision_at(&conn, f.id, &after).unwrap(), Some(2));
+
+        let id_ident = FileIdentifier::Id(f.id);
+        let tx = conn.unchecked_transaction().unwrap();
+        let restored = restore(&tx, id_ident.clone(), 1, dates[2]).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(restored.size, 5);
+        assert_eq!(restored.mode, 0o644);
+        assert_eq!(fetch(&conn, id_ident.clone()).unwrap().content, b"first".to_vec());
+        assert_eq!(current_revision(&conn, f.id).unwrap(), 3);
+        assert_eq!(fetch_revision(&conn, id_ident, 2).unwrap(), f);
+
+        Ok(())
+    }
+
+    #[test]
+    fn shared_content() -> Result<()> {
+        use crate::database::blob;
+
+        let conn = db()?;
+        let new_file = |path: &str, content: &[u8]| NewFile {
+            path: path.into(),
+            content: content.to_vec(),
+            mode: 0o644,
+            modified: "Wed, 21 Oct 2015 02:22:00 GMT".to_string(),
+        };
+
+        let id1 = create(&conn, &new_file("one", b"same")).unwrap();
+        let id2 = create(&conn, &new_file("mydir/two", b"same")).unwrap();
+        assert_eq!(blob::count(&conn).unwrap(), 1);
+
+        //
+        // Revisions keep their content alive
+        //
+        let mut f = fetch(&conn, FileIdentifier::Id(id1)).unwrap();
+        f.content = b"different".to_vec();
+        f.modified = "Thu, 22 Oct 2015 02:22:00 GMT".to_string();
+        update_content(&conn, &f).unwrap();
+        assert_eq!(blob::count(&conn).unwrap(), 2);
+        delete(&conn, FileIdentifier::Id(id2)).unwrap();
+        assert_eq!(blob::count(&conn).unwrap(), 2);
+
+        //
+        // Unreferenced content is removed
+        //
+        delete(&conn, FileIdentifier::Id(id1)).unwrap();
+        assert_eq!(blob::count(&conn).unwrap(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn move_file() -> Result<()> {
+        let mut conn = db()?;
+        let new_file = |path: &str, content: &[u8]| NewFile {
+            path: path.into(),
+            content: content.to_vec(),
+            mode: 0o644,
+            modified: "Wed, 21 Oct 2015 02:22:00 GMT".to_string(),
+        };
+        let id = create(&conn, &new_file("a/first", b"first")).unwrap();
+        let tx = conn.transaction().unwrap();
+        archive(&tx, id).unwrap();
+        tx.commit().unwrap();
+        create(&conn, &new_file("second", b"second")).unwrap();
+
+        //
+        // Moving keeps the id and revisions
+        //
+        assert!(!move_to(&mut conn, id, "b/c/moved".as_ref(), false).unwrap());
+        let moved = fetch_info(&conn, FileIdentifier::Path("b/c/moved".as_ref())).unwrap();
+        assert_eq!(moved.id, id);
+        assert_eq!(current_revision(&conn, id).unwrap(), 2);
+        assert!(!exists(&conn, FileIdentifier::Path("a/first".as_ref())).unwrap());
+
+        //
+        // Existing files are only replaced when overwriting
+        //
+        assert!(matches!(
+            move_to(&mut conn, id, "second".as_ref(), false),
+            Err(CabinetError::PreconditionFailed)
+        ));
+        assert!(move_to(&mut conn, id, "second".as_ref(), true).unwrap());
+        assert_eq!(fetch(&conn, FileIdentifier::Path("second".as_ref())).unwrap().content, b"first");
+        assert_eq!(count(&conn).unwrap(), 1);
+        assert!(move_to(&mut conn, id, "second".as_ref(), true).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn copy_file() -> Result<()> {
+        let mut conn = db()?;
+        let new_file = |path: &str, content: &[u8]| NewFile {
+            path: path.into(),
+            content: content.to_vec(),
+            mode: 0o755,
+            modified: "Wed, 21 Oct 2015 02:22:00 GMT".to_string(),
+        };
+        let id = create(&conn, &new_file("a/first", b"first")).unwrap();
+        let other = create(&conn, &new_file("second", b"second")).unwrap();
+
+        //
+        // Copies share the content blob
+        //
+        assert!(!copy_to(&mut conn, id, "b/copy".as_ref(), false, None).unwrap());
+        let copied = fetch(&conn, FileIdentifier::Path("b/copy".as_ref())).unwrap();
+        assert_ne!(copied.id, id);
+        assert_eq!(copied.content, b"first");
+        assert_eq!(copied.mode, 0o755);
+        assert_eq!(copied.modified, "Wed, 21 Oct 2015 02:22:00 GMT");
+        assert_eq!(blob::count(&conn).unwrap(), 2);
 
-        delete(&conn, id_ident.clone()).await.unwrap();
-        assert!(!exists(&conn, id_ident.clone()).await.unwrap());
+        //
+        // Existing files are only updated when overwriting
+        //
+        let date = "Thu, 22 Oct 2015 02:22:00 GMT";
+        assert!(matches!(
+            copy_to(&mut conn, id, "second".as_ref(), false, Some(date)),
+            Err(CabinetError::PreconditionFailed)
+        ));
+        assert!(copy_to(&mut conn, id, "second".as_ref(), true, Some(date)).unwrap());
+        let updated = fetch(&conn, FileIdentifier::Id(other)).unwrap();
+        assert_eq!(updated.content, b"first");
+        assert_eq!(updated.modified, date);
+        assert_eq!(current_revision(&conn, other).unwrap(), 2);
+        assert!(copy_to(&mut conn, id, "a/first".as_ref(), true, None).is_err());
 
         Ok(())
     }
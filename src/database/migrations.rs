@@ -0,0 +1,257 @@
+//! Versioned schema migrations.
+//!
+//! The schema version of a database is stored in `PRAGMA user_version`, and
+//! is the version of the last migration applied to it. Each migration is
+//! applied in its own transaction, together with the version update.
+//!
+//! Databases created before migrations were introduced have version 0 but
+//! may already contain some of the tables. The migrations up to version 4
+//! are therefore written to be safe to apply to such databases.
+
+use crate::database::blob;
+use crate::{CabinetError, CabinetResult as Result};
+use rusqlite::Connection;
+
+/// A single schema migration.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    step: Step,
+}
+
+enum Step {
+    Sql(&'static str),
+    Code(fn(&Connection) -> Result<()>),
+}
+
+impl Migration {
+    fn apply(&self, conn: &Connection) -> Result<()> {
+        match self.step {
+            Step::Sql(sql) => conn.execute_batch(sql)?,
+            Step::Code(f) => f(conn)?,
+        }
+        Ok(())
+    }
+}
+
+/// All migrations, ordered by version.
+pub static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create file, directory and boilerplate tables",
+        step: Step::Sql(include_str!("migrations/0001_initial.sql")),
+    },
+    Migration {
+        version: 2,
+        description: "Keep file and boilerplate revisions",
+        step: Step::Sql(include_str!("migrations/0002_revisions.sql")),
+    },
+    Migration {
+        version: 3,
+        description: "Add API tokens",
+        step: Step::Sql(include_str!("migrations/0003_tokens.sql")),
+    },
+    Migration {
+        version: 4,
+        description: "Store file content in deduplicated blobs",
+        step: Step::Code(blob_storage),
+    },
+    Migration {
+        version: 5,
+        description: "Log changes of files, directories and boilerplates",
+        step: Step::Sql(include_str!("migrations/0005_changes.sql")),
+    },
+    Migration {
+        version: 6,
+        description: "Compress blob content",
+        step: Step::Sql(include_str!("migrations/0006_blob_compression.sql")),
+    },
+];
+
+/// Return the schema version of the database.
+pub fn version(conn: &Connection) -> Result<u32> {
+    let version = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    Ok(version)
+}
+
+/// Return the version of the latest migration.
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/// Return the migrations not yet applied to the database.
+pub fn pending(conn: &Connection) -> Result<Vec<&'static Migration>> {
+    let current = version(conn)?;
+    if current > latest_version() {
+        return Err(CabinetError::Other(format!(
+            "Database schema version {} is newer than the latest known version {}",
+            current,
+            latest_version()
+        )));
+    }
+    Ok(MIGRATIONS.iter().filter(|m| m.version > current).collect())
+}
+
+/// Apply all pending migrations, returning the applied migrations.
+///
+/// Foreign key enforcement is disabled while migrating, letting migrations
+/// rebuild tables without cascading deletes, and checked before each
+/// migration is committed.
+///
+pub fn migrate(conn: &Connection) -> Result<Vec<&'static Migration>> {
+    let pending = pending(conn)?;
+    if pending.is_empty() {
+        return Ok(pending);
+    }
+
+    conn.execute_batch(
+        "PRAGMA foreign_keys = OFF;
+         PRAGMA legacy_alter_table = ON;",
+    )?;
+    let res = pending.iter().try_for_each(|m| {
+        let tx = conn.unchecked_transaction()?;
+        m.apply(&tx).map_err(|e| {
+            CabinetError::Other(format!("Migration {} failed: {}", m.version, e))
+        })?;
+        if tx.prepare("PRAGMA foreign_key_check")?.exists([])? {
+            return Err(CabinetError::Other(format!(
+                "Migration {} failed: foreign key constraint violated",
+                m.version
+            )));
+        }
+        tx.pragma_update(None, "user_version", m.version)?;
+        tx.commit()?;
+        Ok(())
+    });
+    conn.execute_batch(
+        "PRAGMA legacy_alter_table = OFF;
+         PRAGMA foreign_keys = ON;",
+    )?;
+    res.map(|_| pending)
+}
+
+/// Move file content stored directly in the file and file revision tables
+/// into the blob table.
+fn blob_storage(conn: &Connection) -> Result<()> {
+    if !has_column(conn, "file", "content")? {
+        // Created after blobs were introduced, but before migrations
+        return Ok(());
+    }
+    conn.execute_batch(include_str!("migrations/0004_blob_storage.sql"))?;
+
+    let mut stmt = conn.prepare("SELECT id, name, parent, content, mode, modified FROM file_v1")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let content: Option<Vec<u8>> = row.get("content")?;
+        let blob = store_blob(conn, &content.unwrap_or_default())?;
+        let id: usize = row.get("id")?;
+        let name: String = row.get("name")?;
+        let parent: Option<usize> = row.get("parent")?;
+        let mode: u32 = row.get("mode")?;
+        let modified: String = row.get("modified")?;
+        conn.prepare("INSERT INTO file(id, name, parent, blob, mode, modified) VALUES (?, ?, ?, ?, ?, ?)")?
+            .execute(params![id, name, parent, blob, mode, modified])?;
+    }
+
+    let mut stmt = conn.prepare("SELECT id, file, revision, content, mode, modified FROM file_revision_v1")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let content: Option<Vec<u8>> = row.get("content")?;
+        let blob = store_blob(conn, &content.unwrap_or_default())?;
+        let id: usize = row.get("id")?;
+        let file: usize = row.get("file")?;
+        let revision: usize = row.get("revision")?;
+        let mode: u32 = row.get("mode")?;
+        let modified: String = row.get("modified")?;
+        conn.prepare("INSERT INTO file_revision(id, file, revision, blob, mode, modified) VALUES (?, ?, ?, ?, ?, ?)")?
+            .execute(params![id, file, revision, blob, mode, modified])?;
+    }
+
+    conn.execute_batch(
+        "DROP TABLE file_revision_v1;
+         DROP TABLE file_v1;",
+    )?;
+    Ok(())
+}
+
+/// Store content in the blob table as it was at version 4, before blobs
+/// were compressed.
+fn store_blob(conn: &Connection, content: &[u8]) -> Result<usize> {
+    let hash = blob::hash(content);
+    if let Some(id) = blob::get_id(conn, &hash)? {
+        return Ok(id);
+    }
+    let id = conn
+        .prepare("INSERT INTO blob(hash, size, content) VALUES (?, ?, ?)")?
+        .insert(params![hash, content.len(), content])?;
+    Ok(id as usize)
+}
+
+/// Check if `table` exists and has a column named `column`.
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let exists = conn
+        .prepare("SELECT name FROM pragma_table_info(?) WHERE name IS ?")?
+        .exists([table, column])?;
+    Ok(exists)
+}
+
+/*******************************************************************************
+ *                                                                             *
+ * Tests
+ *                                                                             *
+ *******************************************************************************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use rusqlite::Connection;
+
+    #[test]
+    fn versions_are_ordered() {
+        for (i, m) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(m.version as usize, i + 1);
+        }
+    }
+
+    #[test]
+    fn fresh_database() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        assert_eq!(version(&conn).unwrap(), 0);
+        assert_eq!(pending(&conn).unwrap().len(), MIGRATIONS.len());
+
+        assert_eq!(migrate(&conn).unwrap().len(), MIGRATIONS.len());
+        assert_eq!(version(&conn).unwrap(), latest_version());
+        assert!(pending(&conn).unwrap().is_empty());
+        assert!(migrate(&conn).unwrap().is_empty());
+
+        conn.pragma_update(None, "user_version", latest_version() + 1)?;
+        assert!(pending(&conn).is_err());
+        assert!(migrate(&conn).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn unversioned_database() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        MIGRATIONS[0].apply(&conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO directory VALUES (1, 'mydir', NULL);
+             INSERT INTO file VALUES (1, 'a', 1, x'6869', 420, 'Wed, 21 Oct 2015 02:22:00 GMT');
+             INSERT INTO file VALUES (2, 'b', NULL, x'6869', 420, 'Wed, 21 Oct 2015 02:22:00 GMT');
+             INSERT INTO boilerplate VALUES (1, 'bp', 'Wed, 21 Oct 2015 02:22:00 GMT', NULL);
+             INSERT INTO bp_file_map VALUES (1, 1, 1, '~/a');",
+        )?;
+
+        migrate(&conn).unwrap();
+        assert_eq!(version(&conn).unwrap(), latest_version());
+        assert_eq!(blob::count(&conn).unwrap(), 1);
+        let path: String = conn.query_row("SELECT path FROM file_path WHERE id=1", [], |row| row.get(0))?;
+        assert_eq!(path, "mydir/a");
+        let n: usize = conn.query_row("SELECT count(*) FROM bp_file_map", [], |row| row.get(0))?;
+        assert_eq!(n, 1);
+
+        Ok(())
+    }
+}
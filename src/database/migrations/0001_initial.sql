@@ -1,8 +1,4 @@
-
-PRAGMA foreign_keys = ON;
-
-BEGIN;
-
+-- Initial schema: files, directories and boilerplates.
 
 --------------------------------------------------------------------------------
 -- File
@@ -71,5 +67,3 @@ WITH
 SELECT boilerplate, path, location
 FROM bp_file_map JOIN files
 ON bp_file_map.file = files.id;
-
-COMMIT;
@@ -0,0 +1,25 @@
+-- Keep prior versions of files and boilerplates.
+
+-- Prior versions of a file. The current version is stored in the file table
+-- and has revision number max(revision)+1.
+CREATE TABLE IF NOT EXISTS file_revision (
+    id       INTEGER PRIMARY KEY,
+    file     INTEGER NOT NULL REFERENCES file ON DELETE CASCADE,
+    revision INTEGER NOT NULL,
+    content  BLOB,
+    mode     INTEGER NOT NULL,
+    modified DATETIME NOT NULL,
+    UNIQUE (file, revision)
+);
+
+-- Prior versions of a boilerplate. The current version is stored in the
+-- boilerplate table and has revision number max(revision)+1.
+CREATE TABLE IF NOT EXISTS bp_revision (
+    id          INTEGER PRIMARY KEY,
+    boilerplate INTEGER NOT NULL REFERENCES boilerplate ON DELETE CASCADE,
+    revision    INTEGER NOT NULL,
+    modified    TEXT NOT NULL,
+    script      CLOB,
+    files       TEXT NOT NULL, -- JSON map of client-side location to file id
+    UNIQUE (boilerplate, revision)
+);
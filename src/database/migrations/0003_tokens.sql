@@ -0,0 +1,11 @@
+-- API tokens.
+
+CREATE TABLE IF NOT EXISTS token (
+    id      INTEGER PRIMARY KEY,
+    name    TEXT NOT NULL UNIQUE,
+    hash    TEXT NOT NULL UNIQUE, -- SHA-256 of the token secret
+    scope   TEXT NOT NULL,
+    prefix  TEXT,                 -- Server-side path prefix the token is restricted to
+    created TEXT NOT NULL,
+    CHECK( scope IN ('read', 'write') )
+);
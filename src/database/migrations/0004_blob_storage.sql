@@ -0,0 +1,80 @@
+-- Store file content in a table of deduplicated blobs, keyed by hash.
+--
+-- The old file tables are renamed and replaced by tables referencing blobs.
+-- Their content is copied to the new tables, and the old tables dropped, by
+-- the migration code.
+
+ALTER TABLE file RENAME TO file_v1;
+ALTER TABLE file_revision RENAME TO file_revision_v1;
+DROP INDEX path_idx;
+
+-- File content, stored once per unique content and shared by all files and
+-- file revisions with that content.
+CREATE TABLE blob (
+    id      INTEGER PRIMARY KEY,
+    hash    TEXT UNIQUE,               -- SHA-1 of the content, NULL while being written
+    size    INTEGER NOT NULL,
+    refs    INTEGER NOT NULL DEFAULT 0, -- Number of files and revisions using the blob
+    content BLOB NOT NULL
+);
+
+-- Delete blobs once they are no longer referenced
+CREATE TRIGGER blob_gc AFTER UPDATE OF refs ON blob
+WHEN new.refs <= 0
+BEGIN
+    DELETE FROM blob WHERE id=new.id;
+END;
+
+CREATE TABLE file (
+    id       INTEGER PRIMARY KEY, -- entry id
+    name     TEXT NOT NULL,
+    parent   INTEGER REFERENCES directory ON DELETE CASCADE,
+    blob     INTEGER NOT NULL REFERENCES blob,
+    mode     INTEGER NOT NULL,
+    modified DATETIME NOT NULL,
+    UNIQUE (name, parent),
+    CHECK( typeof(name)='text' AND length(name)>0 )
+);
+
+CREATE INDEX path_idx ON file(name, parent);
+
+-- Prior versions of a file. The current version is stored in the file table
+-- and has revision number max(revision)+1.
+CREATE TABLE file_revision (
+    id       INTEGER PRIMARY KEY,
+    file     INTEGER NOT NULL REFERENCES file ON DELETE CASCADE,
+    revision INTEGER NOT NULL,
+    blob     INTEGER NOT NULL REFERENCES blob,
+    mode     INTEGER NOT NULL,
+    modified DATETIME NOT NULL,
+    UNIQUE (file, revision)
+);
+
+-- Blob reference counting
+
+CREATE TRIGGER file_blob_insert AFTER INSERT ON file
+BEGIN
+    UPDATE blob SET refs=refs+1 WHERE id=new.blob;
+END;
+
+CREATE TRIGGER file_blob_update AFTER UPDATE OF blob ON file
+WHEN old.blob IS NOT new.blob
+BEGIN
+    UPDATE blob SET refs=refs+1 WHERE id=new.blob;
+    UPDATE blob SET refs=refs-1 WHERE id=old.blob;
+END;
+
+CREATE TRIGGER file_blob_delete AFTER DELETE ON file
+BEGIN
+    UPDATE blob SET refs=refs-1 WHERE id=old.blob;
+END;
+
+CREATE TRIGGER file_revision_blob_insert AFTER INSERT ON file_revision
+BEGIN
+    UPDATE blob SET refs=refs+1 WHERE id=new.blob;
+END;
+
+CREATE TRIGGER file_revision_blob_delete AFTER DELETE ON file_revision
+BEGIN
+    UPDATE blob SET refs=refs-1 WHERE id=old.blob;
+END;
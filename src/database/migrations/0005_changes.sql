@@ -0,0 +1,107 @@
+-- Log of changes to files, directories and boilerplates, for clients to
+-- synchronize incrementally. Changes are recorded by triggers, so every
+-- write through the database layer is logged.
+
+-- The id of a change is the sync cursor. AUTOINCREMENT guarantees ids are
+-- never reused, so cursors only ever increase.
+CREATE TABLE change (
+    id       INTEGER PRIMARY KEY AUTOINCREMENT,
+    kind     TEXT NOT NULL,
+    action   TEXT NOT NULL,
+    path     TEXT NOT NULL, -- Path of files and directories, name of boilerplates
+    old_path TEXT,          -- Previous path of moved files and directories
+    time     TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+    CHECK( kind IN ('file', 'dir', 'boilerplate') ),
+    CHECK( action IN ('insert', 'update', 'delete', 'move') )
+);
+
+CREATE VIEW dir_path(id, path) AS
+WITH RECURSIVE
+    -- Recursively build the directory paths from their parents
+    paths(id, name, parent) AS (
+        SELECT id, name, parent FROM directory
+        UNION
+        SELECT paths.id, directory.name || '/' || paths.name, directory.parent
+          FROM paths, directory
+         WHERE directory.id=paths.parent
+    )
+SELECT id, name FROM paths WHERE parent IS NULL;
+
+
+--------------------------------------------------------------------------------
+-- File
+
+CREATE TRIGGER file_change_insert AFTER INSERT ON file
+BEGIN
+    INSERT INTO change(kind, action, path)
+    SELECT 'file', 'insert', path FROM file_path WHERE id=new.id;
+END;
+
+CREATE TRIGGER file_change_update AFTER UPDATE OF blob, mode, modified ON file
+BEGIN
+    INSERT INTO change(kind, action, path)
+    SELECT 'file', 'update', path FROM file_path WHERE id=new.id;
+END;
+
+-- Paths are built from the parents, so the old path is only available
+-- before the update
+CREATE TRIGGER file_change_move BEFORE UPDATE OF name, parent ON file
+WHEN old.name IS NOT new.name OR old.parent IS NOT new.parent
+BEGIN
+    INSERT INTO change(kind, action, path, old_path)
+    SELECT 'file', 'move',
+           coalesce((SELECT path || '/' FROM dir_path WHERE id=new.parent), '') || new.name,
+           path
+      FROM file_path WHERE id=old.id;
+END;
+
+CREATE TRIGGER file_change_delete BEFORE DELETE ON file
+BEGIN
+    INSERT INTO change(kind, action, path)
+    VALUES ('file', 'delete', coalesce((SELECT path FROM file_path WHERE id=old.id), old.name));
+END;
+
+
+--------------------------------------------------------------------------------
+-- Directory
+
+CREATE TRIGGER dir_change_insert AFTER INSERT ON directory
+BEGIN
+    INSERT INTO change(kind, action, path)
+    SELECT 'dir', 'insert', path FROM dir_path WHERE id=new.id;
+END;
+
+CREATE TRIGGER dir_change_move BEFORE UPDATE OF name, parent ON directory
+WHEN old.name IS NOT new.name OR old.parent IS NOT new.parent
+BEGIN
+    INSERT INTO change(kind, action, path, old_path)
+    SELECT 'dir', 'move',
+           coalesce((SELECT path || '/' FROM dir_path WHERE id=new.parent), '') || new.name,
+           path
+      FROM dir_path WHERE id=old.id;
+END;
+
+CREATE TRIGGER dir_change_delete BEFORE DELETE ON directory
+BEGIN
+    INSERT INTO change(kind, action, path)
+    VALUES ('dir', 'delete', coalesce((SELECT path FROM dir_path WHERE id=old.id), old.name));
+END;
+
+
+--------------------------------------------------------------------------------
+-- Boilerplate
+
+CREATE TRIGGER bp_change_insert AFTER INSERT ON boilerplate
+BEGIN
+    INSERT INTO change(kind, action, path) VALUES ('boilerplate', 'insert', new.name);
+END;
+
+CREATE TRIGGER bp_change_update AFTER UPDATE ON boilerplate
+BEGIN
+    INSERT INTO change(kind, action, path) VALUES ('boilerplate', 'update', new.name);
+END;
+
+CREATE TRIGGER bp_change_delete AFTER DELETE ON boilerplate
+BEGIN
+    INSERT INTO change(kind, action, path) VALUES ('boilerplate', 'delete', old.name);
+END;
@@ -0,0 +1,16 @@
+-- Store blob content compressed. The codec of each blob tells how its
+-- content is stored, while the size is always that of the original content.
+-- Existing blobs are left raw until they are recompressed.
+
+-- Compression dictionaries trained on small text files, which are too small
+-- to compress well on their own. The latest dictionary is used for new
+-- blobs, older ones are kept while blobs still use them.
+CREATE TABLE blob_dictionary (
+    id      INTEGER PRIMARY KEY,
+    created TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+    content BLOB NOT NULL
+);
+
+ALTER TABLE blob ADD COLUMN codec TEXT NOT NULL DEFAULT 'raw'
+    CHECK( codec IN ('raw', 'zstd') );
+ALTER TABLE blob ADD COLUMN dictionary INTEGER REFERENCES blob_dictionary;
@@ -2,21 +2,65 @@
 //! with the database for the rest of the server.
 //! 
 //! All conversion to and from rust types are handled by this module.
+//!
+//! The database functions are blocking. Request handlers run them on the
+//! blocking thread pool, with a connection from the shared connection pool,
+//! through `block`.
 
-use rusqlite::{Connection, Result as RResult};
+use crate::config::Config;
+use crate::{CabinetError, CabinetResult as Result};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::Connection;
+use std::time::Duration;
 
+pub mod blob;
 pub mod file;
 pub mod dir;
 pub mod boilerplate;
+pub mod migrations;
+pub mod token;
+pub mod batch;
+pub mod change;
 
-/// Module-internal interface
+pub type Pool = r2d2::Pool<SqliteConnectionManager>;
+
+/// Create the database connection pool.
+///
+/// All connections have foreign keys enabled and wait up to the configured
+/// busy timeout for locks. The database is put in WAL mode, letting readers
+/// run concurrently with a writer.
 ///
-/// Required functionality:
-///  1. Execute a single statement
-///  2. Query a single row
-///  3. Query multiple rows
+pub fn create_pool(config: &Config) -> Result<Pool> {
+    let busy_timeout = Duration::from_millis(config.busy_timeout);
+    let manager = SqliteConnectionManager::file(&config.database).with_init(move |conn| {
+        conn.busy_timeout(busy_timeout)?;
+        conn.pragma_update(None, "foreign_keys", "ON")?;
+        conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))
+    });
+    let pool = r2d2::Pool::builder()
+        .max_size(config.pool_size)
+        .build(manager)
+        .map_err(|e| CabinetError::Other(e.to_string()))?;
+    Ok(pool)
+}
+
+/// Run `f` with a pooled connection on the blocking thread pool.
+pub async fn block<F, T>(pool: &Pool, f: F) -> Result<T>
+where
+    F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    use actix_web::error::BlockingError;
 
-pub async fn create_tables(conn: &Connection) -> RResult<()> {
-    let sql = include_str!("tables.sql");
-    conn.execute_batch(sql)
+    let pool = pool.clone();
+    let res = actix_web::web::block(move || {
+        let mut conn = pool.get().map_err(|e| CabinetError::Other(e.to_string()))?;
+        f(&mut conn)
+    })
+    .await;
+    match res {
+        Ok(val) => Ok(val),
+        Err(BlockingError::Error(e)) => Err(e),
+        Err(BlockingError::Canceled) => Err(CabinetError::Other("Database task canceled".into())),
+    }
 }
@@ -0,0 +1,128 @@
+//! Interface for API token entries in the database.
+
+use crate::token::{NewToken, Token};
+use crate::{CabinetError, CabinetResult as Result};
+use rusqlite::{Connection, OptionalExtension};
+use std::convert::TryFrom;
+
+pub fn count(conn: &Connection) -> Result<usize> {
+    let mut stmt = conn.prepare("SELECT count(*) FROM token")?;
+    let count = stmt.query_row([], |row| row.get(0))?;
+    Ok(count)
+}
+
+/// Return all tokens, ordered by name.
+pub fn all(conn: &Connection) -> Result<Vec<Token>> {
+    let mut stmt = conn.prepare("SELECT * FROM token ORDER BY name")?;
+    let mut tokens = Vec::new();
+    for res in stmt.query_map([], |row| Token::try_from(row))? {
+        tokens.push(res?);
+    }
+    Ok(tokens)
+}
+
+/// Fetch the token with the given secret hash.
+pub fn fetch_by_hash(conn: &Connection, hash: &str) -> Result<Token> {
+    let token = conn
+        .prepare("SELECT * FROM token WHERE hash IS ?")?
+        .query_row([hash], |row| Token::try_from(row))
+        .optional()?;
+    token.ok_or(CabinetError::NotFound)
+}
+
+/// Fetch the token with the given name.
+pub fn fetch_by_name(conn: &Connection, name: &str) -> Result<Token> {
+    let token = conn
+        .prepare("SELECT * FROM token WHERE name IS ?")?
+        .query_row([name], |row| Token::try_from(row))
+        .optional()?;
+    token.ok_or(CabinetError::NotFound)
+}
+
+pub fn create(conn: &Connection, token: &NewToken) -> Result<usize> {
+    use crate::CabinetError::BadRequest;
+
+    if get_id(conn, &token.name)?.is_some() {
+        return Err(BadRequest(format!("Token already exists: {}", token.name)));
+    }
+    let mut stmt = conn.prepare(
+        "INSERT INTO token(name, hash, scope, prefix, created) VALUES (?, ?, ?, ?, ?)",
+    )?;
+    let id = stmt.insert(params![
+        token.name,
+        token.hash,
+        token.scope,
+        token.prefix,
+        token.created,
+    ])?;
+    Ok(id as usize)
+}
+
+pub fn delete(conn: &Connection, name: &str) -> Result<()> {
+    let n = conn
+        .prepare("DELETE FROM token WHERE name IS ?")?
+        .execute([name])?;
+    if n == 0 {
+        return Err(CabinetError::NotFound);
+    }
+    Ok(())
+}
+
+pub fn get_id(conn: &Connection, name: &str) -> Result<Option<usize>> {
+    let mut stmt = conn.prepare("SELECT id FROM token WHERE name IS ?")?;
+    let id = stmt.query_row([name], |row| row.get(0)).optional()?;
+    Ok(id)
+}
+
+/*******************************************************************************
+ *                                                                             *
+ * Tests
+ *                                                                             *
+ *******************************************************************************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::{hash, Scope};
+    use anyhow::Result;
+    use rusqlite::Connection;
+
+    fn db() -> Result<Connection> {
+        use crate::database::migrations::migrate;
+        let conn = Connection::open_in_memory()?;
+        migrate(&conn)?;
+        Ok(conn)
+    }
+
+    #[test]
+    fn all_token_functions() -> Result<()> {
+        let conn = db()?;
+        let new_token = NewToken {
+            name: "laptop".into(),
+            hash: hash("secret"),
+            scope: Scope::Read,
+            prefix: Some("dotfiles".into()),
+            created: "Wed, 21 Oct 2015 02:22:00 GMT".into(),
+        };
+
+        assert_eq!(count(&conn).unwrap(), 0);
+        create(&conn, &new_token).unwrap();
+        assert!(create(&conn, &new_token).is_err());
+        assert_eq!(count(&conn).unwrap(), 1);
+
+        let token = fetch_by_hash(&conn, &hash("secret")).unwrap();
+        assert_eq!(token.name, new_token.name);
+        assert_eq!(token.scope, Scope::Read);
+        assert_eq!(token.prefix, new_token.prefix);
+        assert!(fetch_by_hash(&conn, &hash("wrong")).is_err());
+        assert_eq!(fetch_by_name(&conn, "laptop").unwrap(), token);
+        assert!(fetch_by_name(&conn, "desktop").is_err());
+        assert_eq!(all(&conn).unwrap(), vec![token]);
+
+        delete(&conn, "laptop").unwrap();
+        assert!(delete(&conn, "laptop").is_err());
+        assert_eq!(count(&conn).unwrap(), 0);
+
+        Ok(())
+    }
+}
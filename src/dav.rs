@@ -0,0 +1,501 @@
+//! WebDAV support: property requests, multistatus responses and locks.
+//!
+//! The WebDAV interface exposes the file tree as defined by RFC 4918, with
+//! class 2 write locks. Locks are only kept in memory, and are lost when the
+//! server is restarted.
+
+use crate::dir::{DirEntry, EntryType};
+use crate::{CabinetError, CabinetResult as Result};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const DAV_NS: &[u8] = b"DAV:";
+
+/// Longest lock timeout granted, also used for infinite timeouts.
+const MAX_LOCK_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// The live properties of resources, in the DAV: namespace.
+const LIVE_PROPS: &[&str] = &[
+    "displayname",
+    "getcontentlength",
+    "getcontenttype",
+    "getetag",
+    "getlastmodified",
+    "lockdiscovery",
+    "resourcetype",
+    "supportedlock",
+];
+
+/// A property name: namespace and local name.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct PropName {
+    pub ns: String,
+    pub name: String,
+}
+
+/// The properties requested by a PROPFIND request.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PropFind {
+    AllProp,
+    PropName,
+    Props(Vec<PropName>),
+}
+
+impl PropFind {
+    /// Parse the body of a PROPFIND request. An empty body requests all
+    /// properties.
+    pub fn parse(body: &[u8]) -> Result<PropFind> {
+        if body.iter().all(u8::is_ascii_whitespace) {
+            return Ok(PropFind::AllProp);
+        }
+        let mut find = None;
+        let mut props = Vec::new();
+        parse_xml(body, |ns, name, path| {
+            match (path, name) {
+                ([_], "allprop") => find = Some(PropFind::AllProp),
+                ([_], "propname") => find = Some(PropFind::PropName),
+                ([_, "prop"], _) => props.push(PropName { ns: ns.to_string(), name: name.to_string() }),
+                _ => (),
+            };
+        })?;
+        Ok(find.unwrap_or(PropFind::Props(props)))
+    }
+}
+
+/// Parse the names of the properties set or removed by a PROPPATCH request.
+pub fn parse_proppatch(body: &[u8]) -> Result<Vec<PropName>> {
+    let mut props = Vec::new();
+    parse_xml(body, |ns, name, path| {
+        if let [_, _, "prop"] = path {
+            props.push(PropName { ns: ns.to_string(), name: name.to_string() });
+        }
+    })?;
+    Ok(props)
+}
+
+/// The lock requested by a LOCK request.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LockInfo {
+    pub shared: bool,
+    /// The owner element, as XML.
+    pub owner: Option<String>,
+}
+
+impl LockInfo {
+    /// Parse the body of a LOCK request.
+    ///
+    /// Only the text of the owner element is kept, within an href element
+    /// if it had one.
+    ///
+    pub fn parse(body: &[u8]) -> Result<LockInfo> {
+        let mut shared = false;
+        let mut owner_href = false;
+        parse_xml(body, |_, name, path| match (path, name) {
+            ([_, "lockscope"], "shared") => shared = true,
+            ([_, "owner", ..], "href") => owner_href = true,
+            _ => (),
+        })?;
+
+        let mut reader = Reader::from_reader(body);
+        reader.trim_text(true);
+        let mut buf = Vec::new();
+        let mut depth = None;
+        let mut text = String::new();
+        loop {
+            match reader.read_event(&mut buf) {
+                Ok(Event::Start(e)) if depth.is_none() && e.local_name() == b"owner" => depth = Some(0),
+                Ok(Event::Start(_)) => depth = depth.map(|d| d + 1),
+                Ok(Event::End(_)) if depth == Some(0) => break,
+                Ok(Event::End(_)) => depth = depth.map(|d| d - 1),
+                Ok(Event::Text(t)) if depth.is_some() => {
+                    text += &t.unescape_and_decode(&reader).unwrap_or_default();
+                }
+                Ok(Event::Eof) | Err(_) => break,
+                _ => (),
+            }
+            buf.clear();
+        }
+
+        let owner = match (depth, owner_href) {
+            (None, _) => None,
+            (Some(_), true) => Some(format!("<D:owner><D:href>{}</D:href></D:owner>", escape(&text))),
+            (Some(_), false) => Some(format!("<D:owner>{}</D:owner>", escape(&text))),
+        };
+        Ok(LockInfo { shared, owner })
+    }
+}
+
+/// Call `f` with the namespace, local name and ancestor local names of each
+/// element in an XML document.
+fn parse_xml<F>(body: &[u8], mut f: F) -> Result<()>
+where
+    F: FnMut(&str, &str, &[&str]),
+{
+    let mut reader = Reader::from_reader(body);
+    reader.trim_text(true);
+    let mut buf = Vec::new();
+    let mut ns_buf = Vec::new();
+    let mut path: Vec<String> = Vec::new();
+    loop {
+        let (ns, event) = reader
+            .read_namespaced_event(&mut buf, &mut ns_buf)
+            .map_err(|e| CabinetError::BadRequest(format!("invalid XML: {}", e)))?;
+        match event {
+            Event::Start(ref e) | Event::Empty(ref e) => {
+                let ns = String::from_utf8_lossy(ns.unwrap_or_default()).to_string();
+                let name = String::from_utf8_lossy(e.local_name()).to_string();
+                let ancestors: Vec<&str> = path.iter().map(String::as_str).collect();
+                f(&ns, &name, &ancestors);
+                if let Event::Start(_) = event {
+                    path.push(name);
+                }
+            }
+            Event::End(_) => {
+                path.pop();
+            }
+            Event::Eof if path.is_empty() => break,
+            Event::Eof => return Err(CabinetError::BadRequest("invalid XML: unexpected end".into())),
+            _ => (),
+        }
+        buf.clear();
+    }
+    Ok(())
+}
+
+/*******************************************************************************
+ *                                                                             *
+ * Multistatus
+ *                                                                             *
+ *******************************************************************************/
+
+/// Builder of multistatus response bodies.
+pub struct MultiStatus {
+    xml: String,
+}
+
+impl Default for MultiStatus {
+    fn default() -> Self {
+        MultiStatus {
+            xml: String::from(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+            ),
+        }
+    }
+}
+
+impl MultiStatus {
+    /// Add the response to a PROPFIND request for `entry`.
+    pub fn propfind(&mut self, entry: &DirEntry, find: &PropFind, locks: &[Lock]) {
+        let mut found = String::new();
+        let mut missing = String::new();
+        match find {
+            PropFind::AllProp => {
+                for name in LIVE_PROPS {
+                    if let Some(value) = live_prop(entry, name, locks) {
+                        found += &value;
+                    }
+                }
+            }
+            PropFind::PropName => {
+                for name in LIVE_PROPS {
+                    if live_prop(entry, name, locks).is_some() {
+                        found += &format!("<D:{}/>", name);
+                    }
+                }
+            }
+            PropFind::Props(props) => {
+                for prop in props {
+                    let value = match prop.ns.as_bytes() {
+                        DAV_NS => live_prop(entry, &prop.name, locks),
+                        _ => None,
+                    };
+                    match value {
+                        Some(value) => found += &value,
+                        None => missing += &empty_element(prop),
+                    }
+                }
+            }
+        }
+
+        self.xml += &format!("<D:response><D:href>{}</D:href>", escape(&entry_href(entry)));
+        if !found.is_empty() || missing.is_empty() {
+            self.xml += &propstat(&found, "200 OK");
+        }
+        if !missing.is_empty() {
+            self.xml += &propstat(&missing, "404 Not Found");
+        }
+        self.xml += "</D:response>\n";
+    }
+
+    /// Add the response to a PROPPATCH request for `entry`. Properties can't
+    /// be changed, so every property is refused.
+    pub fn proppatch(&mut self, entry: &DirEntry, props: &[PropName]) {
+        let refused: String = props.iter().map(empty_element).collect();
+        self.xml += &format!("<D:response><D:href>{}</D:href>", escape(&entry_href(entry)));
+        self.xml += &propstat(&refused, "403 Forbidden");
+        self.xml += "</D:response>\n";
+    }
+
+    pub fn finish(mut self) -> String {
+        self.xml += "</D:multistatus>\n";
+        self.xml
+    }
+}
+
+/// Get a live property of `entry`, as XML. `None` is returned if the
+/// property is unknown or not defined for the entry.
+fn live_prop(entry: &DirEntry, name: &str, locks: &[Lock]) -> Option<String> {
+    let file = entry.entry_type == EntryType::File;
+    let value = match name {
+        "displayname" => escape(&entry.name),
+        "getcontentlength" if file => entry.size?.to_string(),
+        "getcontenttype" if file => mime_guess::from_path(&entry.path).first_or_text_plain().to_string(),
+        "getetag" if file => format!("\"{}\"", entry.etag.as_ref()?),
+        "getlastmodified" if file => escape(entry.modified.as_ref()?),
+        "lockdiscovery" => locks.iter().map(Lock::to_xml).collect(),
+        "resourcetype" if file => String::new(),
+        "resourcetype" => "<D:collection/>".to_string(),
+        "supportedlock" => String::from(
+            "<D:lockentry><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>\
+             <D:lockentry><D:lockscope><D:shared/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>",
+        ),
+        _ => return None,
+    };
+    Some(format!("<D:{0}>{1}</D:{0}>", name, value))
+}
+
+fn propstat(props: &str, status: &str) -> String {
+    format!(
+        "<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 {}</D:status></D:propstat>",
+        props, status
+    )
+}
+
+fn empty_element(prop: &PropName) -> String {
+    if prop.ns.as_bytes() == DAV_NS {
+        format!("<D:{}/>", prop.name)
+    } else {
+        format!("<X:{} xmlns:X=\"{}\"/>", prop.name, escape(&prop.ns))
+    }
+}
+
+/// Get the URL path of the resource at `path`. Collections end with a
+/// slash.
+pub fn href(path: &str, collection: bool) -> String {
+    use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+
+    const SEGMENT: &AsciiSet = &CONTROLS
+        .add(b' ').add(b'"').add(b'#').add(b'%').add(b'<').add(b'>')
+        .add(b'?').add(b'`').add(b'{').add(b'}').add(b'&');
+
+    let mut href = String::from("/dav/");
+    for (i, segment) in path.split('/').filter(|s| !s.is_empty()).enumerate() {
+        if i > 0 {
+            href.push('/');
+        }
+        href.extend(utf8_percent_encode(segment, SEGMENT));
+    }
+    if collection && !href.ends_with('/') {
+        href.push('/');
+    }
+    href
+}
+
+fn entry_href(entry: &DirEntry) -> String {
+    href(&entry.path, entry.entry_type == EntryType::Dir)
+}
+
+/// Escape text for use in XML.
+pub fn escape(txt: &str) -> String {
+    String::from_utf8_lossy(&quick_xml::escape::escape(txt.as_bytes())).to_string()
+}
+
+/*******************************************************************************
+ *                                                                             *
+ * Locks
+ *                                                                             *
+ *******************************************************************************/
+
+/// A write lock on a file or directory.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Lock {
+    pub token: String,
+    /// Path of the locked resource.
+    pub path: String,
+    /// Whether the lock covers all descendants of a directory.
+    pub infinite: bool,
+    pub shared: bool,
+    pub owner: Option<String>,
+    pub timeout: Duration,
+    pub expires: Instant,
+}
+
+impl Lock {
+    /// Check if the lock covers the resource at `path`.
+    pub fn covers(&self, path: &str) -> bool {
+        path == self.path || (self.infinite && is_descendant(path, &self.path))
+    }
+
+    /// Get the lock as an activelock element.
+    pub fn to_xml(&self) -> String {
+        let scope = if self.shared { "shared" } else { "exclusive" };
+        let depth = if self.infinite { "infinity" } else { "0" };
+        let remaining = self.expires.saturating_duration_since(Instant::now()).as_millis().div_ceil(1000);
+        format!(
+            "<D:activelock><D:locktype><D:write/></D:locktype><D:lockscope><D:{}/></D:lockscope>\
+             <D:depth>{}</D:depth>{}<D:timeout>Second-{}</D:timeout>\
+             <D:locktoken><D:href>{}</D:href></D:locktoken>\
+             <D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+            scope,
+            depth,
+            self.owner.as_deref().unwrap_or_default(),
+            remaining,
+            self.token,
+            escape(&href(&self.path, false)),
+        )
+    }
+}
+
+/// Check if `path` is below the directory at `dir`.
+fn is_descendant(path: &str, dir: &str) -> bool {
+    dir.is_empty() && !path.is_empty()
+        || path.len() > dir.len() && path.starts_with(dir) && path.as_bytes()[dir.len()] == b'/'
+}
+
+/// In-memory store of the active locks, shared by all workers.
+#[derive(Debug, Default)]
+pub struct LockManager {
+    locks: Mutex<HashMap<String, Lock>>,
+}
+
+impl LockManager {
+    /// Lock the resource at `path`, returning the new lock. `None` is
+    /// returned if the lock conflicts with an existing lock.
+    pub fn lock(&self, path: &str, infinite: bool, info: LockInfo, timeout: Duration) -> Option<Lock> {
+        let mut locks = self.active();
+        let conflict = locks.values().any(|l| {
+            let overlaps = l.covers(path) || (infinite && is_descendant(&l.path, path));
+            overlaps && !(l.shared && info.shared)
+        });
+        if conflict {
+            return None;
+        }
+        let timeout = timeout.min(MAX_LOCK_TIMEOUT);
+        let lock = Lock {
+            token: new_token(),
+            path: path.to_string(),
+            infinite,
+            shared: info.shared,
+            owner: info.owner,
+            timeout,
+            expires: Instant::now() + timeout,
+        };
+        locks.insert(lock.token.clone(), lock.clone());
+        Some(lock)
+    }
+
+    /// Refresh the lock covering `path` with one of the given tokens.
+    pub fn refresh(&self, path: &str, tokens: &[String], timeout: Duration) -> Option<Lock> {
+        let mut locks = self.active();
+        let token = tokens.iter().find(|t| matches!(locks.get(*t), Some(l) if l.covers(path)))?;
+        let lock = locks.get_mut(token)?;
+        lock.timeout = timeout.min(MAX_LOCK_TIMEOUT);
+        lock.expires = Instant::now() + lock.timeout;
+        Some(lock.clone())
+    }
+
+    /// Remove the lock with `token` covering `path`. Returns whether it existed.
+    pub fn unlock(&self, path: &str, token: &str) -> bool {
+        let mut locks = self.active();
+        match locks.get(token) {
+            Some(lock) if lock.covers(path) => locks.remove(token).is_some(),
+            _ => false,
+        }
+    }
+
+    /// Get the locks covering `path`.
+    pub fn locks_on(&self, path: &str) -> Vec<Lock> {
+        self.active().values().filter(|l| l.covers(path)).cloned().collect()
+    }
+
+    /// Check if the resource at `path` may be modified by a request
+    /// submitting `tokens`. With `recursive` set the locks of all
+    /// descendants of a directory are checked as well.
+    pub fn allows(&self, path: &str, tokens: &[String], recursive: bool) -> bool {
+        self.active()
+            .values()
+            .filter(|l| l.covers(path) || (recursive && is_descendant(&l.path, path)))
+            .all(|l| tokens.contains(&l.token))
+    }
+
+    /// Remove all locks of the resource at `path` and its descendants.
+    pub fn remove_tree(&self, path: &str) {
+        self.active()
+            .retain(|_, l| l.path != path && !is_descendant(&l.path, path));
+    }
+
+    /// Get the lock table with expired locks removed.
+    fn active(&self) -> std::sync::MutexGuard<'_, HashMap<String, Lock>> {
+        let mut locks = self.locks.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        locks.retain(|_, l| l.expires > now);
+        locks
+    }
+}
+
+/// Generate a new lock token.
+fn new_token() -> String {
+    use rand::RngCore;
+
+    let mut b = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut b);
+    b[6] = (b[6] & 0x0f) | 0x40;
+    b[8] = (b[8] & 0x3f) | 0x80;
+    let hex = hex::encode(b);
+    format!(
+        "opaquelocktoken:{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Get the lock tokens submitted in an If header.
+pub fn if_tokens(value: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut in_list = false;
+    let mut rest = value;
+    while let Some(c) = rest.chars().next() {
+        match c {
+            '(' => in_list = true,
+            ')' => in_list = false,
+            '<' => {
+                let end = rest.find('>').unwrap_or(rest.len());
+                if in_list {
+                    tokens.push(rest[1..end].to_string());
+                }
+                rest = &rest[end..];
+            }
+            '[' => rest = &rest[rest.find(']').unwrap_or(rest.len() - 1)..],
+            _ => (),
+        }
+        rest = rest.get(c.len_utf8()..).unwrap_or_default();
+    }
+    tokens
+}
+
+/// Parse a Timeout header. Infinite and invalid timeouts get the longest
+/// timeout.
+pub fn timeout(value: Option<&str>) -> Duration {
+    value
+        .and_then(|v| v.split(',').next())
+        .and_then(|v| v.trim().strip_prefix("Second-"))
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(MAX_LOCK_TIMEOUT)
+}
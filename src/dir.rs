@@ -1,5 +1,7 @@
 
+use crate::file::FileInfo;
 use rusqlite::Row;
+use serde::Serialize;
 use std::convert::TryFrom;
 
 /// Directory object which must be fetched from the database.
@@ -26,3 +28,83 @@ impl TryFrom<&Row<'_>> for Directory {
 }
 
 
+
+/// The type of a directory listing entry.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryType {
+    Dir,
+    File,
+}
+
+/// DirEntry describes a single file or directory in a directory listing,
+/// with the metadata needed to synchronize it.
+///
+/// Size, mode, modified date and ETag are only present for files.
+///
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize)]
+pub struct DirEntry {
+    #[serde(skip)]
+    pub id: usize,
+    #[serde(rename = "type")]
+    pub entry_type: EntryType,
+    pub name: String,
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+}
+
+impl DirEntry {
+    /// Create the entry of directory `dir` located in the directory at `parent`.
+    pub fn dir(parent: &str, dir: &Directory) -> Self {
+        DirEntry {
+            id: dir.id,
+            entry_type: EntryType::Dir,
+            name: dir.name.clone(),
+            path: join(parent, &dir.name),
+            size: None,
+            mode: None,
+            modified: None,
+            etag: None,
+        }
+    }
+}
+
+impl From<&FileInfo> for DirEntry {
+    fn from(file: &FileInfo) -> Self {
+        let name = file.path.rsplit('/').next().unwrap_or_default();
+        DirEntry {
+            id: file.id,
+            entry_type: EntryType::File,
+            name: name.to_string(),
+            path: file.path.clone(),
+            size: Some(file.size),
+            mode: Some(file.mode),
+            modified: Some(file.modified.clone()),
+            etag: Some(file.hash.clone()),
+        }
+    }
+}
+
+/// Deleted lists the paths of the directories and files removed by a
+/// recursive directory delete.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash, Serialize)]
+pub struct Deleted {
+    pub dirs: Vec<String>,
+    pub files: Vec<String>,
+}
+
+/// Join a directory path and an entry name.
+fn join(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
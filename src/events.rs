@@ -0,0 +1,288 @@
+//! Push notifications of changes to subscribed clients, as server-sent
+//! events.
+//!
+//! Write requests wake the broadcaster after they succeed. The broadcaster
+//! then reads the new entries of the change log and sends every subscriber
+//! the changes matching its filter, so notifications carry the same cursors
+//! as `GET /changes`.
+
+use crate::change::Change;
+use crate::database::{block, Pool};
+use crate::token::Token;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::web::{self, Bytes};
+use actix_web::Error;
+use futures::channel::mpsc;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use mhlog::err;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Number of events buffered for a subscriber. Subscribers falling further
+/// behind are disconnected, and may resume from their last event.
+pub const BUFFER: usize = 1000;
+
+/// Interval between heartbeats, which keep idle connections open and detect
+/// disconnected subscribers.
+const HEARTBEAT: Duration = Duration::from_secs(30);
+
+/// Filter deciding which changes a subscriber is notified of.
+///
+/// A subscriber without prefixes and boilerplates is notified of every
+/// change. Otherwise it is notified of changes of files and directories
+/// under one of the prefixes, and of changes of the boilerplates and the
+/// files they install.
+///
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Filter {
+    pub prefixes: Vec<String>,
+    pub boilerplates: Vec<String>,
+}
+
+impl Filter {
+    fn matches(&self, change: &Change, bp_files: &HashMap<String, HashSet<String>>) -> bool {
+        if self.prefixes.is_empty() && self.boilerplates.is_empty() {
+            return true;
+        }
+        if change.kind == "boilerplate" {
+            return self.boilerplates.contains(&change.path);
+        }
+        change.paths().any(|path| {
+            self.prefixes.iter().any(|prefix| under(path, prefix))
+                || self
+                    .boilerplates
+                    .iter()
+                    .filter_map(|name| bp_files.get(name))
+                    .any(|files| files.contains(path))
+        })
+    }
+}
+
+/// Check if `path` is `prefix` or below it. The empty prefix is the root.
+fn under(path: &str, prefix: &str) -> bool {
+    prefix.is_empty()
+        || path == prefix
+        || (path.starts_with(prefix) && path[prefix.len()..].starts_with('/'))
+}
+
+struct Subscriber {
+    tx: mpsc::Sender<Bytes>,
+    filter: Filter,
+    token: Option<Token>,
+    /// Cursor of the last change considered for the subscriber.
+    last: usize,
+}
+
+impl Subscriber {
+    /// Send the changes after the cursor of the subscriber. Return false if
+    /// the subscriber is gone or too far behind.
+    fn send(&mut self, changes: &[Change], bp_files: &HashMap<String, HashSet<String>>) -> bool {
+        let last = self.last;
+        for change in changes.iter().filter(|c| c.id > last) {
+            self.last = change.id;
+            if !self.filter.matches(change, bp_files) {
+                continue;
+            }
+            if matches!(&self.token, Some(token) if !change.visible_to(token)) {
+                continue;
+            }
+            if self.tx.try_send(event(change)).is_err() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Format a change as a server-sent event, identified by its cursor.
+fn event(change: &Change) -> Bytes {
+    let data = serde_json::to_string(change).unwrap_or_default();
+    Bytes::from(format!("id: {}\nevent: change\ndata: {}\n\n", change.id, data))
+}
+
+/// Broadcaster keeps the subscribers and notifies them of changes.
+pub struct Broadcaster {
+    subscribers: Mutex<Vec<Subscriber>>,
+    wake: mpsc::UnboundedSender<()>,
+}
+
+impl Broadcaster {
+    /// Create a broadcaster, and spawn the tasks notifying and keeping alive
+    /// its subscribers. Must be called from within the actix runtime.
+    pub fn start(pool: Pool) -> web::Data<Broadcaster> {
+        use actix_web::rt::{spawn, time::interval};
+        use futures::StreamExt;
+
+        let (wake, mut woken) = mpsc::unbounded();
+        let broadcaster = web::Data::new(Broadcaster {
+            subscribers: Mutex::new(Vec::new()),
+            wake,
+        });
+
+        let b = broadcaster.clone();
+        spawn(async move {
+            while woken.next().await.is_some() {
+                // Changes of all pending wakeups are read at once
+                while woken.try_recv().is_ok() {}
+                b.dispatch(&pool).await;
+            }
+        });
+
+        let b = broadcaster.clone();
+        spawn(async move {
+            let mut heartbeat = interval(HEARTBEAT);
+            loop {
+                heartbeat.tick().await;
+                b.heartbeat();
+            }
+        });
+
+        broadcaster
+    }
+
+    /// Wake the broadcaster to notify subscribers of new changes.
+    pub fn publish(&self) {
+        let _ = self.wake.unbounded_send(());
+    }
+
+    /// Subscribe to the changes after the cursor `since` matching `filter`
+    /// and visible to `token`, returning the stream of events.
+    pub fn subscribe(&self, filter: Filter, token: Option<Token>, since: usize) -> mpsc::Receiver<Bytes> {
+        let (tx, rx) = mpsc::channel(BUFFER);
+        self.subscribers.lock().unwrap().push(Subscriber {
+            tx,
+            filter,
+            token,
+            last: since,
+        });
+        // Replay the changes the subscriber missed
+        self.publish();
+        rx
+    }
+
+    /// Send the subscribers the changes after their cursors.
+    async fn dispatch(&self, pool: &Pool) {
+        use crate::database::boilerplate::{fetch, BoilerplateIdentifier::Name};
+        use crate::database::change::since;
+        use crate::CabinetError::NotFound;
+
+        loop {
+            let (cursor, names) = {
+                let subscribers = self.subscribers.lock().unwrap();
+                let cursor = match subscribers.iter().map(|s| s.last).min() {
+                    Some(cursor) => cursor,
+                    None => return,
+                };
+                let names: HashSet<String> = subscribers
+                    .iter()
+                    .flat_map(|s| s.filter.boilerplates.iter().cloned())
+                    .collect();
+                (cursor, names)
+            };
+
+            let res = block(pool, move |conn| {
+                let changes = since(conn, cursor, BUFFER)?;
+                let mut bp_files = HashMap::new();
+                for name in names {
+                    match fetch(conn, Name(&name)) {
+                        Ok(bp) => bp_files.insert(name, bp.files.into_values().collect()),
+                        Err(NotFound) => continue,
+                        Err(e) => return Err(e),
+                    };
+                }
+                Ok((changes, bp_files))
+            })
+            .await;
+            let (changes, bp_files) = match res {
+                Ok(res) => res,
+                Err(e) => {
+                    err!("Failed to read change log: {}", e);
+                    return;
+                }
+            };
+
+            self.subscribers
+                .lock()
+                .unwrap()
+                .retain_mut(|s| s.send(&changes, &bp_files));
+            if changes.len() < BUFFER {
+                return;
+            }
+        }
+    }
+
+    /// Send a comment to every subscriber, dropping those which are gone.
+    fn heartbeat(&self) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain_mut(|s| s.tx.try_send(Bytes::from_static(b": heartbeat\n\n")).is_ok());
+    }
+}
+
+/*******************************************************************************
+ *                                                                             *
+ * Middleware
+ *                                                                             *
+ *******************************************************************************/
+
+/// Middleware waking the broadcaster after every successful request which
+/// may have changed files, directories or boilerplates.
+///
+/// The handlers commit their changes before responding, so the changes are
+/// in the change log once the response is ready.
+///
+pub struct Publish;
+
+impl<S, B> Transform<S> for Publish
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = PublishMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(PublishMiddleware { service })
+    }
+}
+
+pub struct PublishMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for PublishMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let res = fut.await?;
+            if !res.request().method().is_safe() && res.status().is_success() {
+                if let Some(broadcaster) = res.request().app_data::<web::Data<Broadcaster>>() {
+                    broadcaster.publish();
+                }
+            }
+            Ok(res)
+        })
+    }
+}
@@ -1,5 +1,6 @@
 use getset::Getters;
 use rusqlite::Row;
+use serde::Serialize;
 use std::convert::TryFrom;
 
 /// NewFile contains file data without any database information: no database entry
@@ -31,30 +32,90 @@ pub struct File {
 }
 
 impl File {
-    #[inline]
-    pub fn content_type(&self) -> mime_guess::Mime {
-        mime_guess::from_path(&self.path).first_or_text_plain()
-    }
-
     pub fn content_hash(&self) -> String {
         use sha1::{Digest, Sha1};
 
         let mut hasher = Sha1::new();
         hasher.update(&self.content);
         let hash = hasher.finalize();
-        hex::encode(&hash)
+        hex::encode(hash)
+    }
+}
+
+/// FileInfo contains the data of a file entry stored in the database, except
+/// for its content.
+///
+/// FileInfo objects are used when the content itself is not needed, or is
+/// too large to keep in memory and is streamed instead.
+///
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct FileInfo {
+    pub id: usize,
+    pub path: String,
+    pub size: usize,
+    pub mode: u32,
+    pub modified: String,
+    /// SHA-1 hash of the content, as returned by `File::content_hash`.
+    pub hash: String,
+}
+
+impl FileInfo {
+    #[inline]
+    pub fn content_type(&self) -> mime_guess::Mime {
+        mime_guess::from_path(&self.path).first_or_text_plain()
+    }
+}
+
+impl From<&File> for FileInfo {
+    fn from(file: &File) -> Self {
+        FileInfo {
+            id: file.id,
+            path: file.path.clone(),
+            size: file.content.len(),
+            mode: file.mode,
+            modified: file.modified.clone(),
+            hash: file.content_hash(),
+        }
+    }
+}
+
+impl TryFrom<&Row<'_>> for FileInfo {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row<'_>) -> Result<FileInfo, Self::Error> {
+        Ok(FileInfo {
+            id: row.get("id")?,
+            path: row.get("path")?,
+            size: row.get("size")?,
+            mode: row.get("mode")?,
+            modified: row.get("modified")?,
+            hash: row.get("hash")?,
+        })
     }
 }
 
 impl TryFrom<&Row<'_>> for File {
     type Error = rusqlite::Error;
 
+    /// Build a file from a row with the stored content, its codec and the
+    /// dictionary it was compressed with, decompressing the content.
     fn try_from(row: &Row<'_>) -> Result<File, Self::Error> {
-        let content: Option<Vec<u8>> = row.get("content")?;
+        use crate::database::blob::{decode, Codec};
+        use rusqlite::types::Type;
+
+        let codec: Codec = row.get("codec")?;
+        let dictionary: Option<Vec<u8>> = row.get("dictionary")?;
+        let content = decode(codec, row.get("content")?, dictionary.as_deref()).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(
+                row.as_ref().column_index("content").unwrap_or_default(),
+                Type::Blob,
+                Box::new(e),
+            )
+        })?;
         Ok(File {
             id: row.get("id")?,
             path: row.get("path")?,
-            content: content.unwrap_or_default(),
+            content,
             mode: row.get("mode")?,
             modified: row.get("modified")?,
         })
@@ -66,3 +127,28 @@ impl std::fmt::Display for File {
         write!(f, "<File {} {}>", self.id, self.path)
     }
 }
+
+/// Revision describes a single stored version of a file, without its content.
+///
+/// Revision objects are used for listing the history of a file to clients.
+///
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize)]
+pub struct Revision {
+    pub revision: usize,
+    pub size: usize,
+    pub mode: u32,
+    pub modified: String,
+    pub etag: String,
+}
+
+impl Revision {
+    pub fn new(revision: usize, file: &FileInfo) -> Self {
+        Revision {
+            revision,
+            size: file.size,
+            mode: file.mode,
+            modified: file.modified.clone(),
+            etag: file.hash.clone(),
+        }
+    }
+}
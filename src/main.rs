@@ -16,43 +16,108 @@ extern crate quick_error;
 
 #[macro_use]
 mod common;
+mod archive;
+mod auth;
+mod batch;
+mod change;
+mod compress;
 mod boilerplate;
+mod config;
+mod dav;
 mod database;
 mod dir;
+mod events;
 mod file;
 mod request_handlers;
+mod tls;
+mod token;
 
+use actix_http::HttpService;
+use actix_service::map_config;
+use actix_web::dev::AppConfig;
 use actix_web::middleware::Logger;
-use actix_web::{App, HttpServer};
-use rusqlite::Connection;
-
-const DB_NAME: &str = "cabinet.sqlite";
+use actix_web::{web, App, HttpServer};
+use std::path::PathBuf;
+use std::sync::Arc;
 
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
-    use std::str::FromStr;
-    use anyhow::ensure;
-
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    use anyhow::Context;
 
     let m = clap_app!(myapp =>
         (version: "2.0")
         (author: "Magnus Aa. Hirth <magnus.hirth@gmail.com>")
         (about: "Cabinet file server.")
-        (@arg IP: "IP to bind server to.")
+        (@arg IP: "IP to bind server to. Overrides the configured bind addresses.")
         (@arg PORT: "Port to listen on.")
+        (@arg config: -c --config +takes_value "Configuration file. May also be given by CABINET_CONFIG.")
+        (@arg database: -d --database +takes_value "Path of the SQLite database.")
+        (@arg bind: -b --bind +takes_value +multiple number_of_values(1) "Address to listen on, as HOST:PORT. May be repeated.")
+        (@arg max_upload_size: --("max-upload-size") +takes_value "Largest accepted file upload, in bytes.")
+        (@arg log_level: --("log-level") +takes_value "Log level: off, error, warn, info, debug or trace.")
+        (@arg workers: --workers +takes_value "Number of worker threads.")
+        (@arg tls_certificate: --("tls-certificate") +takes_value "PEM file with the TLS certificate chain. Serves HTTPS only.")
+        (@arg tls_key: --("tls-key") +takes_value "PEM file with the private key of the TLS certificate.")
+        (@arg tls_client_ca: --("tls-client-ca") +takes_value "PEM file with the CA certificates to verify client certificates against.")
+        (@arg redirect: --redirect +takes_value +multiple number_of_values(1) "Address to redirect plain HTTP to HTTPS on, as HOST:PORT. May be repeated.")
         (@subcommand migrate =>
             (about: "Migrate from Cabinet v1 to v2.")
             (@arg ROOT: +required "Root of v1 file data."))
+        (@subcommand db =>
+            (about: "Manage the database.")
+            (@setting SubcommandRequiredElseHelp)
+            (@subcommand migrate =>
+                (about: "Apply pending schema migrations. Migrations are also applied at startup.")
+                (@arg dry_run: -n --("dry-run") "Only list the pending migrations."))
+            (@subcommand recompress =>
+                (about: "Compress stored file content which isn't compressed as new content would be.")
+                (@arg train_dictionary: -t --("train-dictionary") "First train a new dictionary for small text files on the stored ones.")))
+        (@subcommand token =>
+            (about: "Manage API tokens. Requests are only authenticated once a token exists.")
+            (@setting SubcommandRequiredElseHelp)
+            (@subcommand create =>
+                (about: "Create a new API token and print its secret.")
+                (@arg NAME: +required "Name of the token.")
+                (@arg read_only: -r --("read-only") "Only allow reading.")
+                (@arg PREFIX: -p --prefix +takes_value "Restrict the token to files and directories under PREFIX."))
+            (@subcommand list =>
+                (about: "List all API tokens."))
+            (@subcommand revoke =>
+                (about: "Revoke an API token.")
+                (@arg NAME: +required "Name of the token.")))
     )
     .get_matches();
 
+    //
+    // Load configuration
+    //
+    let config = load_config(&m)?;
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or(&config.log_level));
+
     //
     // Setup database
     //
+    let pool = database::create_pool(&config)
+        .with_context(|| format!("Failed to open database {:?}", config.database))?;
+
+    //
+    // Manage database and exit if requested
+    //
+    if let Some(m) = m.subcommand_matches("db") {
+        db(m, &pool)?;
+        return Ok(());
+    }
+
     {
-        let conn = get_db_conn();
-        database::create_tables(&conn).await?;
+        use database::migrations::migrate;
+        use mhlog::info;
+
+        let conn = pool.get()?;
+        let applied = migrate(&conn)
+            .with_context(|| format!("Failed to migrate database {:?}", config.database))?;
+        for m in applied {
+            info!("Applied migration {}: {}", m.version, m.description);
+        }
     }
 
     //
@@ -60,44 +125,201 @@ async fn main() -> anyhow::Result<()> {
     //
     if let Some(m) = m.subcommand_matches("migrate") {
         let root: &str = m.value_of("ROOT").unwrap();
-        migrate(root.as_ref()).await?;
+        migrate(root.as_ref(), &pool)?;
+        return Ok(());
+    }
+
+    //
+    // Manage tokens and exit if requested
+    //
+    if let Some(m) = m.subcommand_matches("token") {
+        token(m, &pool)?;
         return Ok(());
     }
 
-    ensure!(m.is_present("IP") && m.is_present("PORT"), "Missing parameters.\n{}", m.usage());
+    //
+    // Load TLS certificate
+    //
+    let tls = match (&config.tls_certificate, &config.tls_key) {
+        (Some(certificate), Some(key)) => {
+            let resolver = Arc::new(tls::CertResolver::load(certificate, key)?);
+            tls::reload_on_hangup(resolver.clone())?;
+            Some(resolver)
+        }
+        _ => None,
+    };
+    let client_ca = match &config.tls_client_ca {
+        Some(path) => Some(tls::load_client_ca(path)?),
+        None => None,
+    };
 
     //
     // Run server
     //
-    let ip: &str = m.value_of("IP").unwrap();
-    let port: u16 = u16::from_str(m.value_of("PORT").unwrap())?;
-    HttpServer::new(move || {
+    let bind = config.bind.clone();
+    let redirect = config.redirect.clone();
+    let workers = config.workers;
+    let locks = web::Data::new(dav::LockManager::default());
+    let broadcaster = events::Broadcaster::start(pool.clone());
+    let app = move || {
         App::new()
+            .data(config.clone())
+            .data(pool.clone())
+            .app_data(locks.clone())
+            .app_data(broadcaster.clone())
             .service(request_handlers::file::get)
             .service(request_handlers::file::head)
             .service(request_handlers::file::put)
+            .service(request_handlers::file::patch)
             .service(request_handlers::file::delete)
+            .service(request_handlers::file::revisions)
+            .service(request_handlers::file::restore)
+            .service(request_handlers::file::move_service())
+            .service(request_handlers::file::copy_service())
             .service(request_handlers::dir::get)
             .service(request_handlers::dir::put)
             .service(request_handlers::dir::delete)
+            .service(request_handlers::dir::move_service())
+            .service(request_handlers::dir::copy_service())
             .service(request_handlers::boilerplate::get_all_boilerplates)
             .service(request_handlers::boilerplate::get)
             .service(request_handlers::boilerplate::put)
             .service(request_handlers::boilerplate::delete)
+            .service(request_handlers::boilerplate::revisions)
+            .service(request_handlers::boilerplate::restore)
             .service(request_handlers::status::get)
-            .wrap(Logger::default())
-    })
-    .bind((ip, port))?
-    .run()
-    .await?;
+            .service(request_handlers::dav::service())
+            .service(request_handlers::archive::get_dir)
+            .service(request_handlers::archive::get_boilerplate)
+            .service(request_handlers::archive::put_dir)
+            .service(request_handlers::batch::post)
+            .service(request_handlers::change::get)
+            .service(request_handlers::events::get)
+            .wrap(events::Publish)
+            .wrap(auth::Authentication)
+            .wrap(compress::Compress)
+            .wrap(access_log())
+    };
+    // The server is built from HttpService rather than HttpServer, whose
+    // connection data only reaches the first request on each connection.
+    let mut server = actix_server::Server::build();
+    if let Some(workers) = workers {
+        server = server.workers(workers);
+    }
+    for addr in &bind {
+        let name = format!("cabinet-{}", addr);
+        let app = app.clone();
+        server = match &tls {
+            Some(resolver) => {
+                let tls_config = tls::server_config(resolver.clone(), client_ca.clone());
+                server.bind(name, addr, move || {
+                    HttpService::build()
+                        .on_connect(tls::on_connect)
+                        .finish(map_config(app(), |_| AppConfig::default()))
+                        .rustls(tls_config.clone())
+                })
+            }
+            None => server.bind(name, addr, move || {
+                HttpService::build()
+                    .finish(map_config(app(), |_| AppConfig::default()))
+                    .tcp()
+            }),
+        }
+        .with_context(|| format!("Failed to bind to {}", addr))?;
+    }
+    let mut servers = vec![server.run()];
+
+    //
+    // Run plain HTTP server redirecting to HTTPS
+    //
+    if !redirect.is_empty() {
+        use request_handlers::redirect::{redirect as to_https, HttpsPort};
+
+        let port = HttpsPort(config::bind_addrs(&bind[0])?[0].port());
+        let mut server = HttpServer::new(move || {
+            App::new()
+                .data(port)
+                .default_service(web::route().to(to_https))
+                .wrap(Logger::default())
+        })
+        .workers(1);
+        for addr in &redirect {
+            server = server
+                .bind(addr)
+                .with_context(|| format!("Failed to bind to {}", addr))?;
+        }
+        servers.push(server.run());
+    }
+    futures::future::try_join_all(servers).await?;
 
     Ok(())
 }
 
-pub fn get_db_conn() -> Connection {
-    let conn = Connection::open(DB_NAME).expect("Opening database");
-    conn.pragma_update(None, "foreign_keys", "ON").unwrap();
-    conn
+/// Access log middleware, logging the default format with the subject of
+/// the client certificate, or "-" for clients without one.
+fn access_log() -> Logger {
+    Logger::new(r#"%a %{CLIENT}xi "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+        .custom_request_replace("CLIENT", |req| match tls::client_certificate(req) {
+            Some(cert) => format!("\"{}\"", cert.subject),
+            None => "-".to_string(),
+        })
+}
+
+/// Build the server configuration from the configuration file, environment
+/// and command line.
+fn load_config(m: &clap::ArgMatches<'_>) -> anyhow::Result<config::Config> {
+    use anyhow::{bail, Context};
+    use std::str::FromStr;
+
+    let path = m
+        .value_of("config")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os(config::CONFIG_ENV).map(PathBuf::from));
+    let mut config = match path {
+        Some(path) => config::Config::from_file(&path)?,
+        None => config::Config::default(),
+    };
+    config.apply_env()?;
+
+    if let Some(val) = m.value_of("database") {
+        config.database = val.into();
+    }
+    if let Some(vals) = m.values_of("bind") {
+        config.bind = vals.map(String::from).collect();
+    }
+    match (m.value_of("IP"), m.value_of("PORT")) {
+        (Some(ip), Some(port)) if ip.contains(':') => config.bind = vec![format!("[{}]:{}", ip, port)],
+        (Some(ip), Some(port)) => config.bind = vec![format!("{}:{}", ip, port)],
+        (Some(_), None) => bail!("Missing PORT.\n{}", m.usage()),
+        _ => (),
+    }
+    if let Some(val) = m.value_of("max_upload_size") {
+        config.max_upload_size = usize::from_str(val)
+            .with_context(|| format!("Invalid --max-upload-size: {}", val))?;
+    }
+    if let Some(val) = m.value_of("log_level") {
+        config.log_level = val.to_string();
+    }
+    if let Some(val) = m.value_of("workers") {
+        let workers = usize::from_str(val)
+            .with_context(|| format!("Invalid --workers: {}", val))?;
+        config.workers = Some(workers);
+    }
+    if let Some(val) = m.value_of("tls_certificate") {
+        config.tls_certificate = Some(val.into());
+    }
+    if let Some(val) = m.value_of("tls_key") {
+        config.tls_key = Some(val.into());
+    }
+    if let Some(val) = m.value_of("tls_client_ca") {
+        config.tls_client_ca = Some(val.into());
+    }
+    if let Some(vals) = m.values_of("redirect") {
+        config.redirect = vals.map(String::from).collect();
+    }
+
+    config.validate()?;
+    Ok(config)
 }
 
 quick_error! {
@@ -114,19 +336,108 @@ quick_error! {
             from(err: anyhow::Error) -> (err.to_string())
             from(err: rusqlite::Error) -> (err.to_string())
             from(err: serde_json::error::Error) -> (err.to_string())
+            from(err: std::io::Error) -> (err.to_string())
         }
     }
 }
 
 pub type CabinetResult<T> = std::result::Result<T, CabinetError>;
 
+/*******************************************************************************
+ *                                                                             *
+ * Tokens
+ *                                                                             *
+ *******************************************************************************/
+
+fn token(m: &clap::ArgMatches<'_>, pool: &database::Pool) -> anyhow::Result<()> {
+    use crate::database::token::{all, create, delete};
+    use crate::token::{generate, hash, NewToken, Scope};
+    use actix_web::http::header::HttpDate;
+    use std::time::SystemTime;
+
+    let conn = pool.get()?;
+    match m.subcommand() {
+        ("create", Some(m)) => {
+            let secret = generate();
+            let new_token = NewToken {
+                name: m.value_of("NAME").unwrap().to_string(),
+                hash: hash(&secret),
+                scope: if m.is_present("read_only") { Scope::Read } else { Scope::Write },
+                prefix: m.value_of("PREFIX").map(|p| p.trim_matches('/').to_string()),
+                created: HttpDate::from(SystemTime::now()).to_string(),
+            };
+            create(&conn, &new_token)?;
+            println!("{}", secret);
+        }
+        ("list", Some(_)) => {
+            for t in all(&conn)? {
+                let prefix = t.prefix.as_deref().unwrap_or("-");
+                println!("{}\t{}\t{}\t{}", t.name, t.scope, prefix, t.created);
+            }
+        }
+        ("revoke", Some(m)) => {
+            let name = m.value_of("NAME").unwrap();
+            match delete(&conn, name) {
+                Err(CabinetError::NotFound) => anyhow::bail!("No such token: {}", name),
+                res => res?,
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/*******************************************************************************
+ *                                                                             *
+ * Database
+ *                                                                             *
+ *******************************************************************************/
+
+fn db(m: &clap::ArgMatches<'_>, pool: &database::Pool) -> anyhow::Result<()> {
+    use crate::database::blob::{recompress, train_dictionary};
+    use crate::database::migrations::{migrate, pending, version};
+
+    let conn = pool.get()?;
+    match m.subcommand() {
+        ("migrate", Some(m)) => {
+            println!("Schema version: {}", version(&conn)?);
+            let migrations = if m.is_present("dry_run") {
+                pending(&conn)?
+            } else {
+                migrate(&conn)?
+            };
+            for m in migrations {
+                println!("{}\t{}", m.version, m.description);
+            }
+        }
+        ("recompress", Some(m)) => {
+            if !pending(&conn)?.is_empty() {
+                anyhow::bail!("The database has pending migrations, apply them with `cabinet db migrate` first");
+            }
+            if m.is_present("train_dictionary") {
+                match train_dictionary(&conn)? {
+                    Some(id) => println!("Trained dictionary {}", id),
+                    None => println!("Too few small text files to train a dictionary"),
+                }
+            }
+            let totals = recompress(&conn)?;
+            println!(
+                "Recompressed {} blobs from {} to {} bytes",
+                totals.blobs, totals.before, totals.after
+            );
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
 /*******************************************************************************
  *                                                                             *
  * Migrate
  *                                                                             *
  *******************************************************************************/
 
-async fn migrate(root: &std::path::Path) -> anyhow::Result<()> {
+fn migrate(root: &std::path::Path, pool: &database::Pool) -> anyhow::Result<()> {
     use mhlog::{info, warn};
     use std::path::{Path, PathBuf};
     use crate::database::file::{create, exists};
@@ -139,8 +450,8 @@ async fn migrate(root: &std::path::Path) -> anyhow::Result<()> {
     use crate::database::boilerplate::BoilerplateIdentifier::Name;
     use crate::boilerplate::NewBoilerplate;
 
-    info!("Migrating data from {:?} to {}", root, DB_NAME);
-    let mut conn = get_db_conn();
+    info!("Migrating data from {:?}", root);
+    let mut conn = pool.get()?;
     let date = HttpDate::from(SystemTime::now());
 
     //
@@ -170,7 +481,7 @@ async fn migrate(root: &std::path::Path) -> anyhow::Result<()> {
     //
     for f in files {
         let path = f.strip_prefix(&file_dir)?;
-        if exists(&conn, PathId(path)).await? {
+        if exists(&conn, PathId(path))? {
             warn!("File already exists: {:?}", path);
             continue
         }
@@ -181,7 +492,7 @@ async fn migrate(root: &std::path::Path) -> anyhow::Result<()> {
             mode: 0o644,
             modified: date.to_string(),
         };
-        create(&conn, &new_file).await?;
+        create(&conn, &new_file)?;
     }
 
     //
@@ -196,7 +507,7 @@ async fn migrate(root: &std::path::Path) -> anyhow::Result<()> {
     //
     for bp in bps {
         let name: String = bp.strip_prefix(&bp_dir)?.to_string_lossy().into();
-        if bp_exists(&conn, Name(&name)).await? {
+        if bp_exists(&conn, Name(&name))? {
             warn!("Boilerplate already exists: {:?}", &name);
             continue
         }
@@ -205,7 +516,7 @@ async fn migrate(root: &std::path::Path) -> anyhow::Result<()> {
             script: None,
             files: serde_json::from_slice(&read(&bp)?)?,
         };
-        bp_create(&mut conn, &new_bp).await?;
+        bp_create(&mut conn, &new_bp)?;
     }
 
     Ok(())
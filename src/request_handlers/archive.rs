@@ -0,0 +1,252 @@
+use crate::archive::{ArchiveEntry, Format};
+use crate::config::Config;
+use crate::database::{block, Pool};
+use crate::token::{Resource, Scope, Token};
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use futures::Stream;
+use mhlog::err;
+use serde::Deserialize;
+
+/// Query parameters accepted when downloading archives.
+#[derive(Debug, Deserialize)]
+pub struct ArchiveQuery {
+    /// Archive format: `tar.gz` (default) or `zip`.
+    #[serde(default)]
+    format: Format,
+}
+
+/// Download a directory and all its content as an archive.
+///
+/// Entries are named relative to the directory.
+///
+#[actix_web::get("/archives/dirs/{dir:.*}")]
+pub async fn get_dir(
+    web::Path(dir_path): web::Path<String>,
+    web::Query(query): web::Query<ArchiveQuery>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse> {
+    use crate::database::dir::{exists, tree, DirIdentifier};
+    use crate::CabinetError::NotFound;
+
+    let path = dir_path.trim_matches('/').to_string();
+    let res = block(&pool, move |conn| {
+        if !path.is_empty() && !exists(conn, DirIdentifier::Path(path.as_ref()))? {
+            return Err(NotFound);
+        }
+        let entries = tree(conn, path.as_ref(), None)?;
+        Ok(entries.iter().map(|e| ArchiveEntry::relative_to(&path, e)).collect())
+    })
+    .await;
+    let entries = match res {
+        Ok(entries) => entries,
+        Err(NotFound) => return Ok(not_found!("{}", &dir_path)),
+        Err(e) => {
+            err!("Failed to list directory for archive: {}", e);
+            return Ok(internal_server_error!());
+        }
+    };
+
+    let name = dir_path.trim_matches('/').rsplit('/').next().unwrap_or_default();
+    let name = if name.is_empty() { "cabinet" } else { name };
+    Ok(archive_response(pool.get_ref().clone(), entries, query.format, name))
+}
+
+/// Download the files of a boilerplate as an archive.
+///
+/// Entries are named by the client-side location of the files, relative to
+/// the home directory or file system root, and have the mode of the files.
+///
+#[actix_web::get("/archives/boilerplates/{boilerplate:.+}")]
+pub async fn get_boilerplate(
+    web::Path(bp_name): web::Path<String>,
+    web::Query(query): web::Query<ArchiveQuery>,
+    pool: web::Data<Pool>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    use crate::archive::archive_name;
+    use crate::database::boilerplate::{fetch, BoilerplateIdentifier::Name};
+    use crate::database::file::{fetch_info, FileIdentifier::Path};
+    use crate::dir::DirEntry;
+    use crate::CabinetError::{BadRequest, NotFound};
+
+    let name = bp_name.clone();
+    let res = block(&pool, move |conn| {
+        let bp = fetch(conn, Name(&name))?;
+        let mut files: Vec<_> = bp.files.into_iter().collect();
+        files.sort();
+        let mut entries = Vec::new();
+        for (location, path) in files {
+            let file = fetch_info(conn, Path(path.as_ref()))?;
+            let mut entry = ArchiveEntry::relative_to("", &DirEntry::from(&file));
+            entry.name = archive_name(&location)?;
+            entries.push((file.path, entry));
+        }
+        Ok(entries)
+    })
+    .await;
+    let entries = match res {
+        Ok(entries) => entries,
+        Err(NotFound) => return Ok(not_found!("{}", &bp_name)),
+        Err(BadRequest(txt)) => return Ok(bad_request!("{}", txt)),
+        Err(e) => {
+            err!("Failed to fetch boilerplate files for archive: {}", e);
+            return Ok(internal_server_error!());
+        }
+    };
+
+    // Tokens limited to a path prefix may only download the files they can read
+    if let Some(token) = req.extensions().get::<Token>() {
+        for (path, _) in &entries {
+            if !token.allows(Scope::Read, Resource::Path(path.as_ref())) {
+                return Ok(forbidden!("token '{}' does not grant access to {}", token.name, path));
+            }
+        }
+    }
+
+    let entries = entries.into_iter().map(|(_, entry)| entry).collect();
+    Ok(archive_response(pool.get_ref().clone(), entries, query.format, &bp_name))
+}
+
+/// Import a tar, tar.gz or zip archive into a directory, creating or
+/// updating every file and directory of the archive in one transaction.
+#[actix_web::put("/archives/dirs/{dir:.*}")]
+pub async fn put_dir(
+    web::Path(dir_path): web::Path<String>,
+    payload: web::Payload,
+    config: web::Data<Config>,
+    pool: web::Data<Pool>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    use crate::archive::import;
+    use crate::compress::Encoding;
+    use crate::request_handlers::file::spool_payload;
+    use crate::CabinetError::{BadRequest, PailoadTooLarge};
+    use actix_web::http::header::CONTENT_LENGTH;
+
+    if !super::unlocked(&req, &dir_path, true) {
+        return Ok(locked!());
+    }
+
+    //
+    // Get payload
+    //
+    let encoding = match Encoding::of_request(req.headers()) {
+        Ok(encoding) => encoding,
+        Err(txt) => return Ok(unsupported_media_type!("{}", txt)),
+    };
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| val.parse::<usize>().ok());
+    if matches!(content_length, Some(n) if n > config.max_upload_size) {
+        return Ok(payload_too_large!());
+    }
+    let (body, _) = match spool_payload(payload, encoding, config.max_upload_size).await? {
+        Some(res) => res,
+        None => return Ok(payload_too_large!()),
+    };
+
+    //
+    // Import archive entries
+    //
+    let max_size = config.max_upload_size;
+    match block(&pool, move |conn| import(conn, &dir_path, body, max_size)).await {
+        Ok(imported) => Ok(HttpResponse::Ok().json(&imported)),
+        Err(BadRequest(txt)) => Ok(bad_request!("{}", txt)),
+        Err(PailoadTooLarge) => Ok(payload_too_large!()),
+        Err(e) => {
+            err!("Failed to import archive: {}", e);
+            Ok(internal_server_error!())
+        }
+    }
+}
+
+fn archive_response(pool: Pool, entries: Vec<ArchiveEntry>, format: Format, name: &str) -> HttpResponse {
+    let filename = format!("{}.{}", name.replace('"', ""), format.extension());
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .header("Content-Disposition", format!("attachment; filename=\"{}\"", filename))
+        .streaming(stream_archive(pool, entries, format))
+}
+
+/// Stream an archive of `entries`.
+///
+/// The archive is written by a blocking task and passed on to the returned
+/// stream in chunks. Zip archives are written to a temporary file first, as
+/// writing them requires seeking.
+///
+fn stream_archive(pool: Pool, entries: Vec<ArchiveEntry>, format: Format) -> impl Stream<Item = Result<Bytes>> {
+    use crate::archive::{write_tar_gz, write_zip};
+    use crate::CabinetResult;
+    use async_std::task::{block_on, spawn_blocking};
+    use futures::channel::mpsc;
+    use futures::{SinkExt, StreamExt};
+    use std::io::{Error, Seek, SeekFrom, Write};
+
+    let (tx, rx) = mpsc::channel(4);
+    spawn_blocking(move || {
+        let mut tx = tx;
+        let res: CabinetResult<()> = (|| {
+            let conn = pool.get().map_err(|e| Error::other(e.to_string()))?;
+            let mut writer = ChunkWriter::new(tx.clone());
+            match format {
+                Format::TarGz => {
+                    writer = write_tar_gz(&conn, &entries, writer)?;
+                }
+                Format::Zip => {
+                    let mut file = write_zip(&conn, &entries, tempfile::tempfile()?)?;
+                    file.seek(SeekFrom::Start(0))?;
+                    std::io::copy(&mut file, &mut writer)?;
+                }
+            }
+            writer.flush()?;
+            Ok(())
+        })();
+        if let Err(e) = res {
+            err!("Failed to write archive: {}", e);
+            let _ = block_on(tx.send(Err(Error::other(e.to_string()))));
+        }
+    });
+    rx.map(|chunk| chunk.map_err(Into::into))
+}
+
+/// Writer passing chunks of data to a channel.
+struct ChunkWriter {
+    tx: futures::channel::mpsc::Sender<std::io::Result<Bytes>>,
+    buf: Vec<u8>,
+}
+
+impl ChunkWriter {
+    fn new(tx: futures::channel::mpsc::Sender<std::io::Result<Bytes>>) -> Self {
+        use crate::database::blob::CHUNK_SIZE;
+
+        ChunkWriter { tx, buf: Vec::with_capacity(CHUNK_SIZE) }
+    }
+}
+
+impl std::io::Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        use crate::database::blob::CHUNK_SIZE;
+
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        use async_std::task::block_on;
+        use futures::SinkExt;
+        use std::io::{Error, ErrorKind};
+
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::take(&mut self.buf));
+        // Sending fails when the client has gone away
+        block_on(self.tx.send(Ok(chunk))).map_err(|_| Error::new(ErrorKind::BrokenPipe, "client gone"))
+    }
+}
@@ -0,0 +1,79 @@
+use crate::batch::Operation;
+use crate::config::Config;
+use crate::database::{block, Pool};
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use mhlog::err;
+
+/// Apply a list of file, directory and boilerplate operations all-or-nothing,
+/// in one transaction.
+///
+/// The body is a JSON array of operations. The response lists the change
+/// made by each operation, or describes the first operation which failed,
+/// in which case nothing is changed.
+///
+#[actix_web::post("/batch")]
+pub async fn post(
+    mut payload: web::Payload,
+    config: web::Data<Config>,
+    pool: web::Data<Pool>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    use super::unlocked;
+    use crate::database::batch::apply;
+    use crate::CabinetError::{BadRequest, NotFound, PreconditionFailed};
+    use actix_web::http::header::HttpDate;
+    use async_std::stream::StreamExt;
+    use std::time::SystemTime;
+
+    //
+    // Get payload
+    //
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if (body.len() + chunk.len()) > config.max_upload_size {
+            return Ok(payload_too_large!());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let ops: Vec<Operation> = match serde_json::from_slice(&body) {
+        Ok(ops) => ops,
+        Err(e) => return Ok(bad_request!("{}", e)),
+    };
+
+    //
+    // Check the WebDAV locks of the files and directories
+    //
+    for (index, op) in ops.iter().enumerate() {
+        if matches!(op, Operation::PutBoilerplate { .. }) {
+            continue;
+        }
+        if !unlocked(&req, op.target(), false) {
+            return Ok(locked!("operation {} ({})", index, op.target()));
+        }
+    }
+
+    //
+    // Apply operations
+    //
+    let date = HttpDate::from(SystemTime::now()).to_string();
+    let targets: Vec<String> = ops.iter().map(|op| op.target().to_string()).collect();
+    let failure = match block(&pool, move |conn| apply(conn, &ops, &date)).await {
+        Ok(Ok(outcomes)) => return Ok(HttpResponse::Ok().json(&outcomes)),
+        Ok(Err(failure)) => failure,
+        Err(e) => {
+            err!("Failed to apply batch: {}", e);
+            return Ok(internal_server_error!());
+        }
+    };
+    let target = &targets[failure.index];
+    match failure.error {
+        BadRequest(txt) => Ok(bad_request!("operation {} ({}): {}", failure.index, target, txt)),
+        NotFound => Ok(not_found!("operation {} ({})", failure.index, target)),
+        PreconditionFailed => Ok(precondition_failed!("operation {} ({})", failure.index, target)),
+        e => {
+            err!("Failed to apply batch operation {}: {}", failure.index, e);
+            Ok(internal_server_error!())
+        }
+    }
+}
@@ -1,16 +1,37 @@
-use crate::get_db_conn;
 use crate::boilerplate::{Boilerplate, NewBoilerplate};
+use crate::database::{block, Pool};
+use crate::CabinetResult;
+use super::RestoreQuery;
 use actix_web::{web, HttpRequest, HttpResponse, Result};
 use mhlog::err;
 use std::str::FromStr;
 
 const MAX_SIZE: usize = 262_144;
 
+/// Check the request conditions against a boilerplate fetched by the
+/// transaction which modifies it.
+fn check_modification(headers: &actix_web::http::HeaderMap, bp: &Boilerplate) -> CabinetResult<()> {
+    use actix_web::http::header::HttpDate;
+    use crate::CabinetError::{BadRequest, PreconditionFailed};
+
+    let modified = HttpDate::from_str(&bp.modified)?;
+    if let Some(val) = headers.get("If-Unmodified-Since") {
+        let date: HttpDate = val
+            .to_str()
+            .ok()
+            .and_then(|val| val.parse().ok())
+            .ok_or_else(|| BadRequest("invalid If-Unmodified-Since header".to_string()))?;
+        if modified > date {
+            return Err(PreconditionFailed);
+        }
+    }
+    Ok(())
+}
+
 #[actix_web::get("/boilerplates")]
-pub async fn get_all_boilerplates() -> Result<HttpResponse> {
+pub async fn get_all_boilerplates(pool: web::Data<Pool>) -> Result<HttpResponse> {
     use crate::database::boilerplate::all_names;
-    let conn = get_db_conn();
-    let names = match all_names(&conn).await {
+    let names = match block(&pool, |conn| all_names(conn)).await {
         Ok(names) => names,
         Err(e) => {
             err!("Failed to get all boilerplate names: {}", e);
@@ -24,6 +45,7 @@ pub async fn get_all_boilerplates() -> Result<HttpResponse> {
 #[actix_web::get("/boilerplates/{boilerplate:.+}")]
 pub async fn get(
     web::Path(bp_name): web::Path<String>,
+    pool: web::Data<Pool>,
     req: HttpRequest,
 ) -> Result<HttpResponse> {
     use actix_web::http::header::HttpDate;
@@ -36,8 +58,8 @@ pub async fn get(
     //
     // Prepare response
     //
-    let conn = get_db_conn();
-    let bp = match fetch(&conn, Name(&bp_name)).await {
+    let name = bp_name.clone();
+    let bp = match block(&pool, move |conn| fetch(conn, Name(&name))).await {
         Ok(bp) => bp,
         Err(NotFound) => return Ok(not_found!("{}", &bp_name)),
         Err(e) => {
@@ -60,25 +82,31 @@ pub async fn get(
         }
     }
 
-    Ok(resp.json(&bp.files))
+    Ok(resp.json(&bp))
 }
 
 #[actix_web::put("/boilerplates/{boilerplate:.*}")]
 pub async fn put(
     web::Path(boilerplate): web::Path<String>,
     mut payload: web::Payload,
+    pool: web::Data<Pool>,
     req: HttpRequest,
 ) -> Result<HttpResponse> {
     use actix_web::http::header::HttpDate;
-    use actix_web::http::HeaderMap;
     use async_std::stream::StreamExt;
-    use crate::database::boilerplate::{create, fetch, update};
+    use crate::compress::{decode, Encoding};
+    use crate::database::boilerplate::{fetch, upsert};
     use crate::database::boilerplate::BoilerplateIdentifier::Name;
-    use crate::CabinetError::{NotFound, BadRequest};
+    use crate::CabinetError::{BadRequest, NotFound, PreconditionFailed};
+    use std::time::SystemTime;
 
     //
-    // Create new boilerplate object
+    // Get payload
     //
+    let encoding = match Encoding::of_request(req.headers()) {
+        Ok(encoding) => encoding,
+        Err(txt) => return Ok(unsupported_media_type!("{}", txt)),
+    };
     let mut body = web::BytesMut::new();
     while let Some(chunk) = payload.next().await {
         let chunk = chunk?;
@@ -87,111 +115,173 @@ pub async fn put(
         }
         body.extend_from_slice(&chunk);
     }
-    let bp = match NewBoilerplate::from_json(&boilerplate, None, &body) {
-        Ok(bp) => bp,
-        Err(err) => return Ok(bad_request!("{}", err)),
+    let body = match decode(encoding, &body, MAX_SIZE) {
+        Ok(body) => body,
+        Err(e) if e.kind() == std::io::ErrorKind::FileTooLarge => return Ok(payload_too_large!()),
+        Err(e) => return Ok(bad_request!("invalid encoded content: {}", e)),
     };
 
     //
-    // Check if the boilerplate already exists
+    // Create or update the boilerplate entry, if the request conditions hold
     //
-    let mut conn = get_db_conn();
-    let already_exists: bool;
-    let bp_entry: Option<Boilerplate>;
-    match fetch(&conn, Name(&boilerplate)).await {
-        Ok(bp) => {
-            already_exists = true;
-            bp_entry = Some(bp);
+    let date = HttpDate::from(SystemTime::now()).to_string();
+    let headers = req.headers().clone();
+    let res = block(&pool, move |conn| {
+        use rusqlite::TransactionBehavior::Immediate;
+
+        let tx = conn.transaction_with_behavior(Immediate)?;
+        let bp_entry = match fetch(&tx, Name(&boilerplate)) {
+            Ok(bp) => Some(bp),
+            Err(NotFound) => None,
+            Err(e) => return Err(e),
+        };
+        if let Some(bp_entry) = &bp_entry {
+            check_modification(&headers, bp_entry)?;
         }
-        Err(NotFound) => {
-            already_exists = false;
-            bp_entry = None;
+
+        // A bare files map keeps the existing script
+        let script = bp_entry.and_then(|bp| bp.script);
+        let bp = NewBoilerplate::from_json(&boilerplate, script, &body)
+            .map_err(|e| BadRequest(e.to_string()))?;
+        let already_exists = upsert(&tx, &bp, &date)?;
+        tx.commit()?;
+        Ok(already_exists)
+    });
+    match res.await {
+        Ok(true) => Ok(HttpResponse::NoContent().finish()),
+        Ok(false) => Ok(HttpResponse::Created().finish()),
+        Err(BadRequest(txt)) => Ok(bad_request!("{}", txt)),
+        Err(PreconditionFailed) => Ok(precondition_failed!()),
+        Err(e) => {
+            err!("Failed to create/update boilerplate: {}", e);
+            Ok(internal_server_error!())
         }
+    }
+}
+
+#[actix_web::get("/revisions/boilerplates/{boilerplate:.+}")]
+pub async fn revisions(
+    web::Path(bp_name): web::Path<String>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse> {
+    use crate::database::boilerplate::revisions;
+    use crate::database::boilerplate::BoilerplateIdentifier::Name;
+    use crate::CabinetError::NotFound;
+
+    let name = bp_name.clone();
+    match block(&pool, move |conn| revisions(conn, Name(&name))).await {
+        Ok(revs) => Ok(HttpResponse::Ok().json(&revs)),
+        Err(NotFound) => Ok(not_found!("{}", &bp_name)),
         Err(e) => {
-            err!("Failed to check if boilerplate exists: {}", e);
-            return Ok(internal_server_error!());
+            err!("Failed to get boilerplate revisions: {}", e);
+            Ok(internal_server_error!())
         }
-    };
+    }
+}
+
+#[actix_web::post("/boilerplates/{boilerplate:.+}")]
+pub async fn restore(
+    web::Path(bp_name): web::Path<String>,
+    web::Query(query): web::Query<RestoreQuery>,
+    pool: web::Data<Pool>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    use actix_web::http::header::HttpDate;
+    use crate::database::boilerplate::{fetch, restore, revision_at};
+    use crate::database::boilerplate::BoilerplateIdentifier::{Id, Name};
+    use crate::CabinetError::{BadRequest, NotFound, PreconditionFailed};
 
     //
-    // Handle request conditions
+    // Parse the revision to restore
     //
-    let headers: &HeaderMap = req.headers();
-    if let Some(bp_entry) = &bp_entry {
-        let modified = HttpDate::from_str(&bp_entry.modified)?;
-        if let Some(val) = headers.get("If-Unmodified-Since") {
-            let date: HttpDate = val.to_str().unwrap().parse()?;
-            if modified > date {
-                return Ok(precondition_failed!());
-            }
-        };
-    }
+    let (rev, date) = match (query.rev, query.date) {
+        (Some(rev), None) => (Some(rev), None),
+        (None, Some(date)) => match date.parse::<HttpDate>() {
+            Ok(date) => (None, Some(date)),
+            Err(_) => return Ok(bad_request!("invalid date: {}", date)),
+        },
+        _ => return Ok(bad_request!("expected either rev or date")),
+    };
+    let missing = match (rev, date) {
+        (Some(rev), _) => format!("{} revision {}", &bp_name, rev),
+        (None, Some(date)) => format!("{} at {}", &bp_name, date),
+        (None, None) => bp_name.clone(),
+    };
 
     //
-    // Create or update the boilerplate entry
+    // Restore the boilerplate as a new modification, if the request
+    // conditions hold
     //
-    let res = if let Some(mut bp_entry) = bp_entry {
-        bp_entry.script = bp.script;
-        bp_entry.files = bp.files;
-        update(&mut conn, &bp_entry).await
-    } else {
-        create(&mut conn, &bp).await
-    };
-    match res {
-        Err(BadRequest(txt)) => return Ok(bad_request!("{}", txt)),
+    let headers = req.headers().clone();
+    let name = bp_name.clone();
+    let res = block(&pool, move |conn| {
+        use rusqlite::TransactionBehavior::Immediate;
+
+        let tx = conn.transaction_with_behavior(Immediate)?;
+        let bp = fetch(&tx, Name(&name))?;
+        check_modification(&headers, &bp)?;
+        let revision = match date {
+            Some(date) => revision_at(&tx, bp.id, &date)?,
+            None => rev,
+        };
+        let restored = match revision {
+            Some(revision) => restore(&tx, Id(bp.id), revision, date.as_ref()),
+            None => Err(NotFound),
+        };
+        match restored {
+            Ok(()) => (),
+            // The revision doesn't exist, unlike the boilerplate itself
+            Err(NotFound) => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        tx.commit()?;
+        Ok(true)
+    });
+    match res.await {
+        Ok(true) => Ok(HttpResponse::NoContent().finish()),
+        Ok(false) => Ok(not_found!("{}", missing)),
+        Err(NotFound) => Ok(not_found!("{}", &bp_name)),
+        Err(BadRequest(txt)) => Ok(bad_request!("{}", txt)),
+        Err(PreconditionFailed) => Ok(precondition_failed!()),
         Err(e) => {
-            err!("Failed to create/update boilerplate: {}", e);
-            return Ok(internal_server_error!())
+            err!("Failed to restore boilerplate: {}", e);
+            Ok(internal_server_error!())
         }
-        _ => (),
-    }
-
-    match already_exists {
-        true => Ok(HttpResponse::NoContent().finish()),
-        false => Ok(HttpResponse::Created().finish()),
     }
 }
 
 #[actix_web::delete("/boilerplates/{boilerplate:.*}")]
 pub async fn delete(
     web::Path(bp_name): web::Path<String>,
+    pool: web::Data<Pool>,
     req: HttpRequest,
 ) -> Result<HttpResponse> {
-    use actix_web::http::header::HttpDate;
-    use actix_web::http::HeaderMap;
     use crate::database::boilerplate::{fetch, delete};
     use crate::database::boilerplate::BoilerplateIdentifier::{Id, Name};
-    use crate::CabinetError::NotFound;
-
-    //
-    // Fetch requested boilerplate
-    //
-    let conn = get_db_conn();
-    let bp: Boilerplate = match fetch(&conn, Name(&bp_name)).await {
-        Ok(bp) => bp,
-        Err(NotFound) => return Ok(not_found!("{}", &bp_name)),
-        Err(e) => {
-            err!("Failed to fetch boilerplate: {}", e);
-            return Ok(internal_server_error!());
-        }
-    };
+    use crate::CabinetError::{BadRequest, NotFound, PreconditionFailed};
 
     //
-    // Handle request conditions
+    // Delete the boilerplate, if the request conditions hold
     //
-    let headers: &HeaderMap = req.headers();
-    let modified = HttpDate::from_str(&bp.modified)?;
-    if let Some(val) = headers.get("If-Unmodified-Since") {
-        let date: HttpDate = val.to_str().unwrap().parse()?;
-        if modified > date {
-            return Ok(precondition_failed!());
-        }
-    };
+    let headers = req.headers().clone();
+    let name = bp_name.clone();
+    let res = block(&pool, move |conn| {
+        use rusqlite::TransactionBehavior::Immediate;
 
-    match delete(&conn, Id(bp.id)).await {
-        Ok(_) => Ok(HttpResponse::NoContent().finish()),
+        let tx = conn.transaction_with_behavior(Immediate)?;
+        let bp = fetch(&tx, Name(&name))?;
+        check_modification(&headers, &bp)?;
+        delete(&tx, Id(bp.id))?;
+        tx.commit()?;
+        Ok(())
+    });
+    match res.await {
+        Ok(()) => Ok(HttpResponse::NoContent().finish()),
+        Err(NotFound) => Ok(not_found!("{}", &bp_name)),
+        Err(BadRequest(txt)) => Ok(bad_request!("{}", txt)),
+        Err(PreconditionFailed) => Ok(precondition_failed!()),
         Err(e) => {
-            err!("{}", e);
+            err!("Failed to delete boilerplate: {}", e);
             Ok(internal_server_error!())
         }
     }
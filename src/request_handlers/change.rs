@@ -0,0 +1,55 @@
+use crate::change::Changes;
+use crate::database::{block, Pool};
+use crate::token::Token;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use mhlog::err;
+use serde::Deserialize;
+
+/// Largest number of changes returned by one request.
+const MAX_LIMIT: usize = 1000;
+
+/// Query parameters accepted when reading the change feed.
+#[derive(Debug, Deserialize)]
+pub struct ChangesQuery {
+    /// Cursor of the last change seen by the client. Defaults to 0, the
+    /// beginning of the log.
+    #[serde(default)]
+    since: usize,
+    /// Largest number of changes to return.
+    limit: Option<usize>,
+}
+
+/// Get the changes after a cursor, in order.
+///
+/// The response carries the cursor to use for the next request. Clients
+/// are up to date once a request returns no changes. Tokens limited to a
+/// path prefix only see changes of files and directories under it.
+///
+#[actix_web::get("/changes")]
+pub async fn get(
+    web::Query(query): web::Query<ChangesQuery>,
+    pool: web::Data<Pool>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    use crate::database::change::{latest, since};
+
+    let limit = query.limit.unwrap_or(MAX_LIMIT).clamp(1, MAX_LIMIT);
+    let cursor = query.since;
+    let res = block(&pool, move |conn| Ok((since(conn, cursor, limit)?, latest(conn)?))).await;
+    let (mut changes, latest) = match res {
+        Ok(res) => res,
+        Err(e) => {
+            err!("Failed to read change log: {}", e);
+            return Ok(internal_server_error!());
+        }
+    };
+
+    // A cursor past the end of the log restarts from the end
+    let cursor = changes.last().map_or(cursor.min(latest), |c| c.id);
+
+    if let Some(token) = req.extensions().get::<Token>() {
+        changes.retain(|c| c.visible_to(token));
+    }
+
+    Ok(HttpResponse::Ok().json(&Changes { cursor, changes }))
+}
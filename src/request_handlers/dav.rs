@@ -0,0 +1,412 @@
+//! WebDAV interface to the file tree, mounted at `/dav`.
+
+use crate::config::Config;
+use crate::dav::{if_tokens, LockManager, MultiStatus, PropFind};
+use crate::database::{block, Pool};
+use crate::dir::{DirEntry, Directory, EntryType};
+use crate::CabinetError;
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use mhlog::err;
+
+/// Largest accepted body of PROPFIND, PROPPATCH and LOCK requests.
+const MAX_XML_SIZE: usize = 1024 * 1024;
+
+/// Methods supported by the WebDAV interface.
+const ALLOW: &str = "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND, PROPPATCH, MKCOL, COPY, MOVE, LOCK, UNLOCK";
+
+/// Resource for all WebDAV requests, which are dispatched on their method.
+pub fn service() -> actix_web::Resource {
+    web::resource("/dav{path:(/.*)?}").to(handle)
+}
+
+/// Handle a WebDAV request.
+pub async fn handle(
+    req: HttpRequest,
+    payload: web::Payload,
+    config: web::Data<Config>,
+    pool: web::Data<Pool>,
+    locks: web::Data<LockManager>,
+) -> Result<HttpResponse> {
+    use crate::request_handlers::file::{delete_file, get_file, put_file};
+
+    let path = req.match_info().query("path").trim_matches('/').to_string();
+    let tokens: Vec<String> = req
+        .headers()
+        .get("If")
+        .and_then(|v| v.to_str().ok())
+        .map(if_tokens)
+        .unwrap_or_default();
+
+    let target = {
+        let path = path.clone();
+        match block(&pool, move |conn| resolve(conn, &path)).await {
+            Ok(target) => target,
+            Err(e) => {
+                err!("Failed to resolve WebDAV resource: {}", e);
+                return Ok(internal_server_error!());
+            }
+        }
+    };
+
+    match (req.method().as_str(), target) {
+        ("OPTIONS", _) => Ok(HttpResponse::Ok()
+            .header("DAV", "1, 2")
+            .header("MS-Author-Via", "DAV")
+            .header("Allow", ALLOW)
+            .finish()),
+
+        //
+        // Properties
+        //
+        ("PROPFIND", None) => Ok(not_found!("{}", &path)),
+        ("PROPFIND", Some(entry)) => {
+            let find = match read_body(payload).await.map(|b| PropFind::parse(&b)) {
+                Ok(Ok(find)) => find,
+                Ok(Err(CabinetError::BadRequest(txt))) => return Ok(bad_request!("{}", txt)),
+                _ => return Ok(bad_request!()),
+            };
+            let depth = match req.headers().get("Depth").map(|v| v.as_bytes()) {
+                Some(b"0") => Some(0),
+                Some(b"1") => Some(1),
+                _ => None,
+            };
+            propfind(entry, find, depth, &pool, &locks).await
+        }
+        ("PROPPATCH", None) => Ok(not_found!("{}", &path)),
+        ("PROPPATCH", Some(entry)) => {
+            if !locks.allows(&path, &tokens, false) {
+                return Ok(locked!());
+            }
+            let props = match read_body(payload).await.map(|b| crate::dav::parse_proppatch(&b)) {
+                Ok(Ok(props)) => props,
+                _ => return Ok(bad_request!()),
+            };
+            let mut ms = MultiStatus::default();
+            ms.proppatch(&entry, &props);
+            Ok(multistatus(ms))
+        }
+
+        //
+        // Content
+        //
+        ("GET", Some(entry)) | ("HEAD", Some(entry)) if entry.entry_type == EntryType::File => {
+            get_file(path, None, &pool, req.clone(), req.method() == "HEAD").await
+        }
+        ("GET", Some(_)) | ("HEAD", Some(_)) => Ok(method_not_allowed!("not a file")),
+        ("GET", None) | ("HEAD", None) => Ok(not_found!("{}", &path)),
+        ("PUT", Some(entry)) if entry.entry_type == EntryType::Dir => {
+            Ok(method_not_allowed!("not a file"))
+        }
+        ("PUT", _) => {
+            if !locks.allows(&path, &tokens, false) {
+                return Ok(locked!());
+            }
+            match parent_exists(&pool, &path).await {
+                Ok(true) => put_file(path, payload, &config, &pool, &req).await,
+                Ok(false) => Ok(conflict!("parent directory does not exist")),
+                Err(resp) => Ok(resp),
+            }
+        }
+
+        //
+        // Collections
+        //
+        ("MKCOL", Some(_)) => Ok(method_not_allowed!("already exists")),
+        ("MKCOL", None) => {
+            if !locks.allows(&path, &tokens, false) {
+                return Ok(locked!());
+            }
+            match read_body(payload).await {
+                Ok(body) if body.is_empty() => (),
+                _ => return Ok(HttpResponse::UnsupportedMediaType().finish()),
+            }
+            match parent_exists(&pool, &path).await {
+                Ok(true) => (),
+                Ok(false) => return Ok(conflict!("parent directory does not exist")),
+                Err(resp) => return Ok(resp),
+            }
+            match block(&pool, move |conn| crate::database::dir::create(conn, path.as_ref())).await {
+                Ok(_) => Ok(HttpResponse::Created().finish()),
+                Err(e) => {
+                    err!("Failed creating directory: {}", e);
+                    Ok(internal_server_error!())
+                }
+            }
+        }
+
+        //
+        // Delete, move and copy
+        //
+        ("DELETE", None) => Ok(not_found!("{}", &path)),
+        ("DELETE", Some(_)) if path.is_empty() => Ok(forbidden!("cannot delete the root")),
+        ("DELETE", Some(entry)) => {
+            if !locks.allows(&path, &tokens, true) {
+                return Ok(locked!());
+            }
+            let resp = match entry.entry_type {
+                EntryType::File => delete_file(path.clone(), &pool, &req).await?,
+                EntryType::Dir => delete_dir(path.clone(), &pool).await,
+            };
+            if resp.status().is_success() {
+                locks.remove_tree(&path);
+            }
+            Ok(resp)
+        }
+        ("MOVE", None) | ("COPY", None) => Ok(not_found!("{}", &path)),
+        ("MOVE", Some(_)) | ("COPY", Some(_)) if path.is_empty() => {
+            Ok(forbidden!("cannot move or copy the root"))
+        }
+        ("MOVE", Some(entry)) | ("COPY", Some(entry)) => {
+            use crate::request_handlers::{destination, overwrite};
+
+            let is_move = req.method() == "MOVE";
+            let (dest, overwrite) = match (destination(req.headers(), "/dav/"), overwrite(req.headers())) {
+                (Ok(dest), Ok(overwrite)) => (dest, overwrite),
+                (Err(CabinetError::BadRequest(txt)), _) | (_, Err(CabinetError::BadRequest(txt))) => {
+                    return Ok(bad_request!("{}", txt))
+                }
+                _ => return Ok(bad_request!()),
+            };
+            if !locks.allows(&dest, &tokens, true) || (is_move && !locks.allows(&path, &tokens, true)) {
+                return Ok(locked!());
+            }
+            let resp = transfer(entry, dest, overwrite, is_move, &pool).await;
+            if is_move && resp.status().is_success() {
+                locks.remove_tree(&path);
+            }
+            Ok(resp)
+        }
+
+        //
+        // Locks
+        //
+        ("LOCK", target) => {
+            let body = match read_body(payload).await {
+                Ok(body) => body,
+                Err(_) => return Ok(bad_request!()),
+            };
+            let timeout = crate::dav::timeout(req.headers().get("Timeout").and_then(|v| v.to_str().ok()));
+            if body.is_empty() {
+                // Refresh an existing lock
+                return match (target, locks.refresh(&path, &tokens, timeout)) {
+                    (Some(_), Some(lock)) => Ok(lock_response(&lock, StatusCode::OK)),
+                    (None, _) => Ok(not_found!("{}", &path)),
+                    (_, None) => Ok(precondition_failed!("no matching lock")),
+                };
+            }
+            let info = match crate::dav::LockInfo::parse(&body) {
+                Ok(info) => info,
+                Err(CabinetError::BadRequest(txt)) => return Ok(bad_request!("{}", txt)),
+                Err(_) => return Ok(bad_request!()),
+            };
+            let infinite = !matches!(req.headers().get("Depth").map(|v| v.as_bytes()), Some(b"0"));
+            let lock = match locks.lock(&path, infinite, info, timeout) {
+                Some(lock) => lock,
+                None => return Ok(locked!()),
+            };
+            if target.is_some() {
+                return Ok(lock_response(&lock, StatusCode::OK));
+            }
+
+            // Locking an unmapped path creates an empty file
+            let created = match parent_exists(&pool, &path).await {
+                Ok(true) => create_empty(&pool, path.clone()).await,
+                Ok(false) => Err(conflict!("parent directory does not exist")),
+                Err(resp) => Err(resp),
+            };
+            match created {
+                Ok(_) => Ok(lock_response(&lock, StatusCode::CREATED)),
+                Err(resp) => {
+                    locks.unlock(&path, &lock.token);
+                    Ok(resp)
+                }
+            }
+        }
+        ("UNLOCK", _) => {
+            let token = req
+                .headers()
+                .get("Lock-Token")
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim().trim_start_matches('<').trim_end_matches('>'));
+            match token {
+                Some(token) if locks.unlock(&path, token) => Ok(HttpResponse::NoContent().finish()),
+                Some(_) => Ok(conflict!("no matching lock")),
+                None => Ok(bad_request!("missing Lock-Token header")),
+            }
+        }
+
+        _ => Ok(HttpResponse::MethodNotAllowed().header("Allow", ALLOW).finish()),
+    }
+}
+
+/// Find the file or directory at `path`.
+fn resolve(conn: &mut rusqlite::Connection, path: &str) -> crate::CabinetResult<Option<DirEntry>> {
+    use crate::database::dir::{fetch, DirIdentifier};
+    use crate::database::file::{fetch_info, FileIdentifier};
+    use crate::CabinetError::NotFound;
+
+    if path.is_empty() {
+        let root = Directory { id: 0, name: String::new(), parent: None };
+        return Ok(Some(DirEntry::dir("", &root)));
+    }
+    match fetch_info(conn, FileIdentifier::Path(path.as_ref())) {
+        Ok(file) => return Ok(Some(DirEntry::from(&file))),
+        Err(NotFound) => (),
+        Err(e) => return Err(e),
+    }
+    match fetch(conn, DirIdentifier::Path(path.as_ref())) {
+        Ok(dir) => {
+            let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+            Ok(Some(DirEntry::dir(parent, &dir)))
+        }
+        Err(NotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Check that the parent directory of `path` exists, as WebDAV doesn't
+/// create missing parents.
+async fn parent_exists(pool: &Pool, path: &str) -> std::result::Result<bool, HttpResponse> {
+    use crate::database::dir::{exists, DirIdentifier};
+
+    let parent = match path.rsplit_once('/') {
+        Some((parent, _)) => parent.to_string(),
+        None => return Ok(true),
+    };
+    block(pool, move |conn| exists(conn, DirIdentifier::Path(parent.as_ref())))
+        .await
+        .map_err(|e| {
+            err!("Failed checking if directory exists: {}", e);
+            internal_server_error!()
+        })
+}
+
+/// Read a request body of at most `MAX_XML_SIZE` bytes.
+async fn read_body(mut payload: web::Payload) -> std::result::Result<Bytes, ()> {
+    use futures::StreamExt;
+
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|_| ())?;
+        if body.len() + chunk.len() > MAX_XML_SIZE {
+            return Err(());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
+}
+
+async fn propfind(
+    entry: DirEntry,
+    find: PropFind,
+    depth: Option<usize>,
+    pool: &Pool,
+    locks: &LockManager,
+) -> Result<HttpResponse> {
+    use crate::database::dir::tree;
+
+    let mut entries = vec![entry.clone()];
+    if entry.entry_type == EntryType::Dir && depth != Some(0) {
+        let path = entry.path.clone();
+        match block(pool, move |conn| tree(conn, path.as_ref(), depth)).await {
+            Ok(children) => entries.extend(children),
+            Err(e) => {
+                err!("Failed getting directory content: {}", e);
+                return Ok(internal_server_error!());
+            }
+        }
+    }
+
+    let mut ms = MultiStatus::default();
+    for entry in &entries {
+        ms.propfind(entry, &find, &locks.locks_on(&entry.path));
+    }
+    Ok(multistatus(ms))
+}
+
+fn multistatus(ms: MultiStatus) -> HttpResponse {
+    HttpResponse::build(StatusCode::MULTI_STATUS)
+        .content_type("application/xml; charset=utf-8")
+        .body(ms.finish())
+}
+
+async fn delete_dir(path: String, pool: &Pool) -> HttpResponse {
+    use crate::database::dir::delete_recursive;
+    use crate::CabinetError::{BadRequest, NotFound};
+
+    match block(pool, move |conn| delete_recursive(conn, path.as_ref())).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(NotFound) => not_found!(),
+        Err(BadRequest(txt)) => forbidden!("{}", txt),
+        Err(e) => {
+            err!("Failed to delete directory recursively: {}", e);
+            internal_server_error!()
+        }
+    }
+}
+
+/// Move or copy a file or directory.
+async fn transfer(entry: DirEntry, dest: String, overwrite: bool, is_move: bool, pool: &Pool) -> HttpResponse {
+    use crate::database::{dir, file};
+    use crate::CabinetError::{BadRequest, NotFound, PreconditionFailed};
+    use actix_web::http::header::HttpDate;
+    use std::time::SystemTime;
+
+    let modified = HttpDate::from(SystemTime::now()).to_string();
+    let res = block(pool, move |conn| {
+        let dest: &std::path::Path = dest.as_ref();
+        match (entry.entry_type, is_move) {
+            (EntryType::File, true) => file::move_to(conn, entry.id, dest, overwrite),
+            (EntryType::File, false) => file::copy_to(conn, entry.id, dest, overwrite, Some(&modified)),
+            (EntryType::Dir, true) => dir::move_to(conn, entry.path.as_ref(), dest, overwrite),
+            (EntryType::Dir, false) => dir::copy_to(conn, entry.path.as_ref(), dest, overwrite, Some(&modified)),
+        }
+    })
+    .await;
+    match res {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::Created().finish(),
+        Err(NotFound) => not_found!(),
+        Err(PreconditionFailed) => precondition_failed!(),
+        Err(BadRequest(txt)) => forbidden!("{}", txt),
+        Err(e) => {
+            err!("Failed to move or copy: {}", e);
+            internal_server_error!()
+        }
+    }
+}
+
+async fn create_empty(pool: &Pool, path: String) -> std::result::Result<(), HttpResponse> {
+    use crate::database::file::create;
+    use crate::file::NewFile;
+    use actix_web::http::header::HttpDate;
+    use std::time::SystemTime;
+
+    let new_file = NewFile {
+        path,
+        content: Vec::new(),
+        mode: 0o644,
+        modified: HttpDate::from(SystemTime::now()).to_string(),
+    };
+    match block(pool, move |conn| create(conn, &new_file)).await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            err!("Failed creating file: {}", e);
+            Err(internal_server_error!())
+        }
+    }
+}
+
+fn lock_response(lock: &crate::dav::Lock, status: StatusCode) -> HttpResponse {
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>\n",
+        lock.to_xml()
+    );
+    HttpResponse::build(status)
+        .header("Lock-Token", format!("<{}>", lock.token))
+        .content_type("application/xml; charset=utf-8")
+        .body(xml)
+}
@@ -1,18 +1,54 @@
-use crate::get_db_conn;
-use actix_web::{web, HttpResponse, Result};
+use crate::database::{block, Pool};
+use actix_web::{web, HttpRequest, HttpResponse, Result};
 use mhlog::err;
+use serde::Deserialize;
+
+/// Query parameters accepted when listing directories.
+#[derive(Debug, Deserialize)]
+pub struct DirQuery {
+    /// Return objects with the metadata of each entry instead of names.
+    #[serde(default)]
+    detailed: bool,
+    /// Include the content of all subdirectories.
+    #[serde(default)]
+    recursive: bool,
+    /// Number of directory levels to include. Defaults to 1, or all levels
+    /// if `recursive` is set.
+    depth: Option<usize>,
+}
+
+/// Query parameters accepted when deleting directories.
+#[derive(Debug, Deserialize)]
+pub struct DeleteQuery {
+    /// Delete the directory with all its files and subdirectories.
+    #[serde(default)]
+    recursive: bool,
+}
 
 #[actix_web::get("/dirs/{dir:.*}")]
-pub async fn get(web::Path(dir_path): web::Path<String>) -> Result<HttpResponse> {
-    use crate::database::dir::{content, DirIdentifier};
+pub async fn get(
+    web::Path(dir_path): web::Path<String>,
+    web::Query(query): web::Query<DirQuery>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse> {
+    use crate::database::dir::{canonical_path, tree};
+    use crate::dir::EntryType;
     use crate::CabinetError::NotFound;
 
+    let depth = match query.depth {
+        Some(0) => return Ok(bad_request!("depth must be larger than 0")),
+        Some(depth) => Some(depth),
+        None if query.recursive => None,
+        None => Some(1),
+    };
+
     //
     // Get directory content
     //
-    let conn = get_db_conn();
-    let content = match content(&conn, DirIdentifier::Path(dir_path.as_ref())).await {
-        Ok(content) => content,
+    let prefix = canonical_path(dir_path.as_ref());
+    let path = prefix.clone();
+    let entries = match block(&pool, move |conn| tree(conn, path.as_ref(), depth)).await {
+        Ok(entries) => entries,
         Err(NotFound) => return Ok(not_found!("{}", &dir_path)),
         Err(e) => {
             err!("Failed getting directory content: {}", e);
@@ -23,21 +59,43 @@ pub async fn get(web::Path(dir_path): web::Path<String>) -> Result<HttpResponse>
     //
     // Create and return response object
     //
-    let names: Vec<_> = content.iter().map(ToString::to_string).collect();
     let mut resp = HttpResponse::Ok();
+    if query.detailed {
+        return Ok(resp.json(&entries));
+    }
+    // Entry paths relative to the listed directory, with a trailing
+    // slash for directories.
+    let names: Vec<_> = entries
+        .iter()
+        .map(|e| {
+            let name = e.path.strip_prefix(&prefix).unwrap_or(&e.path).trim_start_matches('/');
+            match e.entry_type {
+                EntryType::Dir => format!("{}/", name),
+                EntryType::File => name.to_string(),
+            }
+        })
+        .collect();
     Ok(resp.json(&names))
 }
 
 #[actix_web::put("/dirs/{dir:.*}")]
-pub async fn put(web::Path(dir_path): web::Path<String>) -> Result<HttpResponse> {
+pub async fn put(
+    web::Path(dir_path): web::Path<String>,
+    pool: web::Data<Pool>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
     use crate::database::dir::DirIdentifier::Path;
     use crate::database::dir::{create, exists};
 
+    if !super::unlocked(&req, &dir_path, false) {
+        return Ok(locked!());
+    }
+
     //
     // Check if the directory already exists
     //
-    let conn = get_db_conn();
-    let exists = match exists(&conn, Path(dir_path.as_ref())).await {
+    let path = dir_path.clone();
+    let exists = match block(&pool, move |conn| exists(conn, Path(path.as_ref()))).await {
         Ok(exists) => exists,
         Err(e) => {
             err!("Failed checking if directory exists: {}", e);
@@ -51,7 +109,7 @@ pub async fn put(web::Path(dir_path): web::Path<String>) -> Result<HttpResponse>
     //
     // Create directory if it doesn't exist
     //
-    match create(&conn, dir_path.as_ref()).await {
+    match block(&pool, move |conn| create(conn, dir_path.as_ref())).await {
         Ok(_) => Ok(HttpResponse::Created().finish()),
         Err(e) => {
             err!("Failed creating directory: {}", e);
@@ -61,16 +119,41 @@ pub async fn put(web::Path(dir_path): web::Path<String>) -> Result<HttpResponse>
 }
 
 #[actix_web::delete("/dirs/{dir:.*}")]
-pub async fn delete(web::Path(dir_path): web::Path<String>) -> Result<HttpResponse> {
+pub async fn delete(
+    web::Path(dir_path): web::Path<String>,
+    web::Query(query): web::Query<DeleteQuery>,
+    pool: web::Data<Pool>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
     use crate::database::dir::DirIdentifier::{Id, Path};
-    use crate::database::dir::{content, delete, fetch};
-    use crate::CabinetError::NotFound;
+    use crate::database::dir::{content, delete, delete_recursive, fetch};
+    use crate::CabinetError::{BadRequest, NotFound};
+
+    if !super::unlocked(&req, &dir_path, query.recursive) {
+        return Ok(locked!());
+    }
+
+    //
+    // Delete directory and content, returning what was deleted
+    //
+    if query.recursive {
+        let path = dir_path.clone();
+        return match block(&pool, move |conn| delete_recursive(conn, path.as_ref())).await {
+            Ok(deleted) => Ok(HttpResponse::Ok().json(&deleted)),
+            Err(NotFound) => Ok(not_found!("{}", &dir_path)),
+            Err(BadRequest(txt)) => Ok(bad_request!("{}", txt)),
+            Err(e) => {
+                err!("Failed to delete directory recursively: {}", e);
+                Ok(internal_server_error!())
+            }
+        };
+    }
 
     //
     // Fetch requested directory
     //
-    let conn = get_db_conn();
-    let dir_entry = match fetch(&conn, Path(dir_path.as_ref())).await {
+    let path = dir_path.clone();
+    let dir_entry = match block(&pool, move |conn| fetch(conn, Path(path.as_ref()))).await {
         Ok(dir) => dir,
         Err(NotFound) => return Ok(not_found!("{}", &dir_path)),
         Err(e) => {
@@ -82,22 +165,121 @@ pub async fn delete(web::Path(dir_path): web::Path<String>) -> Result<HttpRespon
     //
     // Check that the directory is empty before deleting
     //
-    let content = match content(&conn, Id(dir_entry.id)).await {
+    let id = dir_entry.id;
+    let content = match block(&pool, move |conn| content(conn, Id(id))).await {
         Ok(content) => content,
         Err(e) => {
             err!("Failed to get content of directory: {}", e);
             return Ok(internal_server_error!());
         }
     };
-    if content.len() > 0 {
+    if !content.is_empty() {
         return Ok(bad_request!("directory not empty"));
     }
 
-    match delete(&conn, Id(dir_entry.id)).await {
+    match block(&pool, move |conn| delete(conn, Id(id))).await {
         Ok(_) => Ok(HttpResponse::NoContent().finish()),
         Err(e) => {
             err!("Failed to delete directory: {}", e);
-            return Ok(internal_server_error!());
+            Ok(internal_server_error!())
+        }
+    }
+}
+
+/// Resource for MOVE requests, which the route macros don't support.
+pub fn move_service() -> actix_web::Resource {
+    use actix_web::guard;
+
+    web::resource("/dirs/{dir:.*}")
+        .guard(guard::Method(super::move_method()))
+        .to(move_dir)
+}
+
+/// Move a directory and all its content to the path given by the
+/// Destination header.
+pub async fn move_dir(
+    web::Path(dir_path): web::Path<String>,
+    pool: web::Data<Pool>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    use super::{destination, overwrite, unlocked};
+    use crate::database::dir::move_to;
+    use crate::CabinetError::{BadRequest, NotFound, PreconditionFailed};
+
+    let (dest, overwrite) = match (destination(req.headers(), "/dirs/"), overwrite(req.headers())) {
+        (Ok(dest), Ok(overwrite)) => (dest, overwrite),
+        (Err(BadRequest(txt)), _) | (_, Err(BadRequest(txt))) => return Ok(bad_request!("{}", txt)),
+        _ => return Ok(bad_request!()),
+    };
+    if !unlocked(&req, &dir_path, true) || !unlocked(&req, &dest, true) {
+        return Ok(locked!());
+    }
+
+    let path = dir_path.clone();
+    match block(&pool, move |conn| move_to(conn, path.as_ref(), dest.as_ref(), overwrite)).await {
+        Ok(true) => Ok(HttpResponse::NoContent().finish()),
+        Ok(false) => Ok(HttpResponse::Created().finish()),
+        Err(NotFound) => Ok(not_found!("{}", &dir_path)),
+        Err(PreconditionFailed) => Ok(precondition_failed!()),
+        Err(BadRequest(txt)) => Ok(bad_request!("{}", txt)),
+        Err(e) => {
+            err!("Failed to move directory: {}", e);
+            Ok(internal_server_error!())
+        }
+    }
+}
+
+/// Resource for COPY requests, which the route macros don't support.
+pub fn copy_service() -> actix_web::Resource {
+    use actix_web::guard;
+
+    web::resource("/dirs/{dir:.*}")
+        .guard(guard::Method(super::copy_method()))
+        .to(copy_dir)
+}
+
+/// Copy a directory and all its content to the path given by the
+/// Destination header.
+pub async fn copy_dir(
+    web::Path(dir_path): web::Path<String>,
+    web::Query(query): web::Query<super::CopyQuery>,
+    pool: web::Data<Pool>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    use super::{destination, overwrite, unlocked};
+    use crate::database::dir::copy_to;
+    use crate::CabinetError::{BadRequest, NotFound, PreconditionFailed};
+    use actix_web::http::header::HttpDate;
+    use std::time::SystemTime;
+
+    let (dest, overwrite) = match (destination(req.headers(), "/dirs/"), overwrite(req.headers())) {
+        (Ok(dest), Ok(overwrite)) => (dest, overwrite),
+        (Err(BadRequest(txt)), _) | (_, Err(BadRequest(txt))) => return Ok(bad_request!("{}", txt)),
+        _ => return Ok(bad_request!()),
+    };
+    if !unlocked(&req, &dest, true) {
+        return Ok(locked!());
+    }
+    let modified = if query.keep_modified {
+        None
+    } else {
+        Some(HttpDate::from(SystemTime::now()).to_string())
+    };
+
+    let path = dir_path.clone();
+    let res = block(&pool, move |conn| {
+        copy_to(conn, path.as_ref(), dest.as_ref(), overwrite, modified.as_deref())
+    })
+    .await;
+    match res {
+        Ok(true) => Ok(HttpResponse::NoContent().finish()),
+        Ok(false) => Ok(HttpResponse::Created().finish()),
+        Err(NotFound) => Ok(not_found!("{}", &dir_path)),
+        Err(PreconditionFailed) => Ok(precondition_failed!()),
+        Err(BadRequest(txt)) => Ok(bad_request!("{}", txt)),
+        Err(e) => {
+            err!("Failed to copy directory: {}", e);
+            Ok(internal_server_error!())
         }
     }
 }
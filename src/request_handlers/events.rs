@@ -0,0 +1,86 @@
+use crate::database::{block, Pool};
+use crate::events::{Broadcaster, Filter, BUFFER};
+use crate::token::Token;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use mhlog::err;
+use serde::Deserialize;
+
+/// Header of a reconnecting event stream, giving the cursor of the last
+/// received event.
+const LAST_EVENT_ID_HEADER: &str = "Last-Event-ID";
+
+/// Query parameters accepted when subscribing to events.
+#[derive(Debug, Deserialize)]
+pub struct EventsQuery {
+    /// Cursor to resume after. Defaults to the latest change.
+    since: Option<usize>,
+    /// Comma separated path prefixes to watch.
+    prefix: Option<String>,
+    /// Comma separated names of boilerplates to watch.
+    boilerplate: Option<String>,
+}
+
+fn split(list: &Option<String>) -> Vec<String> {
+    list.iter()
+        .flat_map(|s| s.split(','))
+        .map(|s| s.trim_matches('/').to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Subscribe to notifications of changes, as a stream of server-sent events.
+///
+/// Each event is a change of the change log, with its cursor as event id.
+/// Subscribers are only notified of changes under the watched prefixes, and
+/// of the watched boilerplates and their files, if any are given. Streams
+/// resume after the `Last-Event-ID` header or `since`, but subscribers too
+/// far behind must catch up with `GET /changes` first.
+///
+#[actix_web::get("/events")]
+pub async fn get(
+    web::Query(query): web::Query<EventsQuery>,
+    pool: web::Data<Pool>,
+    broadcaster: web::Data<Broadcaster>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    use crate::database::change::latest;
+    use futures::StreamExt;
+    use std::str::FromStr;
+
+    //
+    // Find the cursor to resume after
+    //
+    let since = match req.headers().get(LAST_EVENT_ID_HEADER) {
+        Some(id) => match id.to_str().ok().and_then(|id| usize::from_str(id.trim()).ok()) {
+            Some(id) => Some(id),
+            None => return Ok(bad_request!("invalid {} header", LAST_EVENT_ID_HEADER)),
+        },
+        None => query.since,
+    };
+    let latest = match block(&pool, |conn| latest(conn)).await {
+        Ok(latest) => latest,
+        Err(e) => {
+            err!("Failed to read change log: {}", e);
+            return Ok(internal_server_error!());
+        }
+    };
+    let since = since.map_or(latest, |since| since.min(latest));
+    if latest - since > BUFFER {
+        return Ok(gone!("cursor {} is too old, catch up with /changes", since));
+    }
+
+    //
+    // Subscribe
+    //
+    let filter = Filter {
+        prefixes: split(&query.prefix),
+        boilerplates: split(&query.boilerplate),
+    };
+    let token = req.extensions().get::<Token>().cloned();
+    let events = broadcaster.subscribe(filter, token, since);
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .streaming(events.map(Ok::<_, actix_web::Error>)))
+}
@@ -1,39 +1,324 @@
 use crate::{CabinetError, CabinetResult};
-use crate::file::{File, NewFile};
-use crate::get_db_conn;
+use crate::compress::Encoding;
+use crate::config::Config;
+use crate::file::{FileInfo, NewFile};
+use crate::database::{block, Pool};
+use super::RestoreQuery;
 use actix_web::dev::HttpResponseBuilder;
+use actix_web::error::ParseError;
 use actix_web::http::header::HttpDate;
 use actix_web::http::HeaderMap;
+use actix_web::web::Bytes;
 use actix_web::{web, HttpRequest, HttpResponse, Result};
+use futures::Stream;
 use mhlog::err;
+use serde::Deserialize;
 use std::str::FromStr;
 
-const MAX_SIZE: usize = 262_144;
+/// Header carrying the file mode, as an octal number.
+const MODE_HEADER: &str = "X-Cabinet-Mode";
+
+/// Mode of new files uploaded without a mode header.
+const DEFAULT_MODE: u32 = 0o644;
+
+/// Query parameters accepted when reading files.
+#[derive(Debug, Deserialize)]
+pub struct FileQuery {
+    /// Revision of the file to read. Defaults to the current version.
+    rev: Option<usize>,
+}
+
+/// Largest number of ranges served for one request. Requests for more
+/// ranges are answered with the whole file.
+const MAX_RANGES: usize = 32;
+
+/// Content of a file response: either already loaded into memory, or
+/// streamed from the database.
+enum Content {
+    Memory(Vec<u8>),
+    Stream { id: usize },
+}
+
+/// Part of a response body: a range of the file content, or literal bytes
+/// between ranges, like the boundaries of multipart responses.
+enum Part {
+    /// Inclusive byte range of the content.
+    Range(u64, u64),
+    Literal(Bytes),
+}
+
+impl Part {
+    fn len(&self) -> u64 {
+        match self {
+            Part::Range(start, end) => end - start + 1,
+            Part::Literal(bytes) => bytes.len() as u64,
+        }
+    }
+}
+
+/// The part of a file a GET request asks for.
+enum Ranges {
+    Full,
+    /// Inclusive byte ranges, in the order requested.
+    Partial(Vec<(u64, u64)>),
+    /// None of the requested ranges overlap the content.
+    Unsatisfiable,
+}
+
+/// Get the ranges of `file` requested by the Range header of a request.
+///
+/// The Range header is ignored if it is invalid, asks for too many ranges,
+/// or if the If-Range condition does not hold. If-Range holds if it gives
+/// the strong ETag of the file, or exactly its modification date.
+///
+fn requested_ranges(headers: &HeaderMap, file: &FileInfo) -> Ranges {
+    use actix_web::http::header::EntityTag;
+
+    let specs = match headers.get("Range").and_then(|v| v.to_str().ok()) {
+        Some(val) => match val.trim().strip_prefix("bytes=") {
+            Some(specs) => specs,
+            None => return Ranges::Full,
+        },
+        None => return Ranges::Full,
+    };
+    if let Some(val) = headers.get("If-Range") {
+        let val = val.to_str().unwrap_or_default().trim();
+        let holds = match (EntityTag::from_str(val), HttpDate::from_str(val)) {
+            (Ok(etag), _) => etag.strong_eq(&EntityTag::strong(file.hash.clone())),
+            (_, Ok(date)) => HttpDate::from_str(&file.modified).is_ok_and(|m| m == date),
+            _ => false,
+        };
+        if !holds {
+            return Ranges::Full;
+        }
+    }
+
+    let size = file.size as u64;
+    let specs: Vec<&str> = specs.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return Ranges::Full;
+    }
+    let mut ranges = Vec::with_capacity(specs.len());
+    for spec in specs {
+        let (first, last) = match spec.split_once('-') {
+            Some(bounds) => bounds,
+            None => return Ranges::Full,
+        };
+        let (first, last) = match (u64::from_str(first), u64::from_str(last)) {
+            // Suffix range, the last bytes of the content
+            (Err(_), Ok(len)) if first.is_empty() => match len.min(size) {
+                0 => continue,
+                len => (size - len, size - 1),
+            },
+            (Ok(first), Err(_)) if last.is_empty() => (first, u64::MAX),
+            (Ok(first), Ok(last)) if first <= last => (first, last),
+            _ => return Ranges::Full,
+        };
+        if first < size {
+            ranges.push((first, last.min(size - 1)));
+        }
+    }
+    if ranges.is_empty() {
+        Ranges::Unsatisfiable
+    } else {
+        Ranges::Partial(ranges)
+    }
+}
+
+/// Check the If-Unmodified-Since and If-Match conditions of a request
+/// modifying an existing file.
+fn modification_allowed(headers: &HeaderMap, file: &FileInfo) -> std::result::Result<bool, ParseError> {
+    let etag = &file.hash;
+    let modified = HttpDate::from_str(&file.modified)?;
+
+    // If-Unmodified-Since condition
+    let unmodified_since = if let Some(val) = headers.get("If-Unmodified-Since") {
+        let date: HttpDate = val.to_str().unwrap().parse()?;
+        modified <= date
+    } else {
+        true
+    };
+    // If-Match condition
+    let if_match = if headers.contains_key("If-Match") {
+        headers
+            .get_all("If-Match")
+            // ETAG values are enclosed in double quotes
+            .map(|e| e.to_str().unwrap().trim_matches('"'))
+            .any(|e| e == etag)
+    } else {
+        true
+    };
+    Ok(unmodified_since && if_match)
+}
+
+/// Check the request conditions against a file fetched by the transaction
+/// which modifies it.
+fn check_modification(headers: &HeaderMap, file: &FileInfo) -> CabinetResult<()> {
+    match modification_allowed(headers, file) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(CabinetError::PreconditionFailed),
+        Err(e) => Err(CabinetError::BadRequest(e.to_string())),
+    }
+}
+
+/// Stream parts of the content of a file from the database.
+///
+/// The content is read by a blocking task, in chunks, and passed on to the
+/// returned stream.
+///
+fn stream_content(pool: Pool, id: usize, parts: Vec<Part>) -> impl Stream<Item = Result<Bytes>> {
+    use crate::database::blob::CHUNK_SIZE;
+    use crate::database::file::open_content;
+    use async_std::task::{block_on, spawn_blocking};
+    use futures::channel::mpsc;
+    use futures::{SinkExt, StreamExt};
+    use std::io::{Error, Read, Seek, SeekFrom};
+
+    let (mut tx, rx) = mpsc::channel(4);
+    spawn_blocking(move || {
+        let conn = match pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                err!("Failed to get database connection: {}", e);
+                let _ = block_on(tx.send(Err(Error::other(e.to_string()))));
+                return;
+            }
+        };
+        let mut blob = match open_content(&conn, id) {
+            Ok(blob) => blob,
+            Err(e) => {
+                err!("Failed to open file content: {}", e);
+                let _ = block_on(tx.send(Err(Error::other(e.to_string()))));
+                return;
+            }
+        };
+        let mut buf = vec![0; CHUNK_SIZE];
+        for part in parts {
+            let (start, end) = match part {
+                Part::Range(start, end) => (start, end),
+                Part::Literal(bytes) => {
+                    if block_on(tx.send(Ok(bytes))).is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            let mut remaining = end - start + 1;
+            let mut res = blob.seek(SeekFrom::Start(start)).map(|_| ());
+            while res.is_ok() && remaining > 0 {
+                let n = remaining.min(CHUNK_SIZE as u64) as usize;
+                res = match blob.read(&mut buf[..n]) {
+                    Ok(0) => Err(Error::new(std::io::ErrorKind::UnexpectedEof, "file content too short")),
+                    Ok(n) => {
+                        remaining -= n as u64;
+                        // Sending fails when the client has gone away
+                        if block_on(tx.send(Ok(Bytes::copy_from_slice(&buf[..n])))).is_err() {
+                            return;
+                        }
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                };
+            }
+            if let Err(e) = res {
+                err!("Failed to read file content: {}", e);
+                let _ = block_on(tx.send(Err(e)));
+                return;
+            }
+        }
+    });
+    rx.map(|chunk| chunk.map_err(Into::into))
+}
+
+/// Receive a request payload into a temporary file, decoding it if it has
+/// a content encoding, returning the file and the decoded size.
+///
+/// `None` is returned if the decoded payload is larger than `max_size`.
+///
+pub async fn spool_payload(
+    mut payload: web::Payload,
+    encoding: Option<Encoding>,
+    max_size: usize,
+) -> Result<Option<(std::fs::File, usize)>> {
+    use crate::compress::{Decoder, Limit};
+    use actix_web::error::ErrorBadRequest;
+    use async_std::stream::StreamExt;
+    use std::io::{ErrorKind, Seek, SeekFrom, Write};
+
+    let invalid = |e: std::io::Error| match e.kind() {
+        ErrorKind::InvalidData | ErrorKind::InvalidInput | ErrorKind::UnexpectedEof | ErrorKind::Other => {
+            ErrorBadRequest(format!("400 Bad Request: invalid encoded content: {}", e))
+        }
+        _ => e.into(),
+    };
+    let mut decoder = Decoder::new(encoding, Limit::new(tempfile::tempfile()?, max_size))?;
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        match decoder.write_all(&chunk) {
+            Err(e) if e.kind() == ErrorKind::FileTooLarge => return Ok(None),
+            res => res.map_err(invalid)?,
+        }
+    }
+    let limit = match decoder.finish() {
+        Err(e) if e.kind() == ErrorKind::FileTooLarge => return Ok(None),
+        res => res.map_err(invalid)?,
+    };
+    let size = limit.written();
+    let mut file = limit.into_inner();
+    file.seek(SeekFrom::Start(0))?;
+    Ok(Some((file, size)))
+}
+
+/// Get the file mode from the mode header of a request, if present.
+fn mode_header(headers: &HeaderMap) -> std::result::Result<Option<u32>, String> {
+    let val = match headers.get(MODE_HEADER) {
+        Some(val) => val.to_str().map_err(|e| e.to_string())?,
+        None => return Ok(None),
+    };
+    match u32::from_str_radix(val.trim().trim_start_matches("0o"), 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(Some(mode)),
+        _ => Err(format!("invalid file mode: {}", val)),
+    }
+}
 
 async fn head_or_get(
     file_path: String,
-    req: HttpRequest,
-) -> CabinetResult<(HttpResponseBuilder, Vec<u8>)> {
-    use crate::database::file::fetch;
+    rev: Option<usize>,
+    pool: &Pool,
+    req: &HttpRequest,
+) -> CabinetResult<(HttpResponseBuilder, FileInfo, Content)> {
+    use crate::database::file::{fetch_info, fetch_revision};
     use crate::database::file::FileIdentifier::Path;
     use actix_web::http::header::{ContentType, ETag, EntityTag, LastModified};
 
     //
-    // Fetch requested file
+    // Fetch requested file. Old revisions are loaded into memory, while
+    // the current version is streamed.
     //
-    let conn = get_db_conn();
-    let file: File = fetch(&conn, Path(file_path.as_ref())).await?;
+    let (file, content) = match rev {
+        Some(rev) => {
+            let file = block(pool, move |conn| fetch_revision(conn, Path(file_path.as_ref()), rev)).await?;
+            (FileInfo::from(&file), Content::Memory(file.content))
+        }
+        None => {
+            let file = block(pool, move |conn| fetch_info(conn, Path(file_path.as_ref()))).await?;
+            let content = Content::Stream { id: file.id };
+            (file, content)
+        }
+    };
 
     //
     // Prepare response header
     //
-    let etag = file.content_hash();
+    let etag = file.hash.clone();
     let mime_type = file.content_type();
     let modified = HttpDate::from_str(&file.modified)?;
     let mut resp = HttpResponse::Ok();
     resp.set(ETag(EntityTag::strong(etag.clone())));
     resp.set(LastModified(modified));
     resp.set(ContentType(mime_type));
+    resp.header(MODE_HEADER, format!("{:04o}", file.mode));
+    resp.header("Accept-Ranges", "bytes");
 
     //
     // Handle request conditions
@@ -48,8 +333,9 @@ async fn head_or_get(
     let none_match = if headers.contains_key("If-None-Match") {
         let matches: bool = headers
             .get_all("If-None-Match")
-            .map(|e| e.to_str().unwrap().trim_matches('"'))
-            .any(|e| e == &etag);
+            // Compared weakly, as compressed responses have weak ETags
+            .map(|e| e.to_str().unwrap().trim_start_matches("W/").trim_matches('"'))
+            .any(|e| e == etag);
         !matches
     } else {
         true
@@ -58,16 +344,47 @@ async fn head_or_get(
         return Err(CabinetError::NotModified);
     }
 
-    Ok((resp, file.content))
+    Ok((resp, file, content))
 }
 
 #[actix_web::get("/files/{file:.*}")]
 pub async fn get(
     web::Path(file_path): web::Path<String>,
+    web::Query(query): web::Query<FileQuery>,
+    pool: web::Data<Pool>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    get_file(file_path, query.rev, &pool, req, false).await
+}
+
+#[actix_web::head("/files/{file:.*}")]
+pub async fn head(
+    web::Path(file_path): web::Path<String>,
+    web::Query(query): web::Query<FileQuery>,
+    pool: web::Data<Pool>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    get_file(file_path, query.rev, &pool, req, true).await
+}
+
+/// Respond with a file, or only its headers if `headers_only` is set.
+///
+/// GET requests with a Range header are answered with only the requested
+/// ranges, as multipart/byteranges if more than one.
+///
+pub async fn get_file(
+    file_path: String,
+    rev: Option<usize>,
+    pool: &Pool,
     req: HttpRequest,
+    headers_only: bool,
 ) -> Result<HttpResponse> {
     use crate::CabinetError::{NotFound, NotModified};
-    let (mut resp, content) = match head_or_get(file_path.clone(), req).await {
+    use actix_web::dev::SizedStream;
+    use actix_web::http::header::{ContentRange, ContentRangeSpec, CONTENT_TYPE};
+    use actix_web::http::StatusCode;
+
+    let (mut resp, file, content) = match head_or_get(file_path.clone(), rev, pool, &req).await {
         Ok(res) => res,
         Err(NotFound) => return Ok(not_found!("{}", &file_path)),
         Err(NotModified) => return Ok(not_modified!()),
@@ -76,202 +393,495 @@ pub async fn get(
             return Ok(internal_server_error!());
         }
     };
-    Ok(resp.body(content))
+    if headers_only {
+        return Ok(resp.finish());
+    }
+
+    //
+    // Select the requested parts of the content
+    //
+    let size = file.size as u64;
+    let content_range = |(start, end)| ContentRange(ContentRangeSpec::Bytes {
+        range: Some((start, end)),
+        instance_length: Some(size),
+    });
+    let parts = match requested_ranges(req.headers(), &file) {
+        Ranges::Full if size == 0 => vec![],
+        Ranges::Full => vec![Part::Range(0, size - 1)],
+        Ranges::Unsatisfiable => {
+            return Ok(HttpResponse::RangeNotSatisfiable()
+                .set(ContentRange(ContentRangeSpec::Bytes { range: None, instance_length: Some(size) }))
+                .body("416 Range Not Satisfiable"))
+        }
+        Ranges::Partial(ranges) if ranges.len() == 1 => {
+            resp.status(StatusCode::PARTIAL_CONTENT);
+            resp.set(content_range(ranges[0]));
+            vec![Part::Range(ranges[0].0, ranges[0].1)]
+        }
+        Ranges::Partial(ranges) => {
+            let boundary = crate::token::generate();
+            let mut parts = Vec::with_capacity(2 * ranges.len() + 1);
+            for (start, end) in ranges {
+                let header = format!(
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                    boundary,
+                    file.content_type(),
+                    content_range((start, end)),
+                );
+                parts.push(Part::Literal(header.into()));
+                parts.push(Part::Range(start, end));
+            }
+            parts.push(Part::Literal(format!("\r\n--{}--\r\n", boundary).into()));
+            resp.status(StatusCode::PARTIAL_CONTENT);
+            resp.set_header(CONTENT_TYPE, format!("multipart/byteranges; boundary={}", boundary));
+            parts
+        }
+    };
+
+    match content {
+        Content::Memory(content) => {
+            let mut body = Vec::new();
+            for part in parts {
+                match part {
+                    Part::Range(start, end) => body.extend_from_slice(&content[start as usize..=end as usize]),
+                    Part::Literal(bytes) => body.extend_from_slice(&bytes),
+                }
+            }
+            Ok(resp.body(body))
+        }
+        Content::Stream { id } => {
+            let len = parts.iter().map(Part::len).sum();
+            let stream = Box::pin(stream_content(pool.clone(), id, parts));
+            Ok(resp.body(SizedStream::new(len, stream)))
+        }
+    }
 }
 
-#[actix_web::head("/files/{file:.*}")]
-pub async fn head(
+#[actix_web::get("/revisions/files/{file:.*}")]
+pub async fn revisions(
     web::Path(file_path): web::Path<String>,
-    req: HttpRequest,
+    pool: web::Data<Pool>,
 ) -> Result<HttpResponse> {
+    use crate::database::file::revisions;
+    use crate::database::file::FileIdentifier::Path;
     use crate::CabinetError::NotFound;
-    let (mut resp, _) = match head_or_get(file_path.clone(), req).await {
-        Ok(res) => res,
-        Err(NotFound) => return Ok(not_found!("{}", &file_path)),
+
+    let path = file_path.clone();
+    match block(&pool, move |conn| revisions(conn, Path(path.as_ref()))).await {
+        Ok(revs) => Ok(HttpResponse::Ok().json(&revs)),
+        Err(NotFound) => Ok(not_found!("{}", &file_path)),
         Err(e) => {
-            err!("Failed to get file (head): {}", e);
-            return Ok(internal_server_error!());
+            err!("Failed to get file revisions: {}", e);
+            Ok(internal_server_error!())
         }
-    };
-    Ok(resp.finish())
+    }
 }
 
 #[actix_web::put("/files/{file:.*}")]
 pub async fn put(
     web::Path(file_path): web::Path<String>,
-    mut payload: web::Payload,
+    payload: web::Payload,
+    config: web::Data<Config>,
+    pool: web::Data<Pool>,
     req: HttpRequest,
+) -> Result<HttpResponse> {
+    if !super::unlocked(&req, &file_path, false) {
+        return Ok(locked!());
+    }
+    put_file(file_path, payload, &config, &pool, &req).await
+}
+
+/// Create or update a file with the content of the request.
+pub async fn put_file(
+    file_path: String,
+    payload: web::Payload,
+    config: &Config,
+    pool: &Pool,
+    req: &HttpRequest,
 ) -> Result<HttpResponse> {
     use crate::database::file::FileIdentifier::Path;
-    use crate::database::file::{create, fetch, update};
-    use crate::CabinetError::NotFound;
-    use async_std::stream::StreamExt;
+    use crate::database::file::{create_from_reader, fetch_info, update_from_reader};
+    use crate::CabinetError::{BadRequest, NotFound, PreconditionFailed};
+    use actix_web::http::header::CONTENT_LENGTH;
     use std::time::SystemTime;
 
     //
-    // Prepare response
+    // Fetch existing file
     //
-    let conn = get_db_conn();
-    let mut already_exists = false;
     let mut file_entry = None;
-    match fetch(&conn, Path(file_path.as_ref())).await {
-        Ok(f) => {
-            already_exists = true;
-            file_entry = Some(f);
-        }
+    let path = file_path.clone();
+    match block(pool, move |conn| fetch_info(conn, Path(path.as_ref()))).await {
+        Ok(f) => file_entry = Some(f),
         Err(NotFound) => (),
         Err(e) => {
             err!("Unexpected error: {}", e);
             return Ok(internal_server_error!());
         }
     };
-    let mut resp = if already_exists {
-        HttpResponse::NoContent()
-    } else {
-        HttpResponse::Created()
-    };
 
     //
     // Handle request conditions
     //
-    let headers: &HeaderMap = req.headers();
     if let Some(file_entry) = &file_entry {
-        let etag = file_entry.content_hash();
-        let modified = match HttpDate::from_str(&file_entry.modified) {
-            Ok(v) => v,
-            Err(e) => {
-                err!("Unexpected error: {}", e);
-                return Ok(internal_server_error!());
-            }
-        };
-
-        // If-Unmodified-Since condition
-        let unmodified_since = if let Some(val) = headers.get("If-Unmodified-Since") {
-            let date: HttpDate = val.to_str().unwrap().parse()?;
-            modified <= date
-        } else {
-            true
-        };
-        // If-Match condition
-        let if_match = if headers.contains_key("If-Match") {
-            let matches: bool = headers
-                .get_all("If-Match")
-                // ETAG values are enclosed in double quotes
-                .map(|e| e.to_str().unwrap().trim_matches('"'))
-                .any(|e| e == &etag);
-            matches
-        } else {
-            true
-        };
-        if !unmodified_since || !if_match {
+        if !modification_allowed(req.headers(), file_entry)? {
             return Ok(precondition_failed!());
         }
     }
 
+    let mode = match mode_header(req.headers()) {
+        Ok(mode) => mode,
+        Err(txt) => return Ok(bad_request!("{}", txt)),
+    };
+    let encoding = match Encoding::of_request(req.headers()) {
+        Ok(encoding) => encoding,
+        Err(txt) => return Ok(unsupported_media_type!("{}", txt)),
+    };
+
     //
     // Get payload
     //
-    let mut body = web::BytesMut::new();
-    while let Some(chunk) = payload.next().await {
-        let chunk = chunk?;
-        if (body.len() + chunk.len()) > MAX_SIZE {
-            return Ok(payload_too_large!());
-        }
-        body.extend_from_slice(&chunk);
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| val.parse::<usize>().ok());
+    if matches!(content_length, Some(n) if n > config.max_upload_size) {
+        return Ok(payload_too_large!());
     }
+    let (body, size) = match spool_payload(payload, encoding, config.max_upload_size).await? {
+        Some(res) => res,
+        None => return Ok(payload_too_large!()),
+    };
 
     //
     // Create or update the file entry
     //
-    let date = HttpDate::from(SystemTime::now());
-    let res = if let Some(mut file_entry) = file_entry {
-        file_entry.content = Vec::from(&body[..]);
-        file_entry.modified = date.to_string();
-        update(&conn, &file_entry).await
-    } else {
-        let new_file = NewFile {
-            path: file_path,
-            content: Vec::from(&body[..]),
-            mode: 0, // XXX: Mode are not yet implemented in the API
-            modified: date.to_string(),
+    let date = HttpDate::from(SystemTime::now()).to_string();
+    let headers = req.headers().clone();
+    let res = block(pool, move |conn| {
+        use rusqlite::TransactionBehavior::Immediate;
+
+        // The file may have been created or changed by a concurrent request
+        // since it was fetched, so it is looked up and its conditions are
+        // checked again once the write lock is held.
+        let tx = conn.transaction_with_behavior(Immediate)?;
+        let file_entry = match fetch_info(&tx, Path(file_path.as_ref())) {
+            Ok(f) => Some(f),
+            Err(NotFound) => None,
+            Err(e) => return Err(e),
         };
-        create(&conn, &new_file).await
+        if let Some(file_entry) = &file_entry {
+            check_modification(&headers, file_entry)?;
+        }
+        let already_exists = file_entry.is_some();
+        if let Some(file_entry) = file_entry {
+            let mode = mode.unwrap_or(file_entry.mode);
+            update_from_reader(&tx, file_entry.id, body, size, mode, &date)?;
+        } else {
+            let new_file = NewFile {
+                path: file_path,
+                content: Vec::new(),
+                mode: mode.unwrap_or(DEFAULT_MODE),
+                modified: date,
+            };
+            create_from_reader(&tx, &new_file, body, size)?;
+        }
+        tx.commit()?;
+        Ok(already_exists)
+    });
+    match res.await {
+        Ok(true) => Ok(HttpResponse::NoContent().finish()),
+        Ok(false) => Ok(HttpResponse::Created().finish()),
+        Err(BadRequest(txt)) => Ok(bad_request!("{}", txt)),
+        Err(PreconditionFailed) => Ok(precondition_failed!()),
+        Err(e) => {
+            err!("{}", e);
+            Ok(internal_server_error!())
+        }
+    }
+}
+
+/// Update the mode of a file without uploading its content.
+#[actix_web::patch("/files/{file:.*}")]
+pub async fn patch(
+    web::Path(file_path): web::Path<String>,
+    pool: web::Data<Pool>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    use crate::database::file::FileIdentifier::Path;
+    use crate::database::file::{fetch_info, update_mode};
+    use crate::CabinetError::{BadRequest, NotFound, PreconditionFailed};
+    use std::time::SystemTime;
+
+    if !super::unlocked(&req, &file_path, false) {
+        return Ok(locked!());
+    }
+    let mode = match mode_header(req.headers()) {
+        Ok(Some(mode)) => mode,
+        Ok(None) => return Ok(bad_request!("missing {} header", MODE_HEADER)),
+        Err(txt) => return Ok(bad_request!("{}", txt)),
     };
-    if let Err(e) = res {
-        err!("{}", e);
-        return Ok(internal_server_error!());
+
+    //
+    // Update the file entry, if the request conditions hold
+    //
+    let date = HttpDate::from(SystemTime::now()).to_string();
+    let headers = req.headers().clone();
+    let path = file_path.clone();
+    let res = block(&pool, move |conn| {
+        use rusqlite::TransactionBehavior::Immediate;
+
+        let tx = conn.transaction_with_behavior(Immediate)?;
+        let file = fetch_info(&tx, Path(path.as_ref()))?;
+        check_modification(&headers, &file)?;
+        if mode != file.mode {
+            update_mode(&tx, file.id, mode, &date)?;
+            tx.commit()?;
+        }
+        Ok(())
+    });
+    match res.await {
+        Ok(()) => Ok(HttpResponse::NoContent().finish()),
+        Err(NotFound) => Ok(not_found!("{}", &file_path)),
+        Err(BadRequest(txt)) => Ok(bad_request!("{}", txt)),
+        Err(PreconditionFailed) => Ok(precondition_failed!()),
+        Err(e) => {
+            err!("Failed to update file mode: {}", e);
+            Ok(internal_server_error!())
+        }
     }
+}
+
+#[actix_web::post("/files/{file:.*}")]
+pub async fn restore(
+    web::Path(file_path): web::Path<String>,
+    web::Query(query): web::Query<RestoreQuery>,
+    pool: web::Data<Pool>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    use crate::database::file::FileIdentifier::{Id, Path};
+    use crate::database::file::{fetch_info, restore, revision_at};
+    use crate::CabinetError::{BadRequest, NotFound, PreconditionFailed};
+    use std::time::SystemTime;
+
+    if !super::unlocked(&req, &file_path, false) {
+        return Ok(locked!());
+    }
+    //
+    // Parse the revision to restore
+    //
+    let (rev, date) = match (query.rev, query.date) {
+        (Some(rev), None) => (Some(rev), None),
+        (None, Some(date)) => match date.parse::<HttpDate>() {
+            Ok(date) => (None, Some(date)),
+            Err(_) => return Ok(bad_request!("invalid date: {}", date)),
+        },
+        _ => return Ok(bad_request!("expected either rev or date")),
+    };
+    let missing = match (rev, date) {
+        (Some(rev), _) => format!("{} revision {}", &file_path, rev),
+        (None, Some(date)) => format!("{} at {}", &file_path, date),
+        (None, None) => file_path.clone(),
+    };
 
-    Ok(resp.finish())
+    //
+    // Restore the file as a new modification, if the request conditions hold
+    //
+    let now = HttpDate::from(SystemTime::now()).to_string();
+    let headers = req.headers().clone();
+    let path = file_path.clone();
+    let res = block(&pool, move |conn| {
+        use rusqlite::TransactionBehavior::Immediate;
+
+        let tx = conn.transaction_with_behavior(Immediate)?;
+        let file = fetch_info(&tx, Path(path.as_ref()))?;
+        check_modification(&headers, &file)?;
+        let revision = match date {
+            Some(date) => revision_at(&tx, file.id, &date)?,
+            None => rev,
+        };
+        let restored = match revision {
+            Some(revision) => restore(&tx, Id(file.id), revision, &now),
+            None => Err(NotFound),
+        };
+        match restored {
+            Ok(_) => (),
+            // The revision doesn't exist, unlike the file itself
+            Err(NotFound) => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        tx.commit()?;
+        Ok(true)
+    });
+    match res.await {
+        Ok(true) => Ok(HttpResponse::NoContent().finish()),
+        Ok(false) => Ok(not_found!("{}", missing)),
+        Err(NotFound) => Ok(not_found!("{}", &file_path)),
+        Err(BadRequest(txt)) => Ok(bad_request!("{}", txt)),
+        Err(PreconditionFailed) => Ok(precondition_failed!()),
+        Err(e) => {
+            err!("Failed to restore file: {}", e);
+            Ok(internal_server_error!())
+        }
+    }
 }
 
 #[actix_web::delete("/files/{file:.*}")]
 pub async fn delete(
     web::Path(file_path): web::Path<String>,
+    pool: web::Data<Pool>,
     req: HttpRequest,
 ) -> Result<HttpResponse> {
+    if !super::unlocked(&req, &file_path, false) {
+        return Ok(locked!());
+    }
+    delete_file(file_path, &pool, &req).await
+}
+
+/// Delete a file, unless it is used in a boilerplate.
+pub async fn delete_file(file_path: String, pool: &Pool, req: &HttpRequest) -> Result<HttpResponse> {
     use crate::database::boilerplate::file_used_in_boilerplates;
     use crate::database::file::FileIdentifier::{Id, Path};
-    use crate::database::file::{delete, fetch};
-    use crate::CabinetError::NotFound;
+    use crate::database::file::{delete, fetch_info};
+    use crate::CabinetError::{BadRequest, NotFound, PreconditionFailed};
 
     //
-    // Fetch requested file
+    // Delete the file, if the request conditions hold
     //
-    let conn = get_db_conn();
-    let file: File = match fetch(&conn, Path(file_path.as_ref())).await {
-        Ok(f) => f,
-        Err(NotFound) => return Ok(not_found!("{}", &file_path)),
+    let headers = req.headers().clone();
+    let path = file_path.clone();
+    let res = block(pool, move |conn| {
+        use rusqlite::TransactionBehavior::Immediate;
+
+        let tx = conn.transaction_with_behavior(Immediate)?;
+        let file = fetch_info(&tx, Path(path.as_ref()))?;
+        let bps = file_used_in_boilerplates(&tx, file.id)?;
+        if !bps.is_empty() {
+            return Err(BadRequest(format!("file is used in boilerplates:\n{}", bps.join("\n"))));
+        }
+        check_modification(&headers, &file)?;
+        delete(&tx, Id(file.id))?;
+        tx.commit()?;
+        Ok(())
+    });
+    match res.await {
+        Ok(()) => Ok(HttpResponse::NoContent().finish()),
+        Err(NotFound) => Ok(not_found!("{}", &file_path)),
+        Err(BadRequest(txt)) => Ok(bad_request!("{}", txt)),
+        Err(PreconditionFailed) => Ok(precondition_failed!()),
         Err(e) => {
-            err!("Failed to fetch file: {}", e);
-            return Ok(internal_server_error!());
+            err!("Failed to delete file: {}", e);
+            Ok(internal_server_error!())
         }
+    }
+}
+
+/// Resource for MOVE requests, which the route macros don't support.
+pub fn move_service() -> actix_web::Resource {
+    use actix_web::guard;
+
+    web::resource("/files/{file:.*}")
+        .guard(guard::Method(super::move_method()))
+        .to(move_file)
+}
+
+/// Move a file to the path given by the Destination header.
+pub async fn move_file(
+    web::Path(file_path): web::Path<String>,
+    pool: web::Data<Pool>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    use super::{destination, overwrite, unlocked};
+    use crate::database::file::FileIdentifier::Path;
+    use crate::database::file::{fetch_info, relocate};
+    use crate::CabinetError::{BadRequest, NotFound, PreconditionFailed};
+
+    let (dest, overwrite) = match (destination(req.headers(), "/files/"), overwrite(req.headers())) {
+        (Ok(dest), Ok(overwrite)) => (dest, overwrite),
+        (Err(BadRequest(txt)), _) | (_, Err(BadRequest(txt))) => return Ok(bad_request!("{}", txt)),
+        _ => return Ok(bad_request!()),
     };
+    if !unlocked(&req, &file_path, false) || !unlocked(&req, &dest, false) {
+        return Ok(locked!());
+    }
 
     //
-    // Check if the file is used in any boilerplates before deleting
+    // Move the file, if the request conditions hold
     //
-    let bps = match file_used_in_boilerplates(&conn, file.id).await {
-        Ok(bps) => bps,
+    let headers = req.headers().clone();
+    let path = file_path.clone();
+    let res = block(&pool, move |conn| {
+        use rusqlite::TransactionBehavior::Immediate;
+
+        let tx = conn.transaction_with_behavior(Immediate)?;
+        let file = fetch_info(&tx, Path(path.as_ref()))?;
+        check_modification(&headers, &file)?;
+        let replaced = relocate(&tx, file.id, dest.as_ref(), overwrite)?;
+        tx.commit()?;
+        Ok(replaced)
+    });
+    match res.await {
+        Ok(true) => Ok(HttpResponse::NoContent().finish()),
+        Ok(false) => Ok(HttpResponse::Created().finish()),
+        Err(NotFound) => Ok(not_found!("{}", &file_path)),
+        Err(PreconditionFailed) => Ok(precondition_failed!()),
+        Err(BadRequest(txt)) => Ok(bad_request!("{}", txt)),
         Err(e) => {
-            err!("Failed to check if file was used in boilerplates: {}", e);
-            return Ok(internal_server_error!());
+            err!("Failed to move file: {}", e);
+            Ok(internal_server_error!())
         }
-    };
-    if bps.len() > 0 {
-        let names = bps.join("\n");
-        return Ok(bad_request!("file is used in boilerplates:\n{}", names));
     }
+}
 
-    //
-    // Handle request conditions
-    //
-    let headers: &HeaderMap = req.headers();
-    let etag = file.content_hash();
-    let modified = HttpDate::from_str(&file.modified)?;
-    let unmodified_since = if let Some(val) = headers.get("If-Unmodified-Since") {
-        let date = val.to_str().unwrap().parse()?;
-        modified <= date
-    } else {
-        true
+/// Resource for COPY requests, which the route macros don't support.
+pub fn copy_service() -> actix_web::Resource {
+    use actix_web::guard;
+
+    web::resource("/files/{file:.*}")
+        .guard(guard::Method(super::copy_method()))
+        .to(copy_file)
+}
+
+/// Copy a file to the path given by the Destination header.
+pub async fn copy_file(
+    web::Path(file_path): web::Path<String>,
+    web::Query(query): web::Query<super::CopyQuery>,
+    pool: web::Data<Pool>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    use super::{destination, overwrite, unlocked};
+    use crate::database::file::{copy_to, get_id};
+    use crate::CabinetError::{BadRequest, NotFound, PreconditionFailed};
+    use std::time::SystemTime;
+
+    let (dest, overwrite) = match (destination(req.headers(), "/files/"), overwrite(req.headers())) {
+        (Ok(dest), Ok(overwrite)) => (dest, overwrite),
+        (Err(BadRequest(txt)), _) | (_, Err(BadRequest(txt))) => return Ok(bad_request!("{}", txt)),
+        _ => return Ok(bad_request!()),
     };
-    let if_match = if headers.contains_key("If-Match") {
-        let matches: bool = headers
-            .get_all("If-Match")
-            .map(|e| e.to_str().unwrap().trim_matches('"'))
-            .any(|e| e == &etag);
-        matches
+    if !unlocked(&req, &dest, false) {
+        return Ok(locked!());
+    }
+    let modified = if query.keep_modified {
+        None
     } else {
-        true
+        Some(HttpDate::from(SystemTime::now()).to_string())
     };
-    if !unmodified_since || !if_match {
-        return Ok(precondition_failed!());
-    }
 
-    match delete(&conn, Id(file.id)).await {
-        Ok(_) => Ok(HttpResponse::NoContent().finish()),
+    let path = file_path.clone();
+    let res = block(&pool, move |conn| {
+        let id = get_id(conn, path.as_ref())?.ok_or(NotFound)?;
+        copy_to(conn, id, dest.as_ref(), overwrite, modified.as_deref())
+    })
+    .await;
+    match res {
+        Ok(true) => Ok(HttpResponse::NoContent().finish()),
+        Ok(false) => Ok(HttpResponse::Created().finish()),
+        Err(NotFound) => Ok(not_found!("{}", &file_path)),
+        Err(PreconditionFailed) => Ok(precondition_failed!()),
+        Err(BadRequest(txt)) => Ok(bad_request!("{}", txt)),
         Err(e) => {
-            err!("{}", e);
+            err!("Failed to copy file: {}", e);
             Ok(internal_server_error!())
         }
     }
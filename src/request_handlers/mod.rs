@@ -3,3 +3,127 @@ pub mod file;
 pub mod dir;
 pub mod boilerplate;
 pub mod status;
+pub mod dav;
+pub mod archive;
+pub mod batch;
+pub mod change;
+pub mod events;
+pub mod redirect;
+
+use crate::{CabinetError, CabinetResult};
+use actix_web::http::{HeaderMap, Method};
+use actix_web::HttpRequest;
+use serde::Deserialize;
+
+/// Header giving the target of a MOVE or COPY request, as an absolute URL or
+/// path.
+pub const DESTINATION_HEADER: &str = "Destination";
+
+/// Header deciding if a MOVE or COPY request may replace an existing
+/// target: `T` or `F`. Defaults to `T`.
+pub const OVERWRITE_HEADER: &str = "Overwrite";
+
+/// Query parameters accepted when restoring files and boilerplates.
+///
+/// Exactly one of `rev` (a revision number) or `date` (an HTTP date) is
+/// expected.
+///
+#[derive(Debug, Deserialize)]
+pub struct RestoreQuery {
+    pub rev: Option<usize>,
+    pub date: Option<String>,
+}
+
+/// Query parameters accepted when copying files and directories.
+#[derive(Debug, Deserialize)]
+pub struct CopyQuery {
+    /// Keep the modified date of the copied files, instead of setting it to
+    /// the time of the copy.
+    #[serde(default)]
+    pub keep_modified: bool,
+}
+
+/// The MOVE method, used for moving and renaming files and directories.
+pub fn move_method() -> Method {
+    Method::from_bytes(b"MOVE").unwrap()
+}
+
+/// The COPY method, used for copying files and directories.
+pub fn copy_method() -> Method {
+    Method::from_bytes(b"COPY").unwrap()
+}
+
+/// Get the URL path of the Destination header, without scheme, host and
+/// query.
+pub fn destination_path(headers: &HeaderMap) -> Option<&str> {
+    let dest = headers.get(DESTINATION_HEADER)?.to_str().ok()?;
+    let path = match dest.find("://") {
+        Some(i) => {
+            let rest = &dest[i + 3..];
+            &rest[rest.find('/')?..]
+        }
+        None => dest,
+    };
+    path.split(['?', '#']).next()
+}
+
+/// Get the server-side path of the Destination header, which must be a
+/// resource below `prefix`, like `/files/`.
+pub fn destination(headers: &HeaderMap, prefix: &str) -> CabinetResult<String> {
+    use percent_encoding::percent_decode_str;
+
+    let path = match destination_path(headers) {
+        Some(path) => path,
+        None => return Err(CabinetError::BadRequest("missing or invalid Destination header".to_string())),
+    };
+    let rest = match path.strip_prefix(prefix) {
+        Some(rest) => rest,
+        None => return Err(CabinetError::BadRequest(format!("destination must be below {}", prefix))),
+    };
+    let decoded = percent_decode_str(rest).decode_utf8_lossy();
+    let decoded = decoded.trim_matches('/');
+    if decoded.is_empty() {
+        return Err(CabinetError::BadRequest("empty destination".to_string()));
+    }
+    // Every segment must name an entry, as `Path::components` would skip
+    // some of the others
+    if decoded.split('/').any(|name| matches!(name, "" | "." | "..")) {
+        return Err(CabinetError::BadRequest(format!("invalid destination: {}", decoded)));
+    }
+    Ok(decoded.to_string())
+}
+
+/// Check if the WebDAV locks allow a request to modify the resource at
+/// `path`, by submitting the tokens of all locks covering it in an If
+/// header. With `recursive` set the locks of all descendants of a
+/// directory are checked as well.
+///
+/// Locks are taken through the WebDAV mount, but hold for every route
+/// which modifies the file tree.
+///
+pub fn unlocked(req: &HttpRequest, path: &str, recursive: bool) -> bool {
+    use crate::database::dir::canonical_path;
+    use crate::dav::{if_tokens, LockManager};
+    use actix_web::web;
+
+    let locks = match req.app_data::<web::Data<LockManager>>() {
+        Some(locks) => locks,
+        None => return true,
+    };
+    let tokens = req
+        .headers()
+        .get("If")
+        .and_then(|v| v.to_str().ok())
+        .map(if_tokens)
+        .unwrap_or_default();
+    locks.allows(&canonical_path(path.as_ref()), &tokens, recursive)
+}
+
+/// Get the value of the Overwrite header.
+pub fn overwrite(headers: &HeaderMap) -> CabinetResult<bool> {
+    match headers.get(OVERWRITE_HEADER).map(|v| v.as_bytes()) {
+        None | Some(b"T") => Ok(true),
+        Some(b"F") => Ok(false),
+        Some(_) => Err(CabinetError::BadRequest("Overwrite must be T or F".to_string())),
+    }
+}
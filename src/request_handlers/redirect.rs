@@ -0,0 +1,30 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+
+/// Port the HTTPS server listens on, which plain HTTP requests are
+/// redirected to.
+#[derive(Debug, Clone, Copy)]
+pub struct HttpsPort(pub u16);
+
+/// Redirect a plain HTTP request to the same URL over HTTPS.
+///
+/// The redirect is permanent and keeps the method and body, so clients
+/// retry uploads over HTTPS as well.
+///
+pub async fn redirect(req: HttpRequest, port: web::Data<HttpsPort>) -> HttpResponse {
+    use actix_web::http::header::LOCATION;
+
+    let info = req.connection_info();
+    let host = info.host();
+    // Strip the port of the plain HTTP listener, keeping IPv6 brackets
+    let host = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    let location = match port.0 {
+        443 => format!("https://{}{}", host, req.uri()),
+        port => format!("https://{}:{}{}", host, port, req.uri()),
+    };
+    HttpResponse::PermanentRedirect()
+        .header(LOCATION, location)
+        .finish()
+}
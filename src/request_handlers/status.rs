@@ -1,15 +1,14 @@
-use crate::get_db_conn;
-use actix_web::{HttpResponse, Result};
+use crate::database::{block, Pool};
+use actix_web::{web, HttpResponse, Result};
 use mhlog::err;
 use std::collections::HashMap;
 
 #[actix_web::get("/status")]
-pub async fn get() -> Result<HttpResponse> {
+pub async fn get(pool: web::Data<Pool>) -> Result<HttpResponse> {
     use crate::database;
     let mut stats = HashMap::new();
-    let conn = get_db_conn();
 
-    let files: usize = match database::file::count(&conn).await {
+    let files: usize = match block(&pool, |conn| database::file::count(conn)).await {
         Ok(n) => n,
         Err(e) => {
             err!("Failed to get files count: {}", e);
@@ -18,7 +17,7 @@ pub async fn get() -> Result<HttpResponse> {
     };
     stats.insert("files", files);
 
-    let dirs: usize = match database::dir::count(&conn).await {
+    let dirs: usize = match block(&pool, |conn| database::dir::count(conn)).await {
         Ok(n) => n,
         Err(e) => {
             err!("Failed to get directories count: {}", e);
@@ -27,7 +26,7 @@ pub async fn get() -> Result<HttpResponse> {
     };
     stats.insert("directories", dirs);
 
-    let bps: usize = match database::boilerplate::count(&conn).await {
+    let bps: usize = match block(&pool, |conn| database::boilerplate::count(conn)).await {
         Ok(n) => n,
         Err(e) => {
             err!("Failed to get boilerplates count: {}", e);
@@ -36,5 +35,5 @@ pub async fn get() -> Result<HttpResponse> {
     };
     stats.insert("boilerplates", bps);
 
-    return Ok(HttpResponse::Ok().json(&stats));
+    Ok(HttpResponse::Ok().json(&stats))
 }
@@ -0,0 +1,197 @@
+//! TLS termination with rustls.
+//!
+//! The certificate chain and private key are read from PEM files, and are
+//! read again when the server receives SIGHUP, so renewed certificates are
+//! served without restarting.
+//!
+//! Clients may authenticate with a certificate issued by a configured CA.
+//! The common name of the certificate subject is the identity of the
+//! client, which is authorized as the API token of the same name.
+
+use actix_tls::rustls::TlsStream;
+use actix_web::rt::net::TcpStream;
+use actix_web::HttpMessage;
+use anyhow::{bail, Context, Result};
+use mhlog::{err, info};
+use rustls::sign::CertifiedKey;
+use rustls::{ClientHello, ResolvesServerCert, RootCertStore, ServerConfig};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+
+/// Certificate resolver serving the latest loaded certificate.
+pub struct CertResolver {
+    certificate: PathBuf,
+    key: PathBuf,
+    current: RwLock<CertifiedKey>,
+}
+
+impl CertResolver {
+    /// Load the certificate chain and private key from the PEM files at
+    /// `certificate` and `key`.
+    pub fn load(certificate: &Path, key: &Path) -> Result<CertResolver> {
+        let current = load_certified_key(certificate, key)?;
+        Ok(CertResolver {
+            certificate: certificate.into(),
+            key: key.into(),
+            current: RwLock::new(current),
+        })
+    }
+
+    /// Load the certificate chain and private key again. The previous
+    /// certificate is kept if they can not be loaded.
+    pub fn reload(&self) -> Result<()> {
+        let current = load_certified_key(&self.certificate, &self.key)?;
+        *self.current.write().unwrap() = current;
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn load_certified_key(certificate: &Path, key: &Path) -> Result<CertifiedKey> {
+    use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+    use rustls::sign::any_supported_type;
+    use std::fs::File;
+    use std::io::{BufReader, Seek, SeekFrom};
+
+    //
+    // Read certificate chain
+    //
+    let file = File::open(certificate)
+        .with_context(|| format!("Failed to open TLS certificate {:?}", certificate))?;
+    let chain = certs(&mut BufReader::new(file))
+        .map_err(|_| anyhow::anyhow!("Invalid TLS certificate {:?}", certificate))?;
+    if chain.is_empty() {
+        bail!("No certificates found in {:?}", certificate);
+    }
+
+    //
+    // Read private key, in PKCS#8 or PKCS#1 format
+    //
+    let file = File::open(key).with_context(|| format!("Failed to open TLS key {:?}", key))?;
+    let mut reader = BufReader::new(file);
+    let mut keys = pkcs8_private_keys(&mut reader).unwrap_or_default();
+    if keys.is_empty() {
+        reader.seek(SeekFrom::Start(0))?;
+        keys = rsa_private_keys(&mut reader).unwrap_or_default();
+    }
+    let key = match keys.into_iter().next() {
+        Some(der) => any_supported_type(&der).map_err(|_| anyhow::anyhow!("Unsupported TLS key {:?}", key))?,
+        None => bail!("No private key found in {:?}", key),
+    };
+
+    Ok(CertifiedKey::new(chain, Arc::new(key)))
+}
+
+/// Load the CA certificates client certificates are verified against from
+/// the PEM file at `path`.
+pub fn load_client_ca(path: &Path) -> Result<RootCertStore> {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    let file = File::open(path).with_context(|| format!("Failed to open TLS client CA {:?}", path))?;
+    let mut roots = RootCertStore::empty();
+    match roots.add_pem_file(&mut BufReader::new(file)) {
+        Ok((valid, _)) if valid > 0 => Ok(roots),
+        _ => bail!("No valid CA certificates found in {:?}", path),
+    }
+}
+
+/// Build the rustls configuration serving the certificates of `resolver`.
+///
+/// With `client_ca` clients may authenticate with a certificate issued by
+/// one of its CAs. Clients without certificates are still accepted, and
+/// authenticate with tokens instead.
+///
+pub fn server_config(resolver: Arc<CertResolver>, client_ca: Option<RootCertStore>) -> ServerConfig {
+    use rustls::{AllowAnyAnonymousOrAuthenticatedClient, NoClientAuth};
+
+    let verifier = match client_ca {
+        Some(roots) => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+        None => NoClientAuth::new(),
+    };
+    let mut config = ServerConfig::new(verifier);
+    config.cert_resolver = resolver;
+    config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    config
+}
+
+/// Reload the certificates of `resolver` whenever the server receives
+/// SIGHUP. Must be called from within the actix runtime.
+pub fn reload_on_hangup(resolver: Arc<CertResolver>) -> Result<()> {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).context("Failed to listen for SIGHUP")?;
+    actix_web::rt::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match resolver.reload() {
+                Ok(()) => info!("Reloaded TLS certificate {:?}", resolver.certificate),
+                Err(e) => err!("Failed to reload TLS certificate: {:#}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/*******************************************************************************
+ *                                                                             *
+ * Client certificates
+ *                                                                             *
+ *******************************************************************************/
+
+/// ClientCertificate describes the verified certificate a client connected
+/// with. Requests get it with `client_certificate`.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct ClientCertificate {
+    /// The full subject, like `CN=laptop, O=Example`.
+    pub subject: String,
+    /// The common name of the subject, which identifies the client.
+    pub common_name: Option<String>,
+}
+
+impl ClientCertificate {
+    fn from_der(der: &[u8]) -> Option<ClientCertificate> {
+        use x509_parser::prelude::{FromDer, X509Certificate};
+
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+        let subject = cert.subject();
+        let common_name = subject
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(String::from);
+        Some(ClientCertificate {
+            subject: subject.to_string(),
+            common_name,
+        })
+    }
+}
+
+/// PeerCertificate is attached to every request on a TLS connection, with
+/// the certificate the client connected with, if any.
+#[derive(Debug, Clone)]
+pub struct PeerCertificate(Option<Rc<ClientCertificate>>);
+
+/// Get the certificate of a newly connected client. Used as the
+/// `on_connect` callback of the HTTPS service, which attaches the result to
+/// every request on the connection.
+pub fn on_connect(stream: &TlsStream<TcpStream>) -> PeerCertificate {
+    use actix_tls::rustls::Session;
+
+    let cert = stream
+        .get_ref()
+        .1
+        .get_peer_certificates()
+        .and_then(|chain| chain.first().and_then(|cert| ClientCertificate::from_der(&cert.0)));
+    PeerCertificate(cert.map(Rc::new))
+}
+
+/// Get the certificate the client of a request connected with.
+pub fn client_certificate(req: &impl HttpMessage) -> Option<Rc<ClientCertificate>> {
+    req.extensions().get::<PeerCertificate>().and_then(|peer| peer.0.clone())
+}
@@ -0,0 +1,144 @@
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::Row;
+use std::convert::TryFrom;
+use std::path::{Component, Path};
+use std::str::FromStr;
+
+/// The access granted by an API token.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Scope {
+    Read,
+    Write,
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "write" => Ok(Scope::Write),
+            _ => Err(format!("Unknown token scope: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scope::Read => write!(f, "read"),
+            Scope::Write => write!(f, "write"),
+        }
+    }
+}
+
+impl ToSql for Scope {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.to_string()))
+    }
+}
+
+impl FromSql for Scope {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: String| FromSqlError::Other(e.into()))
+    }
+}
+
+/// NewToken contains the data of an API token which is not yet stored in
+/// the database.
+///
+/// Only the hash of the token secret is ever stored.
+///
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct NewToken {
+    pub name: String,
+    pub hash: String,
+    pub scope: Scope,
+    pub prefix: Option<String>,
+    pub created: String,
+}
+
+/// Token contains the data of an API token stored in the database.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Token {
+    pub id: usize,
+    pub name: String,
+    pub scope: Scope,
+    pub prefix: Option<String>,
+    pub created: String,
+}
+
+/// A resource accessed with a token.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Resource<'a> {
+    /// A file or directory, by its server-side path.
+    Path(&'a Path),
+    /// Data outside the file tree which tokens restricted to a path prefix
+    /// may read, like boilerplates and the change feed.
+    Shared,
+    /// Anything else.
+    Other,
+}
+
+impl Token {
+    /// Check if the token grants `scope` access to a resource.
+    ///
+    /// Tokens restricted to a path prefix are only allowed to access files
+    /// and directories under it, never using paths with `..` components,
+    /// and to read shared resources.
+    ///
+    pub fn allows(&self, scope: Scope, resource: Resource<'_>) -> bool {
+        if self.scope == Scope::Read && scope == Scope::Write {
+            return false;
+        }
+        match (&self.prefix, resource) {
+            (None, _) => true,
+            (Some(prefix), Resource::Path(path)) => {
+                path.starts_with(prefix) && !path.components().any(|c| c == Component::ParentDir)
+            }
+            (Some(_), Resource::Shared) => scope == Scope::Read,
+            (Some(_), Resource::Other) => false,
+        }
+    }
+}
+
+impl TryFrom<&Row<'_>> for Token {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row<'_>) -> Result<Token, Self::Error> {
+        Ok(Token {
+            id: row.get("id")?,
+            name: row.get("name")?,
+            scope: row.get("scope")?,
+            prefix: row.get("prefix")?,
+            created: row.get("created")?,
+        })
+    }
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<Token {} {}>", self.id, self.name)
+    }
+}
+
+/// Generate a new random token secret.
+pub fn generate() -> String {
+    use rand::RngCore;
+
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Hash a token secret for storing and lookup in the database.
+pub fn hash(secret: &str) -> String {
+    use sha2::{Digest, Sha256};
+
+    let mut hasher = Sha256::new();
+    hasher.update(secret.as_bytes());
+    hex::encode(hasher.finalize())
+}
@@ -1,5 +1,4 @@
 
-.read src/database/tables.sql
 .mode box
 
 BEGIN;
@@ -11,13 +10,18 @@ WITH
 INSERT INTO directory(name, parent) VALUES
     ('foodir', (SELECT id FROM q));
 
+-- Empty content
+INSERT INTO blob(hash, size, content) VALUES
+    ('da39a3ee5e6b4b0d3255bfef95601890afd80709', 0, x'');
+
 WITH
     q1(id) AS (SELECT id FROM directory WHERE name IS 'mydir'),
-    q2(id) AS (SELECT id FROM directory WHERE name IS 'foodir')
-INSERT INTO file(name, parent, mode, modified) VALUES
-    ('myfile', NULL, 101, 'Wed, 21 Oct 2015 01:11:00 GMT'),
-    ('foo.txt', (SELECT id FROM q1), 202, 'Wed, 21 Oct 2015 02:22:00 GMT'),
-    ('bar.txt', (SELECT id FROM q2), 303, 'Fri, 23 Oct 2015 03:33:00 GMT');
+    q2(id) AS (SELECT id FROM directory WHERE name IS 'foodir'),
+    b(id) AS (SELECT id FROM blob)
+INSERT INTO file(name, parent, blob, mode, modified) VALUES
+    ('myfile', NULL, (SELECT id FROM b), 101, 'Wed, 21 Oct 2015 01:11:00 GMT'),
+    ('foo.txt', (SELECT id FROM q1), (SELECT id FROM b), 202, 'Wed, 21 Oct 2015 02:22:00 GMT'),
+    ('bar.txt', (SELECT id FROM q2), (SELECT id FROM b), 303, 'Fri, 23 Oct 2015 03:33:00 GMT');
 
 INSERT INTO boilerplate(name, modified, script) VALUES
     ('mybp', 'Fri, 23 Oct 2015 03:33:00 GMT', 'apt install python');
@@ -32,6 +32,17 @@ set constraints {
   files
   dirs
   boilerplates
+  revisions
+  auth
+  config
+  db
+  dav
+  archives
+  batch
+  changes
+  events
+  tls
+  compress
 }
 log "Enabled test constraints: $constraints"
 
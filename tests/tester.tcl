@@ -19,10 +19,19 @@ set cabinet_host 0.0.0.0
 set cabinet_port 8083
 set cabinet_log [file normalize cabinet.log]
 
-proc start_cabinet {} {
+proc start_cabinet {args} {
   global cabinet_pid cabinet_host cabinet_port cabinet_log
-  set cabinet_pid [exec [cabinet_bin] $cabinet_host $cabinet_port >>& $cabinet_log &]
+  set cabinet_pid [exec [cabinet_bin] {*}$args $cabinet_host $cabinet_port >>& $cabinet_log &]
   log "Started cabinet server (PID $cabinet_pid)"
+  # Wait for the server to accept connections
+  for {set i 0} {$i < 50} {incr i} {
+    if {![catch {socket $cabinet_host $cabinet_port} sock]} {
+      close $sock
+      return
+    }
+    after 100
+  }
+  throw {TESTER} "Cabinet server not accepting connections"
 }
 
 proc teardown_cabinet {} {
@@ -32,7 +41,7 @@ proc teardown_cabinet {} {
     throw {TESTER} "Cabinet PID unknown"
   }
   exec kill -9 $cabinet_pid
-  file delete cabinet.sqlite
+  file delete cabinet.sqlite cabinet.sqlite-wal cabinet.sqlite-shm
   cleanupTests
 }
 
@@ -106,6 +115,22 @@ proc put {path {body {}} {headers {}}} {
     -query $body
 }
 
+# post PATH ?BODY? ?HEADERS?
+#
+#   Perform a POST request to the cabinet server.
+#
+# Arguments:
+#   PATH    Path to the resource.
+#   BODY    Request body.
+#   HEADERS Request headers. A key-value list.
+#
+proc post {path {body {}} {headers {}}} {
+  http::geturl [cabinet_url]/$path \
+    -method POST \
+    -headers $headers \
+    -query $body
+}
+
 # delete PATH ?HEADERS?
 #
 #   Perform a HEAD request to the cabinet server.
@@ -120,6 +145,107 @@ proc delete {path {headers {}}} {
     -headers $headers
 }
 
+# move PATH DEST ?HEADERS?
+#
+#   Perform a MOVE request to the cabinet server.
+#
+# Arguments:
+#   PATH    Path to the resource.
+#   DEST    Path of the destination.
+#   HEADERS Request headers. A key-value list.
+#
+proc move {path dest {headers {}}} {
+  http::geturl [cabinet_url]/$path \
+    -method MOVE \
+    -headers [list Destination /$dest {*}$headers]
+}
+
+# copy PATH DEST ?HEADERS?
+#
+#   Perform a COPY request to the cabinet server.
+#
+# Arguments:
+#   PATH    Path to the resource.
+#   DEST    Path of the destination.
+#   HEADERS Request headers. A key-value list.
+#
+proc copy {path dest {headers {}}} {
+  http::geturl [cabinet_url]/$path \
+    -method COPY \
+    -headers [list Destination /$dest {*}$headers]
+}
+
+# dav METHOD PATH ?BODY? ?HEADERS?
+#
+#   Perform a WebDAV request to the cabinet server.
+#
+# Arguments:
+#   METHOD  Request method.
+#   PATH    Path to the resource, relative to the WebDAV mount.
+#   BODY    Request body.
+#   HEADERS Request headers. A key-value list.
+#
+proc dav {method path {body {}} {headers {}}} {
+  set opts [list -method $method -headers $headers]
+  if {$body ne {}} {
+    lappend opts -query $body -type {application/xml; charset=utf-8}
+  }
+  http::geturl [cabinet_url]/dav/$path {*}$opts
+}
+
+# subscribe ?QUERY? ?HEADERS?
+#
+#   Open an event stream from the cabinet server, returning the socket to
+#   read events from with next_event.
+#
+# Arguments:
+#   QUERY   Query string of the subscription, without the leading "?".
+#   HEADERS Request headers. A key-value list.
+#
+proc subscribe {{query {}} {headers {}}} {
+  global cabinet_host cabinet_port
+  set sock [socket $cabinet_host $cabinet_port]
+  fconfigure $sock -translation {auto crlf}
+  puts $sock "GET /events?$query HTTP/1.1"
+  puts $sock "Host: $cabinet_host:$cabinet_port"
+  foreach {key val} $headers {
+    puts $sock "$key: $val"
+  }
+  puts $sock ""
+  flush $sock
+  # Skip the status line and headers
+  while {[gets $sock line] > 0} {}
+  fconfigure $sock -blocking 0
+  return $sock
+}
+
+# next_event SOCK ?TIMEOUT?
+#
+#   Read the data of the next event of an event stream, or "timeout" if no
+#   event arrives within TIMEOUT milliseconds.
+#
+# Arguments:
+#   SOCK    Socket returned by subscribe.
+#   TIMEOUT Milliseconds to wait for the event.
+#
+proc next_event {sock {timeout 2000}} {
+  set deadline [expr {[clock milliseconds] + $timeout}]
+  set data {}
+  while {[clock milliseconds] < $deadline} {
+    if {[gets $sock line] < 0} {
+      if {[eof $sock]} {
+        return eof
+      }
+      after 20
+    } elseif {[string match "data: *" $line]} {
+      set data [string range $line 6 end]
+    } elseif {$line eq {} && $data ne {}} {
+      return $data
+    }
+  }
+  return timeout
+}
+
 # http_time TIMEVAL
 #
 #   Format a time value as required by HTTP: Wed, 21 Oct 2015 07:28:00 GMT